@@ -21,7 +21,11 @@ use pvrlib::math::vec3::*;
 use pvrlib::math::aabb::*;
 use pvrlib::math::random::MT19937;
 use pvrlib::light::*;
+use pvrlib::light::emission::create_emission_lights;
 use pvrlib::camera::*;
+use pvrlib::stereo::*;
+use pvrlib::animatedcamera::*;
+use pvrlib::camerafile::*;
 use pvrlib::scene::*;
 use pvrlib::phasefn::*;
 use pvrlib::voxelbuffer::dense::DenseField;
@@ -132,6 +136,7 @@ pub struct AppState {
     default_camera_lookat_y: f32,
     default_camera_lookat_z: f32,
     default_fov: f32,
+    default_samples_per_pixel: u32,
     // These are set by GUI widgets
     pub work_group_size_x_input: String,
     pub work_group_size_y_input: String,
@@ -147,6 +152,8 @@ pub struct AppState {
     pub camera_lookat_y_input: String,
     pub camera_lookat_z_input: String,
     pub fov_input: String,
+    pub samples_per_pixel_input: String,
+    pub spectral_input: bool,
     // Misc
     output_log: Arc<Mutex<Vec<String>>>,
     pub stopwatch: Stopwatch
@@ -177,6 +184,7 @@ impl AppState {
             default_camera_lookat_y: render_settings.camera_lookat.y,
             default_camera_lookat_z: render_settings.camera_lookat.z,
             default_fov: render_settings.fov,
+            default_samples_per_pixel: render_settings.samples_per_pixel,
             // Linked to druid widgets
             work_group_size_x_input: render_settings.work_group_size.0.to_string(),
             work_group_size_y_input: render_settings.work_group_size.1.to_string(),
@@ -192,6 +200,8 @@ impl AppState {
             camera_lookat_y_input: render_settings.camera_lookat.y.to_string(),
             camera_lookat_z_input: render_settings.camera_lookat.z.to_string(),
             fov_input: render_settings.fov.to_string(),
+            samples_per_pixel_input: render_settings.samples_per_pixel.to_string(),
+            spectral_input: render_settings.spectral,
             // Misc
             output_log: Arc::new(Mutex::new(logs)),
             stopwatch: Stopwatch::new()
@@ -208,7 +218,13 @@ impl AppState {
             draw_sky: true,
             camera_origin: vec3(self.default_camera_origin_x, self.default_camera_origin_y, self.default_camera_origin_z),
             camera_lookat: vec3(self.default_camera_lookat_x, self.default_camera_lookat_y, self.default_camera_lookat_z),
-            fov: self.default_fov
+            fov: self.default_fov,
+            light_sampling: LIGHT_SAMPLING,
+            light_sample_count: LIGHT_SAMPLE_COUNT,
+            samples_per_pixel: self.default_samples_per_pixel,
+            spectral: false,
+            time: FRAME_TIME,
+            shutter_interval: SHUTTER_INTERVAL
         };
 
         if let Ok(work_group_size_x_parsed) = self.work_group_size_x_input.parse::<usize>() {
@@ -252,6 +268,10 @@ impl AppState {
         if let Ok(parsed) = self.fov_input.parse::<f32>() {
             settings.fov = parsed;
         }
+        if let Ok(parsed) = self.samples_per_pixel_input.parse::<u32>() {
+            settings.samples_per_pixel = parsed.max(1);
+        }
+        settings.spectral = self.spectral_input;
 
         settings
     }
@@ -422,12 +442,20 @@ pub fn begin_render(sink: Option<ExtEventSink>, render_settings: RenderSettings)
     let scene = create_model(render_settings.draw_sky, &mut stopwatch);
 
     // +x to right, +y to up, -z toward screen
-    let camera = Camera::new(
+    let mut camera = Camera::new(
         render_settings.camera_origin,
         render_settings.camera_lookat,
         vec3(0.0, 1.0, 0.0),   // upVector
         render_settings.fov,
         aspect_ratio);
+    if APERTURE_RADIUS > 0.0 {
+        camera.set_lens(Some(ThinLens {
+            aperture_radius: APERTURE_RADIUS,
+            focus_distance: FOCUS_DISTANCE,
+            blade_count: APERTURE_BLADES,
+            blade_rotation: 0.0
+        }));
+    }
 
     // ----------------------------------------------------------
     // Rendering
@@ -440,14 +468,62 @@ pub fn begin_render(sink: Option<ExtEventSink>, render_settings: RenderSettings)
     };
     let mut progress = Mutex::new(RenderProgressWithDruid::new(sink_clone));
 
-    let mut renderer = Renderer::new(render_settings, &mut rt, &mut progress);
-    renderer.render(&camera, &scene);
+    if let Some(stereo_mode) = STEREO_MODE {
+        let rig = StereoRig {
+            position: render_settings.camera_origin,
+            look_at: render_settings.camera_lookat,
+            up: vec3(0.0, 1.0, 0.0),
+            fov_y: render_settings.fov,
+            aspect_ratio,
+            interaxial: STEREO_INTERAXIAL,
+            zero_parallax_distance: STEREO_ZERO_PARALLAX,
+            mode: stereo_mode
+        };
 
-    stopwatch.stop();
-    
-    println!("> Write the result to {}, {}", FILENAME_PNG, FILENAME_JPG);
+        // Separate files rather than Renderer::render_stereo() (side-by-side),
+        // so that the viewport keeps the same size as the mono render.
+        // The viewport ends up showing the right eye.
+        let eyes = [
+            (StereoEye::Left, FILENAME_PNG_LEFT, FILENAME_JPG_LEFT),
+            (StereoEye::Right, FILENAME_PNG_RIGHT, FILENAME_JPG_RIGHT)];
+        for (eye, filename_png, filename_jpg) in eyes.iter() {
+            let mut eye_camera = rig.get_camera(*eye);
+            eye_camera.set_lens(camera.get_lens());
+
+            let mut renderer = Renderer::new(render_settings, &mut rt, &mut progress);
+            renderer.render(&eye_camera, &scene);
+
+            println!("> Write the result to {}, {}", filename_png, filename_jpg);
+            print_rendertarget(&rt, filename_png, filename_jpg);
+        }
+
+        stopwatch.stop();
+    } else if let Some(camera_file) = CAMERA_FILE {
+        let animated_camera = if camera_file.ends_with(".json") {
+            read_json_camera(camera_file)
+        } else {
+            read_chan(camera_file, CameraInterpolation::CatmullRom, aspect_ratio)
+        };
+        let animated_camera = animated_camera.expect("Failed to read the camera file");
+
+        let mut renderer = Renderer::new(render_settings, &mut rt, &mut progress);
+        renderer.render(&animated_camera, &scene);
 
-    print_rendertarget(&rt, FILENAME_PNG, FILENAME_JPG);
+        stopwatch.stop();
+
+        println!("> Write the result to {}, {}", FILENAME_PNG, FILENAME_JPG);
+
+        print_rendertarget(&rt, FILENAME_PNG, FILENAME_JPG);
+    } else {
+        let mut renderer = Renderer::new(render_settings, &mut rt, &mut progress);
+        renderer.render(&camera, &scene);
+
+        stopwatch.stop();
+
+        println!("> Write the result to {}, {}", FILENAME_PNG, FILENAME_JPG);
+
+        print_rendertarget(&rt, FILENAME_PNG, FILENAME_JPG);
+    }
 
     println!("Done.");
     
@@ -469,14 +545,13 @@ fn create_model(draw_sky: bool, stopwatch: &mut Stopwatch) -> Scene {
     // #todo-emptyspace: Sparse buffer is 20x times slower
     //let voxel_buffer = SparseBuffer::new(
     let voxel_buffer = DenseField::new(VOXEL_RESOLUTION, 0.0);
-    let mut voxel_volume = VoxelVolume {
-        buffer: Box::new(voxel_buffer),
-        emission_value: vec3(0.0, 0.0, 0.0),
-        absorption_coeff: vec3(0.7, 0.7, 0.7),
-        scattering_coeff: vec3(0.8, 0.8, 0.8),
-        phase_fn: Box::new(DoubleHenyeyGreenstein{g1: 0.76, g2: -0.5, b: 0.2}),
-        world_bounds: AABB { min: vec3(-20.0, -20.0, -20.0), max: vec3(20.0, 20.0, 20.0) }
-    };
+    let mut voxel_volume = VoxelVolume::new(
+        Box::new(voxel_buffer),
+        AABB { min: vec3(-20.0, -20.0, -20.0), max: vec3(20.0, 20.0, 20.0) },
+        vec3(0.0, 0.0, 0.0),   // emission
+        vec3(0.7, 0.7, 0.7),   // absorption
+        vec3(0.8, 0.8, 0.8),   // scattering
+        Box::new(DoubleHenyeyGreenstein{g1: 0.76, g2: -0.5, b: 0.2}));
 
     let point_prim = pyroclastic_point::PyroclasticPoint {
         center: vec3(0.0, 0.0, 0.0),
@@ -517,14 +592,14 @@ fn create_model(draw_sky: bool, stopwatch: &mut Stopwatch) -> Scene {
         let mut rng = MT19937::new(0);
         for row in 0..rows {
             for col in 0..cols {
-                let shape = if rng.rand() < 0.5 { ConstantVolumeShape::Box } else { ConstantVolumeShape::Sphere };
+                let radius = side_length / 2.0;
+                let shape = if rng.rand() < 0.5 { ConstantVolumeShape::cube(radius) } else { ConstantVolumeShape::Sphere { radius } };
                 let vmin = v0 + vec3(col as f32 * -jump_length, row as f32 * jump_length, 0.0);
                 let emission = vec3(rng.rand_range(0.0, 0.2) as f32, rng.rand_range(0.0, 0.2) as f32, rng.rand_range(0.0, 0.2) as f32);
                 let absorption_coefficient = vec3(rng.rand_range(0.80, 0.99) as f32, rng.rand_range(0.80, 0.99) as f32, rng.rand_range(0.80, 0.99) as f32);
                 let vol = ConstantVolume::new(
                     shape,
                     vmin,                   // center
-                    side_length / 2.0,      // radius
                     emission,
                     absorption_coefficient,
                     vec3(1.0, 1.0, 1.0),    // scattering coefficient
@@ -534,9 +609,10 @@ fn create_model(draw_sky: bool, stopwatch: &mut Stopwatch) -> Scene {
         }
     }
     child_volumes.push(Box::new(voxel_volume));
+    let volume = CompositeVolume::new(child_volumes);
 
     // #todo-light: These intensities are too big? Something wrong with lighting calculation?
-    let lights: Vec<Box<dyn Light>> = vec![
+    let mut lights: Vec<Box<dyn Light>> = vec![
         Box::new(PointLight {
             position: vec3(30.0, 5.0, 30.0),
             intensity: vec3(1.0, 1.0, 10000.0)
@@ -546,6 +622,10 @@ fn create_model(draw_sky: bool, stopwatch: &mut Stopwatch) -> Scene {
             intensity: vec3(10000.0, 1.0, 1.0)
         })
     ];
+    if EMISSION_LIGHTS {
+        lights.append(&mut create_emission_lights(&volume, EMISSION_LIGHT_GRID, 0.01));
+        println!("Lights including emissive volumes: {}", lights.len());
+    }
 
     let sky_atmosphere = if draw_sky {
         SkyAtmosphere::new_atmosphere(vec3(-2.0, -1.0, 15.0), 5.0, 13.61839144264511)
@@ -556,7 +636,7 @@ fn create_model(draw_sky: bool, stopwatch: &mut Stopwatch) -> Scene {
     stopwatch.stop();
 
     let scene = Scene {
-        volume: Box::new(CompositeVolume { children: child_volumes }),
+        volume: Box::new(volume),
         lights: lights,
         sky_atmosphere: sky_atmosphere
     };
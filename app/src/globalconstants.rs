@@ -3,6 +3,8 @@
 // ----------------------------------------------------------
 
 use pvrlib::math::vec3::*;
+use pvrlib::render::raymarcher::LightSampling;
+use pvrlib::stereo::StereoMode;
 
 pub const WINDOW_TITLE: &str = "PVR GUI";
 pub const WINDOW_WIDTH: f64 = 1600.0;
@@ -23,4 +25,28 @@ pub const STEP_SIZE_2ND: f32 = 1.0;
 pub const CAMERA_ORIGIN: vec3f = vec3f { x:0.0, y:0.0, z:50.0 };
 pub const CAMERA_LOOKAT: vec3f = vec3f { x:-15.0, y:10.0, z:0.0 };
 pub const FOV_Y: f32 = 45.0;
+// Depth of field. Pinhole camera if APERTURE_RADIUS is 0.
+pub const APERTURE_RADIUS: f32 = 0.0;
+pub const FOCUS_DISTANCE: f32 = 50.0;
+pub const APERTURE_BLADES: u32 = 6;
+// Stereo output. Left and right eyes are written to FILENAME_*_LEFT and FILENAME_*_RIGHT.
+pub const STEREO_MODE: Option<StereoMode> = None;
+pub const STEREO_INTERAXIAL: f32 = 1.0;
+pub const STEREO_ZERO_PARALLAX: f32 = 50.0;
+pub const FILENAME_PNG_LEFT: &str = "output_left.png";
+pub const FILENAME_JPG_LEFT: &str = "output_left.jpg";
+pub const FILENAME_PNG_RIGHT: &str = "output_right.png";
+pub const FILENAME_JPG_RIGHT: &str = "output_right.jpg";
+pub const LIGHT_SAMPLING: LightSampling = LightSampling::Equiangular;
+pub const LIGHT_SAMPLE_COUNT: u32 = 4;
+pub const SAMPLES_PER_PIXEL: u32 = 1;
+pub const SPECTRAL_RENDERING: bool = false;
+// Motion blur of animated cameras. Shutter interval is relative to FRAME_TIME.
+pub const FRAME_TIME: f32 = 0.0;
+pub const SHUTTER_INTERVAL: (f32, f32) = (0.0, 0.0);
+// Nuke .chan or .json camera animation. Overrides camera origin, lookat and fov of the settings.
+pub const CAMERA_FILE: Option<&str> = None;
+// Let emissive volumes illuminate other volumes via virtual point lights.
+pub const EMISSION_LIGHTS: bool = true;
+pub const EMISSION_LIGHT_GRID: (i32, i32, i32) = (8, 8, 8);
 pub const VOXEL_RESOLUTION: (i32, i32, i32) = (512, 512, 256);
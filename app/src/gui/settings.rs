@@ -56,6 +56,13 @@ pub fn build_ui_settings() -> impl Widget<AppState> {
         .with_child(Label::new("field of view: "))
         .with_child(LensWrap::new(TextBox::new(), AppState::fov_input));
 
+    let spp_row = Flex::row()
+        .with_child(Label::new("samples per pixel: "))
+        .with_child(LensWrap::new(TextBox::new(), AppState::samples_per_pixel_input));
+
+    let spectral_checkbox = LensWrap::new(Checkbox::new("spectral rendering"), AppState::spectral_input);
+    let spectral_row = Flex::row().with_child(spectral_checkbox);
+
     let col = Flex::column()
         .with_spacer(10.0)
         .with_child(label_settings)
@@ -77,6 +84,10 @@ pub fn build_ui_settings() -> impl Widget<AppState> {
         .with_child(camera_lookat_row)
         .with_spacer(20.0)
         .with_child(fov_row)
+        .with_spacer(20.0)
+        .with_child(spp_row)
+        .with_spacer(20.0)
+        .with_child(spectral_row)
         .cross_axis_alignment(CrossAxisAlignment::Start);
         
     SizedBox::new(
@@ -57,6 +57,12 @@ fn get_default_render_settings() -> RenderSettings {
         draw_sky: true,
         camera_origin: CAMERA_ORIGIN,
         camera_lookat: CAMERA_LOOKAT,
-        fov: FOV_Y
+        fov: FOV_Y,
+        light_sampling: LIGHT_SAMPLING,
+        light_sample_count: LIGHT_SAMPLE_COUNT,
+        samples_per_pixel: SAMPLES_PER_PIXEL,
+        spectral: SPECTRAL_RENDERING,
+        time: FRAME_TIME,
+        shutter_interval: SHUTTER_INTERVAL
     }
 }
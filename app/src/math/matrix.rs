@@ -1,6 +0,0 @@
-// #todo-math: Not implemented
-
-pub struct Matrix4 {
-	//
-}
-
@@ -1,4 +0,0 @@
-// #todo-pvrlib: Move to pvrlib
-pub mod rotator;
-pub mod transform;
-pub mod matrix;
@@ -0,0 +1,173 @@
+use crate::math::vec3::*;
+use crate::math::ray::Ray;
+use crate::camera::*;
+
+use std::ops::{Add, Sub, Mul};
+
+// Keyframed perspective camera. Time is in whatever unit the keyframes use (seconds or frames).
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum CameraInterpolation {
+    Linear,
+    CatmullRom
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum CameraRotation {
+    // Target position in world space
+    LookAt(vec3f),
+    // (rx, ry, rz) in degrees, applied in the order of Z, X, Y.
+    // Zero rotation looks toward -z with +y up.
+    Euler(vec3f)
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct CameraKeyframe {
+    pub time: f32,
+    pub position: vec3f,
+    pub rotation: CameraRotation,
+    pub fov_y: f32 // in degrees
+}
+
+pub struct AnimatedCamera {
+    keyframes: Vec<CameraKeyframe>,
+    pub interpolation: CameraInterpolation,
+    // Up vector for LookAt keyframes
+    pub up: vec3f,
+    pub aspect_ratio: f32,
+    pub lens: Option<ThinLens>
+}
+
+impl AnimatedCamera {
+    pub fn new(
+        mut keyframes: Vec<CameraKeyframe>,
+        interpolation: CameraInterpolation,
+        aspect_ratio: f32) -> AnimatedCamera
+    {
+        assert!(!keyframes.is_empty(), "AnimatedCamera needs at least one keyframe");
+        keyframes.sort_by(|a, b| a.time.total_cmp(&b.time));
+
+        AnimatedCamera {
+            keyframes,
+            interpolation,
+            up: vec3(0.0, 1.0, 0.0),
+            aspect_ratio,
+            lens: None
+        }
+    }
+
+    pub fn get_keyframes(&self) -> &[CameraKeyframe] {
+        &self.keyframes
+    }
+
+    // Static camera at the given time. Times out of the keyframe range are clamped.
+    pub fn camera_at(&self, time: f32) -> Camera {
+        let (position, look_at, up, fov_y) = self.pose_at(time);
+        let projection = PerspectiveProjection { fov_y, aspect_ratio: self.aspect_ratio };
+        let mut camera = Camera::with_projection(position, look_at, up, Box::new(projection));
+        camera.set_lens(self.lens);
+        camera
+    }
+
+    // (position, look_at, up, fov_y) at the given time.
+    fn pose_at(&self, time: f32) -> (vec3f, vec3f, vec3f, f32) {
+        let keys = &self.keyframes;
+        let last = keys.len() - 1;
+
+        // Segment [i1, i2] that contains `time`, and its neighbors i0 and i3.
+        let i2 = keys.iter().position(|k| k.time > time).unwrap_or(last).max(1).min(last);
+        let i1 = i2.saturating_sub(1);
+        let i0 = i1.saturating_sub(1);
+        let i3 = (i2 + 1).min(last);
+
+        let span = keys[i2].time - keys[i1].time;
+        let a = if span > 0.0 { ((time - keys[i1].time) / span).clamp(0.0, 1.0) } else { 0.0 };
+        let segment = [&keys[i0], &keys[i1], &keys[i2], &keys[i3]];
+
+        let position = self.interpolate(segment, a, |k| k.position);
+        let fov_y = self.interpolate(segment, a, |k| k.fov_y);
+
+        let all_look_at = segment.iter().all(|k| matches!(k.rotation, CameraRotation::LookAt(_)));
+        if all_look_at {
+            let target = self.interpolate(segment, a, |k| match k.rotation {
+                CameraRotation::LookAt(target) => target,
+                CameraRotation::Euler(_) => unreachable!()
+            });
+            (position, target, self.up, fov_y)
+        } else {
+            let angles = self.interpolate(segment, a, euler_angles);
+            let forward = rotate_zxy(vec3(0.0, 0.0, -1.0), angles);
+            let up = rotate_zxy(vec3(0.0, 1.0, 0.0), angles);
+            (position, position + forward, up, fov_y)
+        }
+    }
+
+    fn interpolate<T, F>(&self, segment: [&CameraKeyframe; 4], a: f32, value: F) -> T
+        where T: Add<Output=T> + Sub<Output=T> + Mul<f32, Output=T> + Copy,
+              F: Fn(&CameraKeyframe) -> T
+    {
+        let (p1, p2) = (value(segment[1]), value(segment[2]));
+        match self.interpolation {
+            CameraInterpolation::Linear => lerp(p1, p2, a),
+            CameraInterpolation::CatmullRom => {
+                let (p0, p3) = (value(segment[0]), value(segment[3]));
+                let (t0, t1, t2, t3) = (segment[0].time, segment[1].time, segment[2].time, segment[3].time);
+                // Tangents scaled to the segment, so that unevenly spaced keyframes don't overshoot.
+                let span = t2 - t1;
+                let m1 = if t2 > t0 { (p2 - p0) * (span / (t2 - t0)) } else { p2 - p1 };
+                let m2 = if t3 > t1 { (p3 - p1) * (span / (t3 - t1)) } else { p2 - p1 };
+                hermite(p1, m1, p2, m2, a)
+            }
+        }
+    }
+}
+
+impl RenderCamera for AnimatedCamera {
+    fn generate_ray(&self, sample: &CameraSample) -> Option<Ray> {
+        // Evaluated on the stack, as this runs for every camera sample.
+        let (position, look_at, up, fov_y) = self.pose_at(sample.time);
+        let basis = CameraBasis::look_at(position, look_at, up);
+        let projection = PerspectiveProjection { fov_y, aspect_ratio: self.aspect_ratio };
+        generate_lens_ray(&basis, &projection, self.lens, sample.s, sample.t, sample.lens_s, sample.lens_t)
+    }
+    fn has_lens(&self) -> bool {
+        self.lens.is_some()
+    }
+}
+
+// Cubic Hermite spline
+fn hermite<T>(p1: T, m1: T, p2: T, m2: T, a: f32) -> T
+    where T: Add<Output=T> + Mul<f32, Output=T> + Copy
+{
+    let a2 = a * a;
+    let a3 = a2 * a;
+    p1 * (2.0 * a3 - 3.0 * a2 + 1.0)
+        + m1 * (a3 - 2.0 * a2 + a)
+        + p2 * (-2.0 * a3 + 3.0 * a2)
+        + m2 * (a3 - a2)
+}
+
+// Euler angles of a keyframe. LookAt keyframes have no roll.
+// #todo-camera: Yaw of LookAt keyframes jumps at +-180 degrees.
+pub fn euler_angles(key: &CameraKeyframe) -> vec3f {
+    match key.rotation {
+        CameraRotation::Euler(angles) => angles,
+        CameraRotation::LookAt(target) => {
+            let f = (target - key.position).normalize();
+            let pitch = f.y.clamp(-1.0, 1.0).asin();
+            let yaw = (-f.x).atan2(-f.z);
+            vec3(pitch.to_degrees(), yaw.to_degrees(), 0.0)
+        }
+    }
+}
+
+// Rotates v around Z, then X, then Y axis. Angles in degrees.
+pub fn rotate_zxy(v: vec3f, angles: vec3f) -> vec3f {
+    let (sx, cx) = angles.x.to_radians().sin_cos();
+    let (sy, cy) = angles.y.to_radians().sin_cos();
+    let (sz, cz) = angles.z.to_radians().sin_cos();
+
+    let v = vec3(v.x * cz - v.y * sz, v.x * sz + v.y * cz, v.z);
+    let v = vec3(v.x, v.y * cx - v.z * sx, v.y * sx + v.z * cx);
+    vec3(v.x * cy + v.z * sy, v.y, -v.x * sy + v.z * cy)
+}
@@ -1,45 +1,321 @@
-use crate::math::vec3::vec3f;
+use crate::math::vec3::*;
 use crate::math::ray::Ray;
 
-pub struct Camera {
-    position: vec3f,
-    // precalculation
-    top_left: vec3f,
-    horizontal: vec3f,
-    vertical: vec3f,
-    // basis
-    _u: vec3f,
-    _v: vec3f,
-    _w: vec3f,
+// Thin lens model for depth of field.
+// Points on the focus plane stay sharp, others are blurred by the shape of the aperture.
+#[derive(Copy, Clone, Debug)]
+pub struct ThinLens {
+    pub aperture_radius: f32,
+    // Distance from the camera to the plane in focus, along the view direction.
+    pub focus_distance: f32,
+    // Polygonal aperture (bokeh shape) if 3 or more. Circular aperture otherwise.
+    pub blade_count: u32,
+    pub blade_rotation: f32, // in degrees
 }
 
-impl Camera {
-    pub fn new(position: vec3f, look_at: vec3f, up: vec3f, fov_y: f32, aspect_ratio: f32) -> Camera {
-        let theta = fov_y * std::f32::consts::PI / 180.0;
-        let half_height = (theta * 0.5).tan();
-        let half_width = aspect_ratio * half_height;
+impl ThinLens {
+    // f-number = focal_length / aperture_diameter
+    // `focal_length` is in world units (ex: 0.05 for a 50mm lens if 1 unit = 1 meter).
+    pub fn from_f_stop(f_stop: f32, focal_length: f32, focus_distance: f32) -> ThinLens {
+        ThinLens {
+            aperture_radius: 0.5 * focal_length / f_stop,
+            focus_distance,
+            blade_count: 0,
+            blade_rotation: 0.0
+        }
+    }
+
+    // Maps uniform random numbers in [0, 1) to a point on the aperture (unit radius).
+    pub fn sample_aperture(&self, r1: f32, r2: f32) -> (f32, f32) {
+        if self.blade_count < 3 {
+            return concentric_sample_disk(r1, r2);
+        }
+
+        // Pick one of the triangles (center, vertex i, vertex i+1) and sample it uniformly.
+        let n = self.blade_count as f32;
+        let x = r1 * n;
+        let blade = x.floor().min(n - 1.0);
+        let r1 = x - blade;
+
+        let sector = 2.0 * std::f32::consts::PI / n;
+        let theta0 = self.blade_rotation.to_radians() + blade * sector;
+        let theta1 = theta0 + sector;
+        let (a, b) = ((theta0.cos(), theta0.sin()), (theta1.cos(), theta1.sin()));
+
+        let su = r1.sqrt();
+        let (wa, wb) = (su * (1.0 - r2), su * r2);
+        (wa * a.0 + wb * b.0, wa * a.1 + wb * b.1)
+    }
+}
+
+// Uniform sampling of a unit disk with less distortion than polar mapping.
+// "A Low Distortion Map Between Disk and Square" (Shirley and Chiu 1997)
+fn concentric_sample_disk(r1: f32, r2: f32) -> (f32, f32) {
+    let sx = 2.0 * r1 - 1.0;
+    let sy = 2.0 * r2 - 1.0;
+    if sx == 0.0 && sy == 0.0 {
+        return (0.0, 0.0);
+    }
+    let quarter_pi = std::f32::consts::FRAC_PI_4;
+    let (r, theta) = if sx.abs() > sy.abs() {
+        (sx, quarter_pi * (sy / sx))
+    } else {
+        (sy, 2.0 * quarter_pi - quarter_pi * (sx / sy))
+    };
+    (r * theta.cos(), r * theta.sin())
+}
+
+// Everything needed to generate a camera ray for one pixel sample.
+#[derive(Copy, Clone, Debug)]
+pub struct CameraSample {
+    // Normalized screen coordinates in [0, 1], with t = 0 at the top.
+    pub s: f32,
+    pub t: f32,
+    // Uniform random numbers in [0, 1) for the point on the lens.
+    pub lens_s: f32,
+    pub lens_t: f32,
+    // Time of the sample inside the shutter interval (for motion blur).
+    pub time: f32,
+}
+
+// Cameras that the renderer can generate rays from.
+pub trait RenderCamera : Sync {
+    /// Returns None if the sample is outside of the projection.
+    fn generate_ray(&self, sample: &CameraSample) -> Option<Ray>;
+    /// Lens samples are only drawn if this is true.
+    fn has_lens(&self) -> bool;
+}
+
+// Generates rays in camera space: +x to right, +y to up, -z forward.
+// (s, t) are normalized screen coordinates in [0, 1], with t = 0 at the top.
+pub trait CameraProjection : Send + Sync {
+    /// Returns None if (s, t) is outside of the projection (ex: corners of a circular fisheye).
+    fn generate_ray(&self, s: f32, t: f32) -> Option<Ray>;
+
+    /// Inverse of generate_ray(). Returns (s, t) of a camera space position,
+    /// or None if it's not visible or the projection does not support it.
+    fn project(&self, _camera_position: vec3f) -> Option<(f32, f32)> {
+        None
+    }
+}
+
+pub struct PerspectiveProjection {
+    pub fov_y: f32, // in degrees
+    pub aspect_ratio: f32,
+}
+
+impl CameraProjection for PerspectiveProjection {
+    fn generate_ray(&self, s: f32, t: f32) -> Option<Ray> {
+        let half_height = (self.fov_y.to_radians() * 0.5).tan();
+        let half_width = self.aspect_ratio * half_height;
+        // 1 unit long along the view direction. ThinLens relies on this.
+        let dir = vec3((2.0 * s - 1.0) * half_width, (1.0 - 2.0 * t) * half_height, -1.0);
+        Some(Ray::new(vec3f::zero(), dir))
+    }
+    fn project(&self, p: vec3f) -> Option<(f32, f32)> {
+        if p.z >= 0.0 {
+            return None;
+        }
+        let half_height = (self.fov_y.to_radians() * 0.5).tan();
+        let half_width = self.aspect_ratio * half_height;
+        let s = 0.5 * (p.x / (-p.z * half_width) + 1.0);
+        let t = 0.5 * (1.0 - p.y / (-p.z * half_height));
+        Some((s, t))
+    }
+}
+
+// Parallel rays. Useful for technical views of voxel grids.
+pub struct OrthographicProjection {
+    // Size of the view in world units
+    pub width: f32,
+    pub height: f32,
+}
+
+impl CameraProjection for OrthographicProjection {
+    fn generate_ray(&self, s: f32, t: f32) -> Option<Ray> {
+        let origin = vec3((s - 0.5) * self.width, (0.5 - t) * self.height, 0.0);
+        Some(Ray::new(origin, vec3(0.0, 0.0, -1.0)))
+    }
+    fn project(&self, p: vec3f) -> Option<(f32, f32)> {
+        Some((p.x / self.width + 0.5, 0.5 - p.y / self.height))
+    }
+}
+
+// Latitude-longitude 360 degree panorama. The image center looks forward.
+pub struct EquirectangularProjection;
+
+impl CameraProjection for EquirectangularProjection {
+    fn generate_ray(&self, s: f32, t: f32) -> Option<Ray> {
+        let phi = (s - 0.5) * 2.0 * std::f32::consts::PI;
+        let theta = (0.5 - t) * std::f32::consts::PI;
+        let dir = vec3(theta.cos() * phi.sin(), theta.sin(), -theta.cos() * phi.cos());
+        Some(Ray::new(vec3f::zero(), dir))
+    }
+}
+
+// Equidistant fisheye (angle from the view direction is proportional to distance from the center).
+// The image circle fits the height of the image, so the left and right sides are black if aspect_ratio > 1.
+pub struct FisheyeProjection {
+    pub fov: f32, // Angle of view across the image circle, in degrees (180 for a dome master)
+    pub aspect_ratio: f32,
+}
+
+impl CameraProjection for FisheyeProjection {
+    fn generate_ray(&self, s: f32, t: f32) -> Option<Ray> {
+        let x = (2.0 * s - 1.0) * self.aspect_ratio;
+        let y = 1.0 - 2.0 * t;
+        let r = (x * x + y * y).sqrt();
+        if r > 1.0 {
+            return None;
+        }
+        let theta = r * self.fov.to_radians() * 0.5;
+        let phi = y.atan2(x);
+        let dir = vec3(theta.sin() * phi.cos(), theta.sin() * phi.sin(), -theta.cos());
+        Some(Ray::new(vec3f::zero(), dir))
+    }
+}
+
+// Position and orientation of a camera. +x to right, +y to up, -z forward in camera space.
+#[derive(Copy, Clone, Debug)]
+pub struct CameraBasis {
+    pub position: vec3f,
+    u: vec3f,
+    v: vec3f,
+    w: vec3f
+}
 
+impl CameraBasis {
+    pub fn look_at(position: vec3f, look_at: vec3f, up: vec3f) -> CameraBasis {
         let w = (position - look_at).normalize();
         let u = (up ^ w).normalize();
         let v = w ^ u;
 
-        let top_left = position - (half_width * u) - (half_height * v) - w;
-        let horizontal = 2.0 * half_width * u;
-        let vertical = 2.0 * half_height * v;
+        CameraBasis { position, u, v, w }
+    }
+
+    pub fn world_to_camera(&self, world_position: vec3f) -> vec3f {
+        let p = world_position - self.position;
+        vec3(p & self.u, p & self.v, p & self.w)
+    }
+
+    pub fn camera_to_world(&self, camera_position: vec3f) -> vec3f {
+        let p = camera_position;
+        self.position + p.x * self.u + p.y * self.v + p.z * self.w
+    }
+
+    // Camera space to world space
+    fn ray_to_world(&self, origin: vec3f, dir: vec3f) -> Ray {
+        let o = self.camera_to_world(origin);
+        let d = dir.x * self.u + dir.y * self.v + dir.z * self.w;
+        Ray::new(o, d.normalize())
+    }
+}
+
+// Ray through the point on the lens given by (lens_s, lens_t), uniform random numbers in [0, 1).
+// Through the center of the lens if there is no lens.
+// Shared by cameras that don't keep a Camera around (ex: AnimatedCamera evaluates its pose per sample).
+pub fn generate_lens_ray(
+    basis: &CameraBasis,
+    projection: &dyn CameraProjection,
+    lens: Option<ThinLens>,
+    s: f32, t: f32, lens_s: f32, lens_t: f32) -> Option<Ray>
+{
+    let ray = projection.generate_ray(s, t)?;
+    let lens = match lens {
+        // Depth of field is undefined for rays not looking forward (ex: equirectangular).
+        Some(lens) if lens.aperture_radius > 0.0 && ray.d.z < 0.0 => lens,
+        _ => return Some(basis.ray_to_world(ray.o, ray.d))
+    };
+
+    let focus_point = ray.o + ray.d * (lens.focus_distance / -ray.d.z);
 
-        Camera { position: position,
-            top_left: top_left,
-            horizontal: horizontal,
-            vertical: vertical,
-            _u: u, _v: v, _w: w }
+    let (lx, ly) = lens.sample_aperture(lens_s, lens_t);
+    let origin = ray.o + vec3(lx, ly, 0.0) * lens.aperture_radius;
+
+    Some(basis.ray_to_world(origin, focus_point - origin))
+}
+
+pub struct Camera {
+    basis: CameraBasis,
+    projection: Box<dyn CameraProjection>,
+    // Pinhole camera if None
+    lens: Option<ThinLens>,
+}
+
+impl Camera {
+    // Perspective camera
+    pub fn new(position: vec3f, look_at: vec3f, up: vec3f, fov_y: f32, aspect_ratio: f32) -> Camera {
+        let projection = PerspectiveProjection { fov_y, aspect_ratio };
+        Camera::with_projection(position, look_at, up, Box::new(projection))
     }
 
-    pub fn get_ray(&self, s: f32, t: f32) -> Ray {
-        let dir = self.top_left
-            + s * self.horizontal
-            + (1.0 - t) * self.vertical
-            - self.position;
-        
-        Ray::new(self.position, dir.normalize())
+    pub fn with_projection(position: vec3f, look_at: vec3f, up: vec3f, projection: Box<dyn CameraProjection>) -> Camera {
+        Camera { basis: CameraBasis::look_at(position, look_at, up), projection, lens: None }
+    }
+
+    pub fn set_projection(&mut self, projection: Box<dyn CameraProjection>) {
+        self.projection = projection;
+    }
+
+    pub fn set_lens(&mut self, lens: Option<ThinLens>) {
+        self.lens = lens;
+    }
+
+    pub fn get_lens(&self) -> Option<ThinLens> {
+        self.lens
+    }
+
+    // Ray through the center of the lens.
+    pub fn get_ray(&self, s: f32, t: f32) -> Option<Ray> {
+        generate_lens_ray(&self.basis, self.projection.as_ref(), None, s, t, 0.0, 0.0)
+    }
+
+    // Ray through the point on the lens given by (lens_s, lens_t), uniform random numbers in [0, 1).
+    // Same as get_ray() if there is no lens.
+    pub fn get_ray_through_lens(&self, s: f32, t: f32, lens_s: f32, lens_t: f32) -> Option<Ray> {
+        generate_lens_ray(&self.basis, self.projection.as_ref(), self.lens, s, t, lens_s, lens_t)
+    }
+
+    // World position to (s, t, depth), where depth is the distance along the view direction.
+    // None if the projection can't map it.
+    pub fn project(&self, world_position: vec3f) -> Option<vec3f> {
+        let p = self.world_to_camera(world_position);
+        let (s, t) = self.projection.project(p)?;
+        Some(vec3(s, t, -p.z))
+    }
+
+    // Inverse of project()
+    pub fn unproject(&self, s: f32, t: f32, depth: f32) -> Option<vec3f> {
+        let ray = self.projection.generate_ray(s, t)?;
+        if ray.d.z >= 0.0 {
+            return None;
+        }
+        let p = ray.o + ray.d * (depth / -ray.d.z);
+        Some(self.camera_to_world(p))
+    }
+
+    pub fn get_position(&self) -> vec3f {
+        self.basis.position
+    }
+
+    pub fn world_to_camera(&self, world_position: vec3f) -> vec3f {
+        self.basis.world_to_camera(world_position)
+    }
+
+    pub fn camera_to_world(&self, camera_position: vec3f) -> vec3f {
+        self.basis.camera_to_world(camera_position)
+    }
+}
+
+impl RenderCamera for Camera {
+    fn generate_ray(&self, sample: &CameraSample) -> Option<Ray> {
+        if self.lens.is_some() {
+            self.get_ray_through_lens(sample.s, sample.t, sample.lens_s, sample.lens_t)
+        } else {
+            self.get_ray(sample.s, sample.t)
+        }
+    }
+    fn has_lens(&self) -> bool {
+        self.lens.is_some()
     }
 }
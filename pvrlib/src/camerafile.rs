@@ -0,0 +1,264 @@
+use crate::math::vec3::*;
+use crate::camera::{Camera, ThinLens};
+use crate::animatedcamera::*;
+
+use std::fs;
+use std::io;
+
+use serde::Deserialize;
+
+// Import/export of camera animation.
+//
+// Nuke .chan: one keyframe per line, whitespace separated.
+//     frame tx ty tz rx ry rz [vfov]
+//     Rotation in degrees, ZXY order (Nuke's default for cameras).
+//
+// JSON:
+//     {
+//       "aspect_ratio": 1.5,
+//       "interpolation": "linear" | "catmull_rom",
+//       "lens": { "aperture_radius": 0.1, "focus_distance": 50, "blade_count": 6, "blade_rotation": 0 },
+//       "keyframes": [
+//         { "time": 0, "position": [0, 0, 50], "look_at": [0, 0, 0], "fov_y": 45 },
+//         { "time": 24, "position": [10, 0, 50], "rotation": [0, 10, 0], "fov_y": 45 }
+//       ]
+//     }
+//     "lens" is optional.
+
+// Static cameras are stored as a single keyframe. Reading them evaluates the animation at a given time.
+
+// Used if a .chan line has no fov column.
+const DEFAULT_FOV_Y: f32 = 45.0;
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// ----------------------------------------------------------
+// Nuke .chan
+
+pub fn parse_chan(text: &str) -> io::Result<Vec<CameraKeyframe>> {
+    let mut keyframes = Vec::new();
+    for (line_number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let values = line.split_whitespace()
+            .map(|token| token.parse::<f32>())
+            .collect::<Result<Vec<f32>, _>>()
+            .map_err(|e| invalid_data(format!("line {}: {}", line_number + 1, e)))?;
+        if values.iter().any(|x| !x.is_finite()) {
+            return Err(invalid_data(format!("line {}: non-finite value", line_number + 1)));
+        }
+        if values.len() != 7 && values.len() != 8 {
+            return Err(invalid_data(format!(
+                "line {}: expected 7 or 8 columns but got {}", line_number + 1, values.len())));
+        }
+
+        keyframes.push(CameraKeyframe {
+            time: values[0],
+            position: vec3(values[1], values[2], values[3]),
+            rotation: CameraRotation::Euler(vec3(values[4], values[5], values[6])),
+            fov_y: if values.len() == 8 { values[7] } else { DEFAULT_FOV_Y }
+        });
+    }
+    Ok(keyframes)
+}
+
+// LookAt keyframes are converted to Euler angles.
+pub fn format_chan(keyframes: &[CameraKeyframe]) -> String {
+    let mut text = String::new();
+    for key in keyframes {
+        let p = key.position;
+        let r = euler_angles(key);
+        text += &format!("{} {} {} {} {} {} {} {}\n", key.time, p.x, p.y, p.z, r.x, r.y, r.z, key.fov_y);
+    }
+    text
+}
+
+pub fn read_chan(filepath: &str, interpolation: CameraInterpolation, aspect_ratio: f32) -> io::Result<AnimatedCamera> {
+    let keyframes = parse_chan(&fs::read_to_string(filepath)?)?;
+    if keyframes.is_empty() {
+        return Err(invalid_data(format!("{}: no keyframes", filepath)));
+    }
+    Ok(AnimatedCamera::new(keyframes, interpolation, aspect_ratio))
+}
+
+pub fn write_chan(filepath: &str, camera: &AnimatedCamera) -> io::Result<()> {
+    fs::write(filepath, format_chan(camera.get_keyframes()))
+}
+
+// Camera::get_ray() is not affected by the aspect ratio of the keyframe, but the .chan format has no room for it.
+pub fn read_chan_camera(filepath: &str, time: f32, aspect_ratio: f32) -> io::Result<Camera> {
+    Ok(read_chan(filepath, CameraInterpolation::Linear, aspect_ratio)?.camera_at(time))
+}
+
+pub fn write_chan_camera(filepath: &str, camera: &Camera, fov_y: f32) -> io::Result<()> {
+    fs::write(filepath, format_chan(&[camera_keyframe(camera, 0.0, fov_y)]))
+}
+
+// ----------------------------------------------------------
+// Static cameras
+
+// Camera does not keep its field of view, so it's given separately.
+pub fn camera_keyframe(camera: &Camera, time: f32, fov_y: f32) -> CameraKeyframe {
+    let position = camera.get_position();
+    let axis = |v: vec3f| camera.camera_to_world(v) - position;
+    let (right, up, forward) = (axis(vec3(1.0, 0.0, 0.0)), axis(vec3(0.0, 1.0, 0.0)), axis(vec3(0.0, 0.0, -1.0)));
+
+    // Pitch and yaw from the view direction, then roll around it.
+    let pitch = forward.y.clamp(-1.0, 1.0).asin().to_degrees();
+    let yaw = (-forward.x).atan2(-forward.z).to_degrees();
+    let right0 = rotate_zxy(vec3(1.0, 0.0, 0.0), vec3(pitch, yaw, 0.0));
+    let up0 = rotate_zxy(vec3(0.0, 1.0, 0.0), vec3(pitch, yaw, 0.0));
+    let roll = (right & up0).atan2(right & right0).to_degrees();
+    debug_assert!((rotate_zxy(vec3(0.0, 1.0, 0.0), vec3(pitch, yaw, roll)) - up).length() < 1e-3);
+
+    CameraKeyframe { time, position, rotation: CameraRotation::Euler(vec3(pitch, yaw, roll)), fov_y }
+}
+
+// Single keyframe animation of a static camera, including its lens.
+pub fn camera_to_animated(camera: &Camera, fov_y: f32, aspect_ratio: f32) -> AnimatedCamera {
+    let mut animated = AnimatedCamera::new(vec![camera_keyframe(camera, 0.0, fov_y)], CameraInterpolation::Linear, aspect_ratio);
+    animated.lens = camera.get_lens();
+    animated
+}
+
+// ----------------------------------------------------------
+// JSON
+
+// Layout of the JSON file. Unknown keys are ignored.
+#[derive(Deserialize)]
+struct JsonCamera {
+    aspect_ratio: f32,
+    interpolation: JsonInterpolation,
+    lens: Option<JsonLens>,
+    keyframes: Vec<JsonKeyframe>
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum JsonInterpolation {
+    Linear,
+    CatmullRom
+}
+
+#[derive(Deserialize)]
+struct JsonLens {
+    aperture_radius: f32,
+    focus_distance: f32,
+    blade_count: u32,
+    blade_rotation: f32
+}
+
+#[derive(Deserialize)]
+struct JsonKeyframe {
+    time: f32,
+    position: [f32; 3],
+    look_at: Option<[f32; 3]>,
+    rotation: Option<[f32; 3]>,
+    fov_y: f32
+}
+
+// Numbers beyond f32 range become infinite when deserialized.
+fn finite(values: &[f32]) -> io::Result<()> {
+    match values.iter().find(|x| !x.is_finite()) {
+        Some(x) => Err(invalid_data(format!("non-finite number: {}", x))),
+        None => Ok(())
+    }
+}
+
+fn to_vec3(v: [f32; 3]) -> io::Result<vec3f> {
+    finite(&v)?;
+    Ok(vec3(v[0], v[1], v[2]))
+}
+
+pub fn parse_json_camera(text: &str) -> io::Result<AnimatedCamera> {
+    let root: JsonCamera = serde_json::from_str(text)
+        .map_err(|e| invalid_data(format!("json: {}", e)))?;
+
+    finite(&[root.aspect_ratio])?;
+    let interpolation = match root.interpolation {
+        JsonInterpolation::Linear => CameraInterpolation::Linear,
+        JsonInterpolation::CatmullRom => CameraInterpolation::CatmullRom
+    };
+
+    let mut keyframes = Vec::new();
+    for key in root.keyframes {
+        let rotation = match (key.look_at, key.rotation) {
+            (Some(look_at), None) => CameraRotation::LookAt(to_vec3(look_at)?),
+            (None, Some(rotation)) => CameraRotation::Euler(to_vec3(rotation)?),
+            _ => return Err(invalid_data("keyframe needs either look_at or rotation".to_string()))
+        };
+        finite(&[key.time, key.fov_y])?;
+        keyframes.push(CameraKeyframe {
+            time: key.time,
+            position: to_vec3(key.position)?,
+            rotation,
+            fov_y: key.fov_y
+        });
+    }
+    if keyframes.is_empty() {
+        return Err(invalid_data("no keyframes".to_string()));
+    }
+
+    let mut camera = AnimatedCamera::new(keyframes, interpolation, root.aspect_ratio);
+    if let Some(lens) = root.lens {
+        finite(&[lens.aperture_radius, lens.focus_distance, lens.blade_rotation])?;
+        camera.lens = Some(ThinLens {
+            aperture_radius: lens.aperture_radius,
+            focus_distance: lens.focus_distance,
+            blade_count: lens.blade_count,
+            blade_rotation: lens.blade_rotation
+        });
+    }
+    Ok(camera)
+}
+
+pub fn format_json_camera(camera: &AnimatedCamera) -> String {
+    let vec3_str = |v: vec3f| format!("[{}, {}, {}]", v.x, v.y, v.z);
+    let interpolation = match camera.interpolation {
+        CameraInterpolation::Linear => "linear",
+        CameraInterpolation::CatmullRom => "catmull_rom"
+    };
+
+    let mut text = String::from("{\n");
+    text += &format!("  \"aspect_ratio\": {},\n", camera.aspect_ratio);
+    text += &format!("  \"interpolation\": \"{}\",\n", interpolation);
+    if let Some(lens) = camera.lens {
+        text += &format!(
+            "  \"lens\": {{ \"aperture_radius\": {}, \"focus_distance\": {}, \"blade_count\": {}, \"blade_rotation\": {} }},\n",
+            lens.aperture_radius, lens.focus_distance, lens.blade_count, lens.blade_rotation);
+    }
+    text += "  \"keyframes\": [\n";
+    let keyframes = camera.get_keyframes();
+    for (i, key) in keyframes.iter().enumerate() {
+        let rotation = match key.rotation {
+            CameraRotation::LookAt(target) => format!("\"look_at\": {}", vec3_str(target)),
+            CameraRotation::Euler(angles) => format!("\"rotation\": {}", vec3_str(angles))
+        };
+        text += &format!(
+            "    {{ \"time\": {}, \"position\": {}, {}, \"fov_y\": {} }}{}\n",
+            key.time, vec3_str(key.position), rotation, key.fov_y,
+            if i + 1 < keyframes.len() { "," } else { "" });
+    }
+    text += "  ]\n}\n";
+    text
+}
+
+pub fn read_json_camera(filepath: &str) -> io::Result<AnimatedCamera> {
+    parse_json_camera(&fs::read_to_string(filepath)?)
+}
+
+pub fn write_json_camera(filepath: &str, camera: &AnimatedCamera) -> io::Result<()> {
+    fs::write(filepath, format_json_camera(camera))
+}
+
+pub fn read_json_static_camera(filepath: &str, time: f32) -> io::Result<Camera> {
+    Ok(read_json_camera(filepath)?.camera_at(time))
+}
+
+pub fn write_json_static_camera(filepath: &str, camera: &Camera, fov_y: f32, aspect_ratio: f32) -> io::Result<()> {
+    fs::write(filepath, format_json_camera(&camera_to_animated(camera, fov_y, aspect_ratio)))
+}
@@ -2,9 +2,14 @@ pub mod math;
 pub mod light;
 pub mod phasefn;
 pub mod camera;
+pub mod stereo;
+pub mod animatedcamera;
+pub mod camerafile;
 pub mod scene;
 pub mod voxelbuffer;
 pub mod primitive;
 pub mod volume;
+pub mod transfer;
 pub mod render;
 pub mod skyatmosphere;
+pub mod spectrum;
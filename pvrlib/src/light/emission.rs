@@ -0,0 +1,62 @@
+use super::*;
+use crate::volume::Volume;
+
+// Emissive volumes only add luminance along camera rays. To let them illuminate
+// other volumes (ex: an explosion lighting its own smoke), cluster the emission
+// into a coarse grid and replace each cell with a virtual point light.
+
+// Sub-samples per axis in a grid cell.
+const SAMPLES_PER_AXIS: i32 = 4;
+
+/// Create virtual point lights from emissive regions of `volume`.<br/>
+/// `grid_size` : Number of clusters along each axis of the volume's world bounds.<br/>
+/// `min_intensity` : Clusters dimmer than this are discarded.
+pub fn create_emission_lights(
+    volume: &dyn Volume,
+    grid_size: (i32, i32, i32),
+    min_intensity: f32) -> Vec<Box<dyn Light>>
+{
+    let bounds = volume.world_bounds();
+    let grid_sizef = vec3(grid_size.0 as f32, grid_size.1 as f32, grid_size.2 as f32);
+    let cell_size = bounds.size() / grid_sizef;
+    let sample_step = cell_size / (SAMPLES_PER_AXIS as f32);
+    let sample_volume = sample_step.x * sample_step.y * sample_step.z;
+
+    let mut lights: Vec<Box<dyn Light>> = Vec::new();
+    for x in 0..grid_size.0 {
+        for y in 0..grid_size.1 {
+            for z in 0..grid_size.2 {
+                let cell_min = bounds.min + cell_size * vec3(x as f32, y as f32, z as f32);
+
+                let mut intensity = vec3f::zero();
+                let mut centroid = vec3f::zero();
+                let mut weight = 0.0;
+                for i in 0..SAMPLES_PER_AXIS {
+                    for j in 0..SAMPLES_PER_AXIS {
+                        for k in 0..SAMPLES_PER_AXIS {
+                            let offset = vec3(i as f32 + 0.5, j as f32 + 0.5, k as f32 + 0.5);
+                            let p = cell_min + sample_step * offset;
+                            let emission = volume.emission(p);
+                            let w = emission.max_component();
+                            if w > 0.0 {
+                                // An emissive element dV radiates (emission * dV) in all directions.
+                                intensity += emission * sample_volume;
+                                centroid += p * w;
+                                weight += w;
+                            }
+                        }
+                    }
+                }
+
+                if weight > 0.0 && intensity.max_component() >= min_intensity {
+                    lights.push(Box::new(PointLight {
+                        position: centroid / weight,
+                        intensity
+                    }));
+                }
+            }
+        }
+    }
+
+    lights
+}
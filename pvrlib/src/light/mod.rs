@@ -0,0 +1,82 @@
+pub mod emission;
+
+use crate::math::vec3::*;
+
+use std::marker::Sync;
+
+pub struct LightSample {
+    pub luminance: vec3f, // #todo-light: Luminance? not radiance?
+    pub position: vec3f
+}
+
+pub trait Light : Sync {
+    // Luminance arriving at a given world position, ignoring occlusion.
+    fn sample(&self, ray_position: vec3f, ray_direction: vec3f) -> LightSample;
+
+    // World position of a point-like light, used for equiangular sampling.
+    // None if the light is not located at a single point.
+    fn get_position(&self) -> Option<vec3f>;
+}
+
+// Inverse square falloff, but clamped near the light to avoid infinite luminance.
+fn inverse_square_falloff(len_sq: f32) -> f32 {
+    if len_sq < 1.0 { 1.0 } else { 1.0 / len_sq }
+}
+
+pub struct PointLight {
+    pub position: vec3f,
+    pub intensity: vec3f // #todo-light: What? Give it a physical meaning
+}
+
+impl Light for PointLight {
+    fn sample(&self, ray_position: vec3f, _ray_direction: vec3f) -> LightSample {
+        let len_sq = (ray_position - self.position).length_sq();
+        let falloff = inverse_square_falloff(len_sq);
+
+        LightSample { luminance: self.intensity * falloff, position: self.position }
+    }
+
+    fn get_position(&self) -> Option<vec3f> {
+        Some(self.position)
+    }
+}
+
+// Point light that only emits inside a cone.
+pub struct SpotLight {
+    pub position: vec3f,
+    pub direction: vec3f, // Center of the cone (normalized)
+    pub intensity: vec3f,
+    pub inner_angle: f32, // Full intensity inside this half angle (degrees)
+    pub outer_angle: f32  // No light outside this half angle (degrees)
+}
+
+impl SpotLight {
+    fn cone_falloff(&self, wo: vec3f) -> f32 {
+        let cos_inner = self.inner_angle.to_radians().cos();
+        let cos_outer = self.outer_angle.to_radians().cos();
+        let cos_theta = wo.dot(self.direction);
+        if cos_theta >= cos_inner {
+            1.0
+        } else if cos_theta <= cos_outer {
+            0.0
+        } else {
+            // smoothstep between the outer and inner cones
+            let x = (cos_theta - cos_outer) / (cos_inner - cos_outer);
+            x * x * (3.0 - 2.0 * x)
+        }
+    }
+}
+
+impl Light for SpotLight {
+    fn sample(&self, ray_position: vec3f, _ray_direction: vec3f) -> LightSample {
+        let delta = ray_position - self.position;
+        let len_sq = delta.length_sq();
+        let falloff = inverse_square_falloff(len_sq) * self.cone_falloff(delta.normalize());
+
+        LightSample { luminance: self.intensity * falloff, position: self.position }
+    }
+
+    fn get_position(&self) -> Option<vec3f> {
+        Some(self.position)
+    }
+}
@@ -45,6 +45,11 @@ impl AABB {
 
         Some((t_near, t_far))
     }
+    pub fn contains(&self, p: vec3f) -> bool {
+        self.min.x <= p.x && p.x <= self.max.x
+            && self.min.y <= p.y && p.y <= self.max.y
+            && self.min.z <= p.z && p.z <= self.max.z
+    }
     // Minimum bounds that encompasses original AABBs
     pub fn extend(&self, other: AABB) -> AABB {
         AABB {
@@ -0,0 +1,113 @@
+use super::vec3::*;
+use super::ray::Ray;
+use super::aabb::AABB;
+
+// Bounding volume hierarchy over a list of AABBs.
+// Queries report indices into the list the BVH was built from.
+
+const MAX_LEAF_SIZE: usize = 4;
+
+struct BVHNode {
+    bounds: AABB,
+    // Leaf: items[first .. first + count]
+    // Inner: children are nodes[first] and nodes[first + 1], count is 0
+    first: usize,
+    count: usize
+}
+
+pub struct BVH {
+    nodes: Vec<BVHNode>,
+    items: Vec<usize>,
+    item_bounds: Vec<AABB>
+}
+
+impl BVH {
+    // Top-down build, splitting at the median centroid along the longest axis.
+    pub fn new(bounds: &[AABB]) -> BVH {
+        let mut bvh = BVH {
+            nodes: Vec::new(),
+            items: (0..bounds.len()).collect(),
+            item_bounds: bounds.to_vec()
+        };
+        if !bounds.is_empty() {
+            bvh.nodes.push(BVHNode { bounds: AABB::default(), first: 0, count: bounds.len() });
+            bvh.subdivide(0, bounds);
+        }
+        bvh
+    }
+
+    fn subdivide(&mut self, node_index: usize, bounds: &[AABB]) {
+        let (first, count) = (self.nodes[node_index].first, self.nodes[node_index].count);
+        let range = first..(first + count);
+
+        let mut node_bounds = bounds[self.items[first]];
+        let mut centroid_min = node_bounds.center();
+        let mut centroid_max = centroid_min;
+        for &item in &self.items[range.clone()] {
+            node_bounds = node_bounds.extend(bounds[item]);
+            centroid_min = vec3f::min(centroid_min, bounds[item].center());
+            centroid_max = vec3f::max(centroid_max, bounds[item].center());
+        }
+        self.nodes[node_index].bounds = node_bounds;
+
+        if count <= MAX_LEAF_SIZE {
+            return;
+        }
+
+        let extent = centroid_max - centroid_min;
+        let axis = if extent.x >= extent.y && extent.x >= extent.z { 0 } else if extent.y >= extent.z { 1 } else { 2 };
+        let mid = count / 2;
+        self.items[range].select_nth_unstable_by(mid, |&a, &b| {
+            bounds[a].center()[axis].total_cmp(&bounds[b].center()[axis])
+        });
+
+        let left = self.nodes.len();
+        self.nodes.push(BVHNode { bounds: AABB::default(), first, count: mid });
+        self.nodes.push(BVHNode { bounds: AABB::default(), first: first + mid, count: count - mid });
+        self.nodes[node_index].first = left;
+        self.nodes[node_index].count = 0;
+
+        self.subdivide(left, bounds);
+        self.subdivide(left + 1, bounds);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    // Calls `f` for every item whose bounds contain `p`.
+    pub fn query_point<F: FnMut(usize)>(&self, p: vec3f, f: F) {
+        self.traverse(|bounds| bounds.contains(p), f);
+    }
+
+    // Calls `f` for every item whose bounds are hit by the ray.
+    // Bounds are tested only, so `f` should find the exact intersection.
+    pub fn query_ray<F: FnMut(usize)>(&self, ray: Ray, f: F) {
+        self.traverse(|bounds| bounds.intersect(ray).is_some(), f);
+    }
+
+    fn traverse<P, F>(&self, overlaps: P, mut f: F)
+        where P: Fn(&AABB) -> bool, F: FnMut(usize)
+    {
+        if self.nodes.is_empty() {
+            return;
+        }
+        let mut stack = vec![0];
+        while let Some(node_index) = stack.pop() {
+            let node = &self.nodes[node_index];
+            if !overlaps(&node.bounds) {
+                continue;
+            }
+            if node.count > 0 {
+                for &item in &self.items[node.first .. node.first + node.count] {
+                    if overlaps(&self.item_bounds[item]) {
+                        f(item);
+                    }
+                }
+            } else {
+                stack.push(node.first);
+                stack.push(node.first + 1);
+            }
+        }
+    }
+}
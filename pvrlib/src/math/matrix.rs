@@ -0,0 +1,161 @@
+use super::vec3::*;
+use super::aabb::AABB;
+use std::ops::Mul;
+
+// 4x4 matrix for affine transforms. Row-major, multiplied with column vectors (p' = M * p).
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Matrix4 {
+    pub m: [[f32; 4]; 4]
+}
+
+impl Matrix4 {
+    pub fn identity() -> Matrix4 {
+        Matrix4 { m: [
+            [1.0, 0.0, 0.0, 0.0],
+            [0.0, 1.0, 0.0, 0.0],
+            [0.0, 0.0, 1.0, 0.0],
+            [0.0, 0.0, 0.0, 1.0]] }
+    }
+
+    pub fn translation(t: vec3f) -> Matrix4 {
+        Matrix4 { m: [
+            [1.0, 0.0, 0.0, t.x],
+            [0.0, 1.0, 0.0, t.y],
+            [0.0, 0.0, 1.0, t.z],
+            [0.0, 0.0, 0.0, 1.0]] }
+    }
+
+    pub fn scale(s: vec3f) -> Matrix4 {
+        Matrix4 { m: [
+            [s.x, 0.0, 0.0, 0.0],
+            [0.0, s.y, 0.0, 0.0],
+            [0.0, 0.0, s.z, 0.0],
+            [0.0, 0.0, 0.0, 1.0]] }
+    }
+
+    // Rotations in degrees, counter-clockwise when looking from the positive axis.
+    pub fn rotation_x(degrees: f32) -> Matrix4 {
+        let (s, c) = degrees.to_radians().sin_cos();
+        Matrix4 { m: [
+            [1.0, 0.0, 0.0, 0.0],
+            [0.0, c, -s, 0.0],
+            [0.0, s, c, 0.0],
+            [0.0, 0.0, 0.0, 1.0]] }
+    }
+    pub fn rotation_y(degrees: f32) -> Matrix4 {
+        let (s, c) = degrees.to_radians().sin_cos();
+        Matrix4 { m: [
+            [c, 0.0, s, 0.0],
+            [0.0, 1.0, 0.0, 0.0],
+            [-s, 0.0, c, 0.0],
+            [0.0, 0.0, 0.0, 1.0]] }
+    }
+    pub fn rotation_z(degrees: f32) -> Matrix4 {
+        let (s, c) = degrees.to_radians().sin_cos();
+        Matrix4 { m: [
+            [c, -s, 0.0, 0.0],
+            [s, c, 0.0, 0.0],
+            [0.0, 0.0, 1.0, 0.0],
+            [0.0, 0.0, 0.0, 1.0]] }
+    }
+
+    pub fn transpose(&self) -> Matrix4 {
+        let mut result = Matrix4 { m: [[0.0; 4]; 4] };
+        for i in 0..4 {
+            for j in 0..4 {
+                result.m[i][j] = self.m[j][i];
+            }
+        }
+        result
+    }
+
+    // Gauss-Jordan elimination with partial pivoting. None if singular or not finite.
+    pub fn inverse(&self) -> Option<Matrix4> {
+        if self.m.iter().flatten().any(|x| !x.is_finite()) {
+            return None;
+        }
+        let mut a = self.m;
+        let mut inv = Matrix4::identity().m;
+        for col in 0..4 {
+            let pivot = (col..4)
+                .max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs()))
+                .unwrap();
+            if a[pivot][col].abs() < 1.0e-12 {
+                return None;
+            }
+            a.swap(col, pivot);
+            inv.swap(col, pivot);
+
+            let d = 1.0 / a[col][col];
+            for j in 0..4 {
+                a[col][j] *= d;
+                inv[col][j] *= d;
+            }
+            for i in 0..4 {
+                if i != col {
+                    let f = a[i][col];
+                    for j in 0..4 {
+                        a[i][j] -= f * a[col][j];
+                        inv[i][j] -= f * inv[col][j];
+                    }
+                }
+            }
+        }
+        Some(Matrix4 { m: inv })
+    }
+
+    pub fn transform_position(&self, p: vec3f) -> vec3f {
+        let m = &self.m;
+        vec3(
+            m[0][0] * p.x + m[0][1] * p.y + m[0][2] * p.z + m[0][3],
+            m[1][0] * p.x + m[1][1] * p.y + m[1][2] * p.z + m[1][3],
+            m[2][0] * p.x + m[2][1] * p.y + m[2][2] * p.z + m[2][3])
+    }
+
+    // Ignores translation. The result is not normalized, so a ray transformed
+    // by transform_position() and transform_direction() keeps the same t.
+    pub fn transform_direction(&self, d: vec3f) -> vec3f {
+        let m = &self.m;
+        vec3(
+            m[0][0] * d.x + m[0][1] * d.y + m[0][2] * d.z,
+            m[1][0] * d.x + m[1][1] * d.y + m[1][2] * d.z,
+            m[2][0] * d.x + m[2][1] * d.y + m[2][2] * d.z)
+    }
+
+    // Smallest AABB that contains the transformed box.
+    pub fn transform_aabb(&self, bounds: AABB) -> AABB {
+        let mut result = AABB {
+            min: vec3(f32::MAX, f32::MAX, f32::MAX),
+            max: vec3(f32::MIN, f32::MIN, f32::MIN)
+        };
+        for i in 0..8 {
+            let corner = vec3(
+                if i & 1 == 0 { bounds.min.x } else { bounds.max.x },
+                if i & 2 == 0 { bounds.min.y } else { bounds.max.y },
+                if i & 4 == 0 { bounds.min.z } else { bounds.max.z });
+            let p = self.transform_position(corner);
+            result.min = vec3f::min(result.min, p);
+            result.max = vec3f::max(result.max, p);
+        }
+        result
+    }
+}
+
+impl Default for Matrix4 {
+    fn default() -> Matrix4 {
+        Matrix4::identity()
+    }
+}
+
+impl Mul<Matrix4> for Matrix4 {
+    type Output = Matrix4;
+    fn mul(self, rhs: Matrix4) -> Matrix4 {
+        let mut result = Matrix4 { m: [[0.0; 4]; 4] };
+        for i in 0..4 {
+            for j in 0..4 {
+                result.m[i][j] = (0..4).map(|k| self.m[i][k] * rhs.m[k][j]).sum();
+            }
+        }
+        result
+    }
+}
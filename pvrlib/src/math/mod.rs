@@ -4,6 +4,10 @@ pub mod aabb;
 pub mod noise;
 pub mod sphere;
 pub mod random;
+pub mod matrix;
+pub mod rotator;
+pub mod transform;
+pub mod bvh;
 
 // ----------------------------------------------------------
 // Analysis
@@ -28,3 +32,57 @@ pub fn solve_quadratic(a: f32, b: f32, c: f32) -> Option<(f32, f32)> {
         }
     }
 }
+
+// Real roots of (c[0] + c[1] * x + c[2] * xx + ...) in [x_min, x_max], in ascending order.
+// The range is split at the roots of the derivative, so each piece is monotonic and can be bisected.
+pub fn solve_polynomial(coeffs: &[f64], x_min: f64, x_max: f64) -> Vec<f64> {
+    let mut n = coeffs.len();
+    while n > 0 && coeffs[n - 1] == 0.0 {
+        n -= 1;
+    }
+    let coeffs = &coeffs[..n];
+    if n <= 1 {
+        return Vec::new();
+    }
+    if n == 2 {
+        let x = -coeffs[0] / coeffs[1];
+        return if x_min <= x && x <= x_max { vec![x] } else { Vec::new() };
+    }
+
+    let eval = |x: f64| coeffs.iter().rev().fold(0.0, |acc, c| acc * x + c);
+    let derivative: Vec<f64> = (1..n).map(|i| coeffs[i] * i as f64).collect();
+    let mut bounds = vec![x_min];
+    bounds.append(&mut solve_polynomial(&derivative, x_min, x_max));
+    bounds.push(x_max);
+
+    let mut roots: Vec<f64> = Vec::new();
+    for w in bounds.windows(2) {
+        let (mut lo, mut hi) = (w[0], w[1]);
+        let (f_lo, f_hi) = (eval(lo), eval(hi));
+        if f_lo == 0.0 {
+            if roots.last() != Some(&lo) {
+                roots.push(lo);
+            }
+            continue;
+        }
+        if f_lo * f_hi > 0.0 {
+            continue;
+        }
+        if f_hi == 0.0 {
+            roots.push(hi);
+            continue;
+        }
+        for _ in 0..100 {
+            let mid = 0.5 * (lo + hi);
+            if mid <= lo || mid >= hi {
+                break;
+            }
+            if (eval(mid) < 0.0) == (f_lo < 0.0) { lo = mid; } else { hi = mid; }
+        }
+        roots.push(0.5 * (lo + hi));
+    }
+    if eval(x_max) == 0.0 && roots.last() != Some(&x_max) {
+        roots.push(x_max);
+    }
+    roots
+}
@@ -54,19 +54,25 @@ pub fn fBm(p: Vec3, octaves: i32, octave_gain: f32, lacunarity: f32) -> f32 {
 ///*
 #[allow(non_snake_case)]
 pub fn fBm(x0: vec3f) -> f32 {
+    fBm_octaves(x0, 5, 0.5, 2.0)
+}
+//*/
+
+// Sum of `octaves` noises. Each octave scales amplitude by `gain` and frequency by `lacunarity`.
+#[allow(non_snake_case)]
+pub fn fBm_octaves(x0: vec3f, octaves: u32, gain: f32, lacunarity: f32) -> f32 {
     let mut x = x0;
-	let mut v: f32 = 0.0;
-	let mut a: f32 = 0.5;
+    let mut v: f32 = 0.0;
+    let mut a: f32 = 0.5;
     let shift = vec3(100.0, 100.0, 100.0);
-    for _i in 0..5 {
-		v += a * noise(x);
-		x = x * 2.0 + shift;
-		a *= 0.5;
+    for _i in 0..octaves {
+        v += a * noise(x);
+        x = x * lacunarity + shift;
+        a *= gain;
     }
-    
-	return v
+
+    v
 }
-//*/
 
 pub fn pyroclastic(distance: f32, noise: f32, filter_width: f32) -> f32 {
     let width = filter_width * 0.5;
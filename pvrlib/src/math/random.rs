@@ -22,7 +22,9 @@ impl MT19937 {
         };
         result.mt[0] = seed;
         for mti in 1..NN {
-            result.mt[mti] = 6364136223846793005 * (result.mt[mti-1] ^ (result.mt[mti-1] >> 62)) + (mti as u64);
+            result.mt[mti] = 6364136223846793005_u64
+                .wrapping_mul(result.mt[mti-1] ^ (result.mt[mti-1] >> 62))
+                .wrapping_add(mti as u64);
         }
 
         result
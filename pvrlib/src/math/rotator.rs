@@ -0,0 +1,30 @@
+use super::vec3::*;
+use super::matrix::Matrix4;
+
+// Euler angles in degrees.
+// Applied in the order of roll (around Z), pitch (around X), then yaw (around Y),
+// the same as CameraRotation::Euler (rx = pitch, ry = yaw, rz = roll).
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub struct Rotator {
+    pub yaw: f32,
+    pub pitch: f32,
+    pub roll: f32
+}
+
+pub fn rotator(yaw: f32, pitch: f32, roll: f32) -> Rotator {
+    Rotator { yaw, pitch, roll }
+}
+
+impl Rotator {
+    pub fn zero() -> Rotator {
+        Rotator { yaw: 0.0, pitch: 0.0, roll: 0.0 }
+    }
+
+    pub fn to_matrix(&self) -> Matrix4 {
+        Matrix4::rotation_y(self.yaw) * Matrix4::rotation_x(self.pitch) * Matrix4::rotation_z(self.roll)
+    }
+
+    pub fn rotate_vector(&self, v: vec3f) -> vec3f {
+        self.to_matrix().transform_direction(v)
+    }
+}
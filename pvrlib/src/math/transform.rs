@@ -0,0 +1,44 @@
+use super::vec3::*;
+use super::matrix::Matrix4;
+use super::rotator::Rotator;
+
+// Scale, then rotation, then translation.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Transform {
+    pub translation: vec3f,
+    pub rotation: Rotator,
+    pub scale: vec3f
+}
+
+impl Transform {
+    pub fn identity() -> Transform {
+        Transform {
+            translation: vec3f::zero(),
+            rotation: Rotator::zero(),
+            scale: vec3f::one()
+        }
+    }
+
+    pub fn to_matrix(&self) -> Matrix4 {
+        Matrix4::translation(self.translation) * self.rotation.to_matrix() * Matrix4::scale(self.scale)
+    }
+
+    pub fn transform_position(&self, p: vec3f) -> vec3f {
+        self.to_matrix().transform_position(p)
+    }
+    pub fn transform_direction(&self, dir: vec3f) -> vec3f {
+        self.to_matrix().transform_direction(dir)
+    }
+}
+
+impl Default for Transform {
+    fn default() -> Transform {
+        Transform::identity()
+    }
+}
+
+impl From<Transform> for Matrix4 {
+    fn from(transform: Transform) -> Matrix4 {
+        transform.to_matrix()
+    }
+}
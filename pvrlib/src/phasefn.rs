@@ -1,5 +1,10 @@
 use crate::math::vec3::*;
 
+use std::fs;
+use std::io;
+
+const PI: f32 = std::f32::consts::PI;
+
 // (1.0 / 4pi)
 pub const ISOTROPIC_PHASE_FN: f32 = 1.0 / (4.0 * std::f32::consts::PI);
 
@@ -7,8 +12,36 @@ pub const ISOTROPIC_PHASE_FN: f32 = 1.0 / (4.0 * std::f32::consts::PI);
 // more performant less flexible. Can't decide which will be better, but switching
 // between them will be not that hard.
 // Scattering prob. given incoming and outgoing directions.
-pub trait PhaseFunction : Sync {
+pub trait PhaseFunction : Send + Sync {
     fn probability(&self, wi: vec3f, wo: vec3f) -> f32;
+
+    /// Sample an outgoing direction for the incoming direction `wi`.<br/>
+    /// Directions are distributed exactly as probability(wi, wo), so the pdf is the probability itself.<br/>
+    /// `u1`, `u2` : Uniform random numbers in [0, 1).
+    fn sample(&self, wi: vec3f, u1: f32, u2: f32) -> vec3f;
+}
+
+// Direction at angle acos(cos_theta) from `axis`, rotated by 2pi * u around it.
+pub fn direction_around(axis: vec3f, cos_theta: f32, u: f32) -> vec3f {
+    let cos_theta = cos_theta.clamp(-1.0, 1.0);
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let (sin_phi, cos_phi) = (2.0 * std::f32::consts::PI * u).sin_cos();
+
+    // Orthonormal basis around the axis
+    let helper = if axis.x.abs() > 0.9 { vec3(0.0, 1.0, 0.0) } else { vec3(1.0, 0.0, 0.0) };
+    let t1 = (axis ^ helper).normalize();
+    let t2 = axis ^ t1;
+
+    t1 * (sin_theta * cos_phi) + t2 * (sin_theta * sin_phi) + axis * cos_theta
+}
+
+// cos(theta) between wi and wo for Henyey-Greenstein, by inverting its CDF.
+fn sample_hg_cos_theta(g: f32, u: f32) -> f32 {
+    if g.abs() < 1e-3 {
+        return 1.0 - 2.0 * u;
+    }
+    let k = (1.0 - g * g) / (1.0 - g + 2.0 * g * u);
+    (1.0 + g * g - k * k) / (2.0 * g)
 }
 
 pub struct Isotropic {}
@@ -16,6 +49,9 @@ impl PhaseFunction for Isotropic {
     fn probability(&self, _wi: vec3f, _wo: vec3f) -> f32 {
         ISOTROPIC_PHASE_FN
     }
+    fn sample(&self, wi: vec3f, u1: f32, u2: f32) -> vec3f {
+        direction_around(wi, 1.0 - 2.0 * u1, u2)
+    }
 }
 
 // Similar to specular behavior in a surface BRDF
@@ -34,6 +70,9 @@ impl PhaseFunction for HenyeyGreenstein {
         ISOTROPIC_PHASE_FN * (1.0 - g * g) /
             (1.0 + g * g - 2.0 * g * t).powf(1.5)
     }
+    fn sample(&self, wi: vec3f, u1: f32, u2: f32) -> vec3f {
+        direction_around(wi, sample_hg_cos_theta(self.g, u1), u2)
+    }
 }
 
 // Can represent both diffuse and specular behaviors
@@ -57,4 +96,253 @@ impl PhaseFunction for DoubleHenyeyGreenstein {
         
         b * hg1 + (1.0 - b) * hg2
     }
+    // Pick a lobe with probability b, then reuse u1 to sample it.
+    fn sample(&self, wi: vec3f, u1: f32, u2: f32) -> vec3f {
+        let cos_theta = if u1 < self.b {
+            sample_hg_cos_theta(self.g1, u1 / self.b)
+        } else {
+            sample_hg_cos_theta(self.g2, (u1 - self.b) / (1.0 - self.b))
+        };
+        direction_around(wi, cos_theta, u2)
+    }
+}
+
+// ----------------------------------------------------------
+// Atmosphere and cloud phase functions
+
+pub fn rayleigh_phase(cos_theta: f32) -> f32 {
+    3.0 / (16.0 * PI) * (1.0 + cos_theta * cos_theta)
+}
+
+// Improved HG for Mie scattering by aerosols.
+pub fn cornette_shanks_phase(g: f32, cos_theta: f32) -> f32 {
+    let gg = g * g;
+    let num = 3.0 * (1.0 - gg) * (1.0 + cos_theta * cos_theta);
+    let denom = (8.0 * PI) * (2.0 + gg) * (1.0 + gg - 2.0 * g * cos_theta).powf(1.5);
+    num / denom
+}
+
+// Scattering by air molecules
+pub struct Rayleigh {}
+impl PhaseFunction for Rayleigh {
+    fn probability(&self, wi: vec3f, wo: vec3f) -> f32 {
+        rayleigh_phase(wi & wo)
+    }
+    // Invert the CDF (cos^3 + 3cos + 4) / 8 = u1 with Cardano's formula.
+    fn sample(&self, wi: vec3f, u1: f32, u2: f32) -> vec3f {
+        let q = 4.0 * u1 - 2.0;
+        let d = (q * q + 1.0).sqrt();
+        let cos_theta = (q + d).cbrt() + (q - d).cbrt();
+        direction_around(wi, cos_theta, u2)
+    }
+}
+
+// Sampled from a tabulated CDF, so the sample distribution is a close approximation of probability().
+pub struct CornetteShanks {
+    g: f32,
+    table: PhaseTable
+}
+impl CornetteShanks {
+    pub fn new(g: f32) -> CornetteShanks {
+        CornetteShanks { g, table: PhaseTable::from_fn(|cos_theta| cornette_shanks_phase(g, cos_theta)) }
+    }
+    pub fn get_g(&self) -> f32 {
+        self.g
+    }
+}
+impl PhaseFunction for CornetteShanks {
+    fn probability(&self, wi: vec3f, wo: vec3f) -> f32 {
+        cornette_shanks_phase(self.g, wi & wo)
+    }
+    fn sample(&self, wi: vec3f, u1: f32, u2: f32) -> vec3f {
+        direction_around(wi, self.table.sample_cos_theta(u1), u2)
+    }
+}
+
+// Cheaper approximation of HG without powf().
+pub struct Schlick {
+    // -1.0 ~ 1.0, same sign convention as HenyeyGreenstein::g
+    pub k: f32
+}
+impl Schlick {
+    // Approximate k for the given HG eccentricity.
+    pub fn from_hg(g: f32) -> Schlick {
+        Schlick { k: 1.55 * g - 0.55 * g * g * g }
+    }
+}
+impl PhaseFunction for Schlick {
+    fn probability(&self, wi: vec3f, wo: vec3f) -> f32 {
+        let k = self.k;
+        let denom = 1.0 - k * (wi & wo);
+        ISOTROPIC_PHASE_FN * (1.0 - k * k) / (denom * denom)
+    }
+    fn sample(&self, wi: vec3f, u1: f32, u2: f32) -> vec3f {
+        let k = self.k;
+        let cos_theta = (2.0 * u1 + k - 1.0) / (2.0 * k * u1 + 1.0 - k);
+        direction_around(wi, cos_theta, u2)
+    }
+}
+
+// Draine's phase function for interstellar dust and water droplets.
+// alpha = 0 is HenyeyGreenstein and alpha = 1 is CornetteShanks.
+// Sampled from a tabulated CDF like CornetteShanks.
+pub struct Draine {
+    g: f32,
+    alpha: f32,
+    table: PhaseTable
+}
+impl Draine {
+    pub fn new(g: f32, alpha: f32) -> Draine {
+        Draine { g, alpha, table: PhaseTable::from_fn(|cos_theta| draine_phase(g, alpha, cos_theta)) }
+    }
+    pub fn get_g(&self) -> f32 {
+        self.g
+    }
+    pub fn get_alpha(&self) -> f32 {
+        self.alpha
+    }
+}
+fn draine_phase(g: f32, alpha: f32, cos_theta: f32) -> f32 {
+    let gg = g * g;
+    let hg = ISOTROPIC_PHASE_FN * (1.0 - gg) / (1.0 + gg - 2.0 * g * cos_theta).powf(1.5);
+    hg * (1.0 + alpha * cos_theta * cos_theta) / (1.0 + alpha * (1.0 + 2.0 * gg) / 3.0)
+}
+impl PhaseFunction for Draine {
+    fn probability(&self, wi: vec3f, wo: vec3f) -> f32 {
+        draine_phase(self.g, self.alpha, wi & wo)
+    }
+    fn sample(&self, wi: vec3f, u1: f32, u2: f32) -> vec3f {
+        direction_around(wi, self.table.sample_cos_theta(u1), u2)
+    }
+}
+
+// Phase function from measured or precomputed data (ex: Mie output for cloud droplets).
+// Values are linearly interpolated in cos(theta) and normalized over the sphere,
+// so sampling matches probability() exactly.
+pub struct TabulatedPhaseFunction {
+    table: PhaseTable
+}
+impl TabulatedPhaseFunction {
+    // `samples` : (scattering angle in degrees, phase value). Any scale, it's normalized.
+    pub fn new(samples: &[(f32, f32)]) -> io::Result<TabulatedPhaseFunction> {
+        if samples.len() < 2 {
+            return Err(invalid_data(format!("needs at least 2 samples but got {}", samples.len())));
+        }
+        if let Some(&(angle, value)) = samples.iter().find(|(angle, value)| !(0.0..=180.0).contains(angle) || *value < 0.0) {
+            return Err(invalid_data(format!("invalid sample: angle = {}, value = {}", angle, value)));
+        }
+        let mut points: Vec<(f32, f32)> = samples.iter()
+            .map(|&(angle, value)| (angle.to_radians().cos(), value))
+            .collect();
+        points.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        // The table covers the whole sphere.
+        if points[0].0 > -1.0 {
+            points.insert(0, (-1.0, points[0].1));
+        }
+        if points[points.len() - 1].0 < 1.0 {
+            points.push((1.0, points[points.len() - 1].1));
+        }
+        match PhaseTable::new(points) {
+            Some(table) => Ok(TabulatedPhaseFunction { table }),
+            None => Err(invalid_data("phase values are all zero".to_string()))
+        }
+    }
+
+    // One "angle,value" pair per line. Empty lines, '#' comments and a header line are skipped.
+    pub fn parse_csv(text: &str) -> io::Result<TabulatedPhaseFunction> {
+        let mut samples = Vec::new();
+        for (line_number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let values = line.split(',')
+                .map(|token| token.trim().parse::<f32>())
+                .collect::<Result<Vec<f32>, _>>();
+            match values {
+                Ok(values) if values.len() == 2 => samples.push((values[0], values[1])),
+                Ok(values) => return Err(invalid_data(format!(
+                    "line {}: expected 2 columns but got {}", line_number + 1, values.len()))),
+                Err(_) if samples.is_empty() => continue, // header
+                Err(e) => return Err(invalid_data(format!("line {}: {}", line_number + 1, e)))
+            }
+        }
+        TabulatedPhaseFunction::new(&samples)
+    }
+
+    pub fn read_csv(filepath: &str) -> io::Result<TabulatedPhaseFunction> {
+        TabulatedPhaseFunction::parse_csv(&fs::read_to_string(filepath)?)
+    }
+}
+impl PhaseFunction for TabulatedPhaseFunction {
+    fn probability(&self, wi: vec3f, wo: vec3f) -> f32 {
+        self.table.evaluate(wi & wo)
+    }
+    fn sample(&self, wi: vec3f, u1: f32, u2: f32) -> vec3f {
+        direction_around(wi, self.table.sample_cos_theta(u1), u2)
+    }
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Resolution for tabulating analytic phase functions
+const PHASE_TABLE_SIZE: usize = 512;
+
+// Piecewise linear phase function over cos(theta) with its CDF.
+struct PhaseTable {
+    cos_theta: Vec<f32>, // ascending from -1 to 1
+    values: Vec<f32>,    // normalized over the sphere
+    cdf: Vec<f32>
+}
+impl PhaseTable {
+    // Uniform in theta, which puts more points near the forward and backward peaks.
+    fn from_fn<F: Fn(f32) -> f32>(phase: F) -> PhaseTable {
+        let points = (0..=PHASE_TABLE_SIZE).rev()
+            .map(|i| (PI * i as f32 / PHASE_TABLE_SIZE as f32).cos())
+            .map(|cos_theta| (cos_theta, phase(cos_theta)))
+            .collect();
+        PhaseTable::new(points).expect("PhaseTable: phase function is zero everywhere")
+    }
+
+    fn new(points: Vec<(f32, f32)>) -> Option<PhaseTable> {
+        let cos_theta: Vec<f32> = points.iter().map(|p| p.0).collect();
+        let mut values: Vec<f32> = points.iter().map(|p| p.1).collect();
+
+        // Integral over the sphere is 2pi * integral over cos(theta).
+        let mut cdf = vec![0.0; points.len()];
+        for i in 1..points.len() {
+            let area = 0.5 * (values[i - 1] + values[i]) * (cos_theta[i] - cos_theta[i - 1]);
+            cdf[i] = cdf[i - 1] + 2.0 * PI * area;
+        }
+        let total = cdf[cdf.len() - 1];
+        if total <= 0.0 {
+            return None;
+        }
+        values.iter_mut().for_each(|v| *v /= total);
+        cdf.iter_mut().for_each(|c| *c /= total);
+
+        Some(PhaseTable { cos_theta, values, cdf })
+    }
+
+    fn evaluate(&self, cos_theta: f32) -> f32 {
+        let i = self.cos_theta.partition_point(|&c| c < cos_theta).clamp(1, self.cos_theta.len() - 1);
+        let (c0, c1) = (self.cos_theta[i - 1], self.cos_theta[i]);
+        let a = if c1 > c0 { ((cos_theta - c0) / (c1 - c0)).clamp(0.0, 1.0) } else { 0.0 };
+        lerp(self.values[i - 1], self.values[i], a)
+    }
+
+    // Exact inversion of the CDF, which is quadratic in each bin.
+    fn sample_cos_theta(&self, u: f32) -> f32 {
+        let i = self.cdf.partition_point(|&c| c <= u).clamp(1, self.cdf.len() - 1);
+        let (c0, c1) = (self.cos_theta[i - 1], self.cos_theta[i]);
+        let (v0, v1) = (self.values[i - 1], self.values[i]);
+
+        // Solve 0.5 * (v1 - v0) * x^2 + v0 * x = a for x in [0, 1].
+        let a = (u - self.cdf[i - 1]) / (2.0 * PI * (c1 - c0));
+        let denom = v0 + (v0 * v0 + 2.0 * (v1 - v0) * a).max(0.0).sqrt();
+        let x = if denom > 0.0 { (2.0 * a / denom).clamp(0.0, 1.0) } else { 0.5 };
+        c0 + x * (c1 - c0)
+    }
 }
@@ -1,6 +1,7 @@
 pub mod rast;
 
-use crate::volume::voxel::VoxelVolume;
+use crate::math::vec3::*;
+use crate::voxelbuffer::VoxelBuffer;
 
 // #wip
 /*
@@ -16,5 +17,54 @@ pub trait Primitive {
 }
 
 pub trait RasterizationPrimitive : Primitive {
-    fn rasterize(&self, voxel_buffer: &mut VoxelVolume);
+    fn rasterize(&self, target: &mut dyn RasterizationTarget);
+}
+
+// Voxel volumes that primitives can rasterize into.
+// The mapping between world and voxel space is not necessarily linear (ex: FrustumVolume).
+pub trait RasterizationTarget {
+    /// world position to voxel coord.
+    fn world_to_voxel(&self, world_position: vec3f) -> vec3f;
+    /// voxel coord to world position.
+    fn voxel_to_world(&self, voxel_coord: vec3f) -> vec3f;
+    /// world position to local uvw in voxel volume.
+    fn world_to_local(&self, world_position: vec3f) -> vec3f;
+
+    fn get_buffer(&mut self) -> &mut dyn VoxelBuffer<f32>;
+
+    /// False if world_to_voxel() can't map the position (ex: behind the camera of a frustum).
+    fn can_project(&self, _world_position: vec3f) -> bool {
+        true
+    }
+}
+
+/// Range of voxel coords that covers the world space box [world_min, world_max], clamped to the buffer.<br/>
+/// Voxel coords of all 8 corners are considered, as the mapping might not preserve axes.
+/// The whole buffer if any corner can't be projected.
+pub fn voxel_bounds(
+    target: &mut dyn RasterizationTarget,
+    world_min: vec3f,
+    world_max: vec3f) -> ((i32, i32, i32), (i32, i32, i32))
+{
+    let size = target.get_buffer().get_size();
+    let mut p_min = vec3f::new(f32::MAX, f32::MAX, f32::MAX);
+    let mut p_max = vec3f::new(f32::MIN, f32::MIN, f32::MIN);
+    for i in 0..8 {
+        let corner = vec3(
+            if i & 1 == 0 { world_min.x } else { world_max.x },
+            if i & 2 == 0 { world_min.y } else { world_max.y },
+            if i & 4 == 0 { world_min.z } else { world_max.z });
+        if !target.can_project(corner) {
+            return ((0, 0, 0), size);
+        }
+        let p = target.world_to_voxel(corner);
+        p_min = vec3f::min(p_min, p);
+        p_max = vec3f::max(p_max, p);
+    }
+
+    let clamp = |x: f32, n: i32| (x as i32).clamp(0, n);
+    (
+        (clamp(p_min.x.floor(), size.0), clamp(p_min.y.floor(), size.1), clamp(p_min.z.floor(), size.2)),
+        (clamp(p_max.x.ceil(), size.0), clamp(p_max.y.ceil(), size.1), clamp(p_max.z.ceil(), size.2))
+    )
 }
@@ -1,6 +1,5 @@
 use crate::math::vec3::*;
 use crate::primitive::*;
-use crate::volume::voxel::VoxelVolume;
 
 pub struct Line {
     pub	p0: vec3f,   // World position of a vertex
@@ -23,15 +22,11 @@ impl Line {
 impl Primitive for Line {}
 
 impl RasterizationPrimitive for Line {
-    fn rasterize(&self, voxel_volume: &mut VoxelVolume) {
-        let p0 = voxel_volume.world_to_voxel(self.p0);
-        let p1 = voxel_volume.world_to_voxel(self.p1);
+    fn rasterize(&self, target: &mut dyn RasterizationTarget) {
         let aug = vec3(self.radius, self.radius, self.radius);
-
-        let p_min = vec3(f32::min(p0.x, p1.x), f32::min(p0.y, p1.y), f32::min(p0.z, p1.z)) - aug;
-        let p_max = vec3(f32::max(p0.x, p1.x), f32::max(p0.y, p1.y), f32::max(p0.z, p1.z)) + aug;
-        let (x_min, y_min, z_min) = (p_min.x as i32, p_min.y as i32, p_min.z as i32);
-        let (x_max, y_max, z_max) = (p_max.x as i32, p_max.y as i32, p_max.z as i32);
+        let ws_min = vec3f::min(self.p0, self.p1) - aug;
+        let ws_max = vec3f::max(self.p0, self.p1) + aug;
+        let ((x_min, y_min, z_min), (x_max, y_max, z_max)) = voxel_bounds(target, ws_min, ws_max);
 
         println!("Rasterize a line: min=({}, {}, {}) max=({}, {}, {})",
             x_min, y_min, z_min, x_max, y_max, z_max);
@@ -40,9 +35,9 @@ impl RasterizationPrimitive for Line {
             for y in y_min .. y_max {
                 for z in z_min .. z_max {
                     let vs_pos = vec3(x as f32, y as f32, z as f32);
-                    let density = self.density(voxel_volume.voxel_to_world(vs_pos));
+                    let density = self.density(target.voxel_to_world(vs_pos));
                     if density != 0.0 {
-                        voxel_volume.get_buffer().write(x, y, z, density);
+                        target.get_buffer().write(x, y, z, density);
                     }
                 }
             }
@@ -1,6 +1,5 @@
 use crate::math::vec3::*;
 use crate::primitive::*;
-use crate::volume::voxel::VoxelVolume;
 
 use crate::math::noise::*; // pyroclastic test
 
@@ -27,13 +26,12 @@ impl Primitive for PyroclasticPoint {
 }
 
 impl RasterizationPrimitive for PyroclasticPoint {
-    fn rasterize(&self, voxel_volume: &mut VoxelVolume) {
-        let p_min: vec3f = voxel_volume.world_to_voxel(self.center - self.radius.into());
-        let p_max: vec3f = voxel_volume.world_to_voxel(self.center + self.radius.into());
-        let (x_min, y_min, z_min) = (p_min.x as i32, p_min.y as i32, p_min.z as i32);
-        let (x_max, y_max, z_max) = (p_max.x as i32, p_max.y as i32, p_max.z as i32);
+    fn rasterize(&self, target: &mut dyn RasterizationTarget) {
+        let ((x_min, y_min, z_min), (x_max, y_max, z_max)) = voxel_bounds(
+            target, self.center - self.radius.into(), self.center + self.radius.into());
 
-        println!("Rasterize a PyroclasticPoint: vs_bounds={{min: {:?}, max: {:?}}}", p_min, p_max);
+        println!("Rasterize a PyroclasticPoint: vs_bounds={{min: {:?}, max: {:?}}}",
+            (x_min, y_min, z_min), (x_max, y_max, z_max));
 
         //let mut rng = crate::math::random::MT19937::new(0);
 
@@ -41,9 +39,9 @@ impl RasterizationPrimitive for PyroclasticPoint {
             for y in y_min .. y_max {
                 for z in z_min .. z_max {
                     let vs_pos = vec3(x as f32, y as f32, z as f32);
-                    let density = self.density(voxel_volume.voxel_to_world(vs_pos));
+                    let density = self.density(target.voxel_to_world(vs_pos));
 
-                    let ws_pos = voxel_volume.voxel_to_world(vs_pos);
+                    let ws_pos = target.voxel_to_world(vs_pos);
                     let ls_pos = (ws_pos - self.center) / self.radius;
 
                     // #todo-noise: From the book's source code, but looks weird
@@ -57,7 +55,7 @@ impl RasterizationPrimitive for PyroclasticPoint {
                     //let pyro;
                     //if is_pyroclastic {
                     //    let sphere_func = ls_pos.length() - 1.0;
-                    //    let filter_width = voxel_volume.world_bounds.size().length() / self.radius;
+                    //    let filter_width = target.world_bounds.size().length() / self.radius;
                     //    pyro = pyroclastic(sphere_func, noise, filter_width);
                     //} else {
                     //    let distance_func = 1.0 - ls_pos.length();
@@ -71,7 +69,7 @@ impl RasterizationPrimitive for PyroclasticPoint {
                     let pyro = pyroclastic(sphere_func, noise, filter_width);
 
 					if density != 0.0 {
-	                    voxel_volume.get_buffer().write(x, y, z, density * pyro);
+	                    target.get_buffer().write(x, y, z, density * pyro);
 					}
                 }
             }
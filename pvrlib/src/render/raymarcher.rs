@@ -1,7 +1,10 @@
 use crate::math::vec3::*;
 use crate::math::ray::*;
+use crate::math::random::MT19937;
 use crate::light::*;
 use crate::volume::*;
+use crate::spectrum::rgb_to_spectral;
+use super::renderer::RenderSettings;
 
 /* Math cheatsheet
 
@@ -21,86 +24,354 @@ pub struct IntegrationResult {
     pub transmittance: vec3f
 }
 
+// How in-scattering from point-like lights is estimated.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum LightSampling {
+	// Evaluate every light at every primary step.
+	Raymarch,
+	// Equiangular distance sampling toward each point-like light,
+	// combined with transmittance-based distance sampling by MIS.
+	Equiangular
+}
+
+// A single primary raymarching step.
+// Recorded to evaluate and sample the transmittance along the camera ray.
+struct MarchStep {
+	t: f32,        // start of the step
+	dt: f32,       // step length
+	tau: vec3f,    // optical thickness accumulated before this step
+	sigma_t: vec3f // extinction coefficient in this step
+}
+
+fn average(v: vec3f) -> f32 {
+	(v.x + v.y + v.z) / 3.0
+}
+
+// In spectral mode, each channel holds a value at the corresponding wavelength.
+fn to_spectral(rgb: vec3f, wavelengths: Option<vec3f>) -> vec3f {
+	match wavelengths {
+		Some(w) => rgb_to_spectral(rgb, w),
+		None => rgb
+	}
+}
+
+// Transmittance from the ray origin to ray.at(t).
+fn march_transmittance(march: &[MarchStep], t: f32) -> vec3f {
+	for step in march {
+		if t < step.t {
+			return (-step.tau).exp();
+		} else if t < step.t + step.dt {
+			return (-(step.tau + step.sigma_t * (t - step.t))).exp();
+		}
+	}
+	match march.last() {
+		Some(step) => (-(step.tau + step.sigma_t * step.dt)).exp(),
+		None => vec3f::one()
+	}
+}
+
+// Samples a distance proportional to (sigma_t * transmittance) along the camera ray.
+// Operates on the channel average of extinction.
+struct DistanceSampler<'a> {
+	march: &'a [MarchStep],
+	total_tau: f32
+}
+
+impl DistanceSampler<'_> {
+	fn new(march: &[MarchStep]) -> DistanceSampler<'_> {
+		let total_tau = match march.last() {
+			Some(step) => average(step.tau + step.sigma_t * step.dt),
+			None => 0.0
+		};
+		DistanceSampler { march, total_tau }
+	}
+
+	fn is_valid(&self) -> bool {
+		self.total_tau > 1.0e-6
+	}
+
+	// Returns (t, pdf)
+	fn sample(&self, u: f32) -> Option<(f32, f32)> {
+		if !self.is_valid() {
+			return None;
+		}
+		let norm = 1.0 - (-self.total_tau).exp();
+		let target = -(1.0 - u * norm).ln();
+		for step in self.march {
+			let tau0 = average(step.tau);
+			let sigma = average(step.sigma_t);
+			let tau1 = tau0 + sigma * step.dt;
+			if target < tau1 && sigma > 0.0 {
+				let t = step.t + (target - tau0).max(0.0) / sigma;
+				return Some((t, sigma * (-target).exp() / norm));
+			}
+		}
+		None
+	}
+
+	fn pdf(&self, t: f32) -> f32 {
+		if !self.is_valid() {
+			return 0.0;
+		}
+		let norm = 1.0 - (-self.total_tau).exp();
+		for step in self.march {
+			if t < step.t {
+				return 0.0;
+			} else if t < step.t + step.dt {
+				let sigma = average(step.sigma_t);
+				let tau = average(step.tau) + sigma * (t - step.t);
+				return sigma * (-tau).exp() / norm;
+			}
+		}
+		0.0
+	}
+}
+
+// Samples a distance proportional to the inverse squared distance to a point.
+// See "Importance Sampling Techniques for Path Tracing in Participating Media" (Kulla and Fajardo, 2012)
+struct EquiangularSampler {
+	delta: f32, // ray time of the closest point to the light
+	dist: f32,  // distance between the ray and the light
+	theta_a: f32,
+	theta_b: f32
+}
+
+impl EquiangularSampler {
+	fn new(ray: Ray, light_position: vec3f, t_min: f32, t_max: f32) -> EquiangularSampler {
+		let delta = (light_position - ray.o).dot(ray.d);
+		let dist = (ray.at(delta) - light_position).length().max(1.0e-4);
+		EquiangularSampler {
+			delta,
+			dist,
+			theta_a: ((t_min - delta) / dist).atan(),
+			theta_b: ((t_max - delta) / dist).atan()
+		}
+	}
+
+	// Returns (t, pdf)
+	fn sample(&self, u: f32) -> (f32, f32) {
+		let theta = lerp(self.theta_a, self.theta_b, u);
+		let t = self.delta + self.dist * theta.tan();
+		(t, self.pdf(t))
+	}
+
+	fn pdf(&self, t: f32) -> f32 {
+		let x = t - self.delta;
+		self.dist / ((self.theta_b - self.theta_a) * (self.dist * self.dist + x * x))
+	}
+}
+
+// Transmittance from p to the light, raymarched with the secondary step size.
+#[allow(non_snake_case)]
+fn light_transmittance(
+	vol: &dyn Volume,
+	p: vec3f,
+	light_position: vec3f,
+	secondary_step_size: f32,
+	wavelengths: Option<vec3f>) -> vec3f
+{
+	let wi = (light_position - p).normalize();
+
+	let mut T_L: vec3f = vec3f::one(); // Transmittance(P -> light)
+	let mut tau = vec3f::zero(); // Optical thickness
+	let mut t_L = 0.0;
+
+	let mut t_L_end = (light_position - p).length();
+	for (_, t_L_end2) in vol.find_intersections(Ray::new(p, wi)) {
+		t_L_end = if t_L_end2 < t_L_end { t_L_end2 } else { t_L_end };
+	}
+
+	while t_L < t_L_end {
+		let p_L = p + wi * t_L;
+		let sigma_a_L = to_spectral(vol.absorption_coeff(p_L), wavelengths);
+
+		tau += sigma_a_L * secondary_step_size;
+		T_L = (-tau).exp();
+		if T_L.max_component() < 0.01 {
+			break;
+		}
+
+		t_L += secondary_step_size;
+	}
+
+	T_L
+}
+
+// Single scattered luminance toward the camera at ray.at(t), not divided by any pdf.
+fn single_scattering(
+	vol: &dyn Volume,
+	ray: Ray,
+	t: f32,
+	light: &dyn Light,
+	march: &[MarchStep],
+	secondary_step_size: f32,
+	wavelengths: Option<vec3f>) -> vec3f
+{
+	let p = ray.at(t);
+	let sigma_s = to_spectral(vol.scattering_coeff(p), wavelengths);
+	if sigma_s.max_component() <= 0.0 {
+		return vec3f::zero();
+	}
+
+	let light_sample: LightSample = light.sample(p, ray.d);
+	let wi = (light_sample.position - p).normalize();
+	let luminance = to_spectral(light_sample.luminance, wavelengths);
+	let transmittance_light = light_transmittance(vol, p, light_sample.position, secondary_step_size, wavelengths);
+	let sc_prob = vol.phase_function(p, -wi, ray.d);
+
+	march_transmittance(march, t) * sigma_s * sc_prob * luminance * transmittance_light
+}
+
 // #todo: UniformRaymarcher, AdaptiveRaymarcher
+// `wavelengths` : Wavelengths (nanometers) for spectral rendering. If given, RGB properties
+//                 of volumes and lights are upsampled to spectral values at each wavelength
+//                 and the result holds spectral values instead of RGB.
 #[allow(non_snake_case)]
 pub fn integrate_ray(
 	vol: &dyn Volume,
 	ray: Ray,
 	lights: &[Box<dyn Light>],
-	primary_step_size: f32,
-	secondary_step_size: f32) -> IntegrationResult
+	settings: &RenderSettings,
+	rng: &mut MT19937,
+	wavelengths: Option<vec3f>) -> IntegrationResult
 {
-	// #todo-refactor: Interval struct
-	// Integration bounds
-	let intervals: Vec<(f32, f32)> = vol.find_intersections(ray);
-	
+	let primary_step_size = settings.primary_step_size;
+	let secondary_step_size = settings.secondary_step_size;
+	let equiangular = settings.light_sampling == LightSampling::Equiangular;
+
+	// Integration bounds (ignore the part behind the ray origin)
+	let segments: Vec<VolumeSegment> = vol.find_segments(ray)
+		.into_iter()
+		.filter(|segment| segment.t_max > 0.0)
+		.map(|segment| VolumeSegment { t_min: segment.t_min.max(0.0), ..segment })
+		.collect();
+
 	let mut T: vec3f = vec3f::one(); // total transmittance
 	let mut L: vec3f = vec3f::zero(); // total luminance
+	let mut march: Vec<MarchStep> = Vec::new();
+	let mut tau = vec3f::zero();
 
 	// Loop for primary ray
-	for (t_start, t_end) in intervals {
-		let mut t_current = t_start;
-	
-		while t_current < t_end {
+	'segments: for segment in &segments {
+		let mut t_current = segment.t_min;
+
+		while t_current < segment.t_max {
 			let p_i: vec3f = ray.at(t_current);
+			// The last step is cut at the segment end, so that boundaries are not counted twice.
+			let dt = primary_step_size.min(segment.t_max - t_current);
 
-			// Sample the volume
-			let vol_sample: VolumeSample = vol.sample(p_i);
-			let L_em = vol_sample.emission;
-			let sigma_a = vol_sample.absorption_coeff;
-			let sigma_s = vol_sample.scattering_coeff;
+			// Sample the lighting model's attributes (LIGHTING_ATTRIBUTES) at once.
+			// Other attributes are read by handle only in integrate_attribute().
+			let vol_sample: VolumeSample = vol.sample_segment(p_i, segment);
+			let L_em = to_spectral(vol_sample.emission, wavelengths);
+			let sigma_a = to_spectral(vol_sample.absorption_coeff, wavelengths);
+			let sigma_s = to_spectral(vol_sample.scattering_coeff, wavelengths);
 
 			let mut L_sc = vec3f::zero(); // luminance by scattering
-			
+
 			// Loop for secondary ray
 			for light in lights {
+				// Point-like lights are handled by equiangular sampling below.
+				if equiangular && light.get_position().is_some() {
+					continue;
+				}
+
 				let light_sample: LightSample = light.sample(p_i, ray.d);
 				let wi = (light_sample.position - p_i).normalize();
-
-				let mut T_L: vec3f = vec3f::one(); // Transmittance(P -> light)
-				let mut tau = vec3f::zero(); // Optical thickness
-				{
-					let mut t_L = 0.0;
-
-					let mut t_L_end = (light_sample.position - p_i).length();
-					for (_, t_L_end2) in vol.find_intersections(Ray::new(p_i, wi)) {
-						t_L_end = if t_L_end2 < t_L_end { t_L_end2 } else { t_L_end };
-					}
-
-					while t_L < t_L_end {
-						let p_L = p_i + wi * t_L;
-						let sigma_a_L = vol.absorption_coeff(p_L);
-
-						tau += sigma_a_L * secondary_step_size;
-						T_L = (-tau).exp();
-						if T_L.max_component() < 0.01 {
-							break;
-						}
-
-						t_L += secondary_step_size;
-					}
-				}
+				let T_L = light_transmittance(vol, p_i, light_sample.position, secondary_step_size, wavelengths);
 
 				// Scattering probability
 				let sc_prob = vol.phase_function(p_i, -wi, ray.d);
 
 				// #todo: L_sc contributes almost nothing. (sc_prob is too small)
-				L_sc += sigma_s * sc_prob * light_sample.luminance * T_L;
+				L_sc += sigma_s * sc_prob * to_spectral(light_sample.luminance, wavelengths) * T_L;
 			}
-			
-			let T_i: vec3f = (-sigma_a * primary_step_size).exp();
+
+			let T_i: vec3f = (-sigma_a * dt).exp();
 
 			T *= T_i;
-			L += (L_em + L_sc) * T * primary_step_size;
+			L += (L_em + L_sc) * T * dt;
+
+			if equiangular {
+				march.push(MarchStep { t: t_current, dt, tau, sigma_t: sigma_a });
+				tau += sigma_a * dt;
+			}
 
 			// Stop raymarching if too opaque
 			if T.max_component() < 0.01 {
-				break;
+				break 'segments;
 			}
 
-			t_current += primary_step_size;
+			t_current += dt;
 		}
 	}
-	
+
+	if equiangular && !segments.is_empty() {
+		let t_min = segments[0].t_min;
+		let t_max = segments[segments.len() - 1].t_max;
+		let num_samples = settings.light_sample_count.max(1);
+		let distance_sampler = DistanceSampler::new(&march);
+
+		for light in lights {
+			let light_position = match light.get_position() {
+				Some(position) => position,
+				None => continue
+			};
+			let equiangular_sampler = EquiangularSampler::new(ray, light_position, t_min, t_max);
+
+			// One sample from each strategy, combined by the balance heuristic:
+			// f / p_i * (p_i / (p_eq + p_tr)) = f / (p_eq + p_tr)
+			let mut L_light = vec3f::zero();
+			for _ in 0..num_samples {
+				let (t, pdf_eq) = equiangular_sampler.sample(rng.rand() as f32);
+				let pdf_sum = pdf_eq + distance_sampler.pdf(t);
+				if pdf_sum > 0.0 {
+					L_light += single_scattering(vol, ray, t, light.as_ref(), &march, secondary_step_size, wavelengths) / pdf_sum;
+				}
+
+				if let Some((t, pdf_tr)) = distance_sampler.sample(rng.rand() as f32) {
+					if t >= t_min && t <= t_max {
+						let pdf_sum = pdf_tr + equiangular_sampler.pdf(t);
+						L_light += single_scattering(vol, ray, t, light.as_ref(), &march, secondary_step_size, wavelengths) / pdf_sum;
+					}
+				}
+			}
+			L += L_light / (num_samples as f32);
+		}
+	}
+
 	IntegrationResult { luminance: L, transmittance: T }
 }
+
+// AOV of a named attribute (ex: temperature, velocity) for custom shaders and compositing.
+// Attribute values along the ray are weighted by the opacity of each step and the transmittance
+// in front of it, so the result is premultiplied like the luminance.
+// Returns None if the volume doesn't have the attribute.
+#[allow(non_snake_case)]
+pub fn integrate_attribute(vol: &dyn Volume, ray: Ray, attr: &AttrRef, step_size: f32) -> Option<vec3f> {
+	if !vol.attributes().contains(attr) {
+		return None;
+	}
+
+	let mut T = 1.0;
+	let mut value = vec3f::zero();
+	for segment in vol.find_segments(ray) {
+		let mut t_current = segment.t_min.max(0.0);
+		while t_current < segment.t_max {
+			let p_i = ray.at(t_current);
+			let dt = step_size.min(segment.t_max - t_current);
+			let opacity = 1.0 - (-average(vol.sample_segment(p_i, &segment).absorption_coeff) * dt).exp();
+			if opacity > 0.0 {
+				if let Some(v) = vol.sample_attribute(attr, p_i) {
+					value += v * (T * opacity);
+				}
+				T *= 1.0 - opacity;
+				if T < 0.01 {
+					return Some(value);
+				}
+			}
+			t_current += dt;
+		}
+	}
+	Some(value)
+}
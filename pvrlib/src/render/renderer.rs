@@ -1,9 +1,12 @@
 use super::rendertarget::*;
 use super::raymarcher::*;
 use crate::math::vec3::*;
-use crate::camera::Camera;
+use crate::math::random::MT19937;
+use crate::camera::*;
+use crate::stereo::StereoRig;
 use crate::scene::Scene;
 use crate::skyatmosphere::SkyAtmosphere;
+use crate::spectrum::*;
 use crate::render::tone_mapping::*;
 
 use std::ops::Deref;
@@ -23,6 +26,34 @@ pub struct RenderSettings {
     pub camera_origin: vec3f,
     pub camera_lookat: vec3f,
     pub fov: f32,
+    pub light_sampling: LightSampling,
+    pub light_sample_count: u32, // Samples per point-like light for LightSampling::Equiangular
+    pub samples_per_pixel: u32,  // Pixel positions are jittered if more than 1
+    pub spectral: bool,          // Sample wavelengths per pixel sample instead of RGB
+    pub time: f32,                      // Time of the frame, for animated cameras
+    pub shutter_interval: (f32, f32),   // Open and close time relative to `time`. Motion blur if not empty.
+}
+
+impl Default for RenderSettings {
+    fn default() -> RenderSettings {
+        RenderSettings {
+            work_group_size: (16, 16),
+            exposure: 1.0,
+            gamma: 2.2,
+            primary_step_size: 1.0,
+            secondary_step_size: 1.0,
+            draw_sky: true,
+            camera_origin: vec3(0.0, 0.0, 50.0),
+            camera_lookat: vec3f::zero(),
+            fov: 45.0,
+            light_sampling: LightSampling::Raymarch,
+            light_sample_count: 1,
+            samples_per_pixel: 1,
+            spectral: false,
+            time: 0.0,
+            shutter_interval: (0.0, 0.0)
+        }
+    }
 }
 
 // Handles change of render progress.
@@ -62,27 +93,47 @@ impl Renderer<'_> {
         }
     }
 
-    pub fn render(&mut self, camera: &Camera, scene: &Scene) {
+    pub fn render(&mut self, camera: &dyn RenderCamera, scene: &Scene) {
+        let width = self.render_target.get_width();
+        let height = self.render_target.get_height();
+        self.progress.get_mut().unwrap().set_total((width * height) as u32);
+
+        self.render_view(camera, scene, 0, width);
+    }
+
+    // Renders the left eye into the left half of the render target and the right eye into the right half.
+    pub fn render_stereo(&mut self, rig: &StereoRig, scene: &Scene) {
         let width = self.render_target.get_width();
         let height = self.render_target.get_height();
-        let inv_width = 1.0 / (width as f32);
+        self.progress.get_mut().unwrap().set_total((width * height) as u32);
+
+        let half_width = width / 2;
+        self.render_view(&rig.left_camera(), scene, 0, half_width);
+        self.render_view(&rig.right_camera(), scene, half_width, width - half_width);
+    }
+
+    // Renders the camera into columns [view_x, view_x + view_width) of the render target.
+    fn render_view(&mut self, camera: &dyn RenderCamera, scene: &Scene, view_x: usize, view_width: usize) {
+        let width = self.render_target.get_width();
+        let height = self.render_target.get_height();
+        let inv_width = 1.0 / (view_width as f32);
         let inv_height = 1.0 / (height as f32);
 
         // Partition the whole region into subregions
         let work_group_size = self.settings.work_group_size;
         let work_group_count = (
-            (width / work_group_size.0) + if width % work_group_size.0 == 0 { 0 } else { 1 },
+            (view_width / work_group_size.0) + if view_width % work_group_size.0 == 0 { 0 } else { 1 },
             (height / work_group_size.1) + if height % work_group_size.1 == 0 { 0 } else { 1 }
         );
         let mut regions = Vec::new();
         for i in 0..(work_group_count.0) {
             for j in 0..(work_group_count.1) {
-                let x = i * work_group_size.0;
+                let x = view_x + i * work_group_size.0;
                 let y = j * work_group_size.1;
                 let region = RenderRegion {
                     x0: x,
                     y0: y,
-                    x1: std::cmp::min(x + work_group_size.0, width),
+                    x1: std::cmp::min(x + work_group_size.0, view_x + view_width),
                     y1: std::cmp::min(y + work_group_size.1, height),
                     data: Vec::new()
                 };
@@ -90,38 +141,83 @@ impl Renderer<'_> {
             }
         }
 
+        let settings = self.settings;
         let exposure = self.settings.exposure;
         let gamma = self.settings.gamma;
-        let step_size1 = self.settings.primary_step_size;
-        let step_size2 = self.settings.secondary_step_size;
+        let spp = self.settings.samples_per_pixel.max(1);
+        let has_lens = camera.has_lens();
+        let (shutter_open, shutter_close) = self.settings.shutter_interval;
 
         // Raymarching
-        let total_pixels = width * height;
-        self.progress.lock().unwrap().set_total(total_pixels as u32);
-        
         regions.par_iter_mut().for_each(|region| {
+            // Seed by region so that the result does not depend on thread scheduling.
+            let mut rng = MT19937::new((region.y0 * width + region.x0) as u64);
+
             // Render a subregion
             for y in region.y0 .. region.y1 {
                 for x in region.x0 .. region.x1 {
-                    let u = (x as f32) * inv_width;
-                    let v = (y as f32) * inv_height;
-                    let ray = camera.get_ray(u, v);
-
-                    let result: IntegrationResult = integrate_ray(
-                        scene.volume.deref(),
-                        ray,
-                        &scene.lights,
-                        step_size1,
-                        step_size2);
-                    
-                    let mut luminance = result.luminance;
-                    let transmittance = result.transmittance;
-                    
-                    // #todo-sky: Move into integrate_ray?
-                    // Atmosphere is not a mere background texture. It should affect volumes on the ground.
-                    let ray_on_earth = SkyAtmosphere::get_camera_ray_on_earth(ray);
-                    let sky_sample = scene.sky_atmosphere.sample(ray_on_earth);
-                    luminance += sky_sample * transmittance;
+                    let mut luminance = vec3f::zero();
+                    for _ in 0..spp {
+                        let (jitter_x, jitter_y) = if spp > 1 {
+                            (rng.rand() as f32, rng.rand() as f32)
+                        } else {
+                            (0.0, 0.0)
+                        };
+                        let (lens_s, lens_t) = if has_lens {
+                            (rng.rand() as f32, rng.rand() as f32)
+                        } else {
+                            (0.5, 0.5)
+                        };
+                        let time = if shutter_close > shutter_open {
+                            settings.time + lerp(shutter_open, shutter_close, rng.rand() as f32)
+                        } else {
+                            settings.time + shutter_open
+                        };
+                        let camera_sample = CameraSample {
+                            s: ((x - view_x) as f32 + jitter_x) * inv_width,
+                            t: (y as f32 + jitter_y) * inv_height,
+                            lens_s,
+                            lens_t,
+                            time
+                        };
+                        // Outside of the projection
+                        let ray = match camera.generate_ray(&camera_sample) {
+                            Some(ray) => ray,
+                            None => continue
+                        };
+
+                        let wavelengths = if settings.spectral {
+                            Some(sample_wavelengths(rng.rand() as f32))
+                        } else {
+                            None
+                        };
+
+                        let result: IntegrationResult = integrate_ray(
+                            scene.volume.deref(),
+                            ray,
+                            &scene.lights,
+                            &settings,
+                            &mut rng,
+                            wavelengths);
+
+                        let mut sample_luminance = result.luminance;
+                        let transmittance = result.transmittance;
+
+                        // #todo-sky: Move into integrate_ray?
+                        // Atmosphere is not a mere background texture. It should affect volumes on the ground.
+                        let ray_on_earth = SkyAtmosphere::get_camera_ray_on_earth(ray);
+                        let sky_sample = match wavelengths {
+                            Some(w) => scene.sky_atmosphere.sample_spectral(ray_on_earth, w),
+                            None => scene.sky_atmosphere.sample(ray_on_earth)
+                        };
+                        sample_luminance += sky_sample * transmittance;
+
+                        if let Some(w) = wavelengths {
+                            sample_luminance = spectral_to_linear_srgb(sample_luminance, w);
+                        }
+                        luminance += sample_luminance;
+                    }
+                    luminance /= spp as f32;
 
                     // Tone mapping and gamma correction
                     luminance = aces_tone_mapping(luminance * exposure);
@@ -2,12 +2,13 @@
 use crate::math::vec3::*;
 use crate::math::ray::Ray;
 use crate::math::sphere::Sphere;
+use crate::phasefn::{rayleigh_phase, cornette_shanks_phase};
 
 // Forked from my OpenGL project: https://github.com/codeonwort/pathosengine
 
 const MAGIC_RAYLEIGH: f32 = 1.0;
 const MAGIC_MIE: f32 = 0.3;
-const PI: f32 = std::f32::consts::PI;
+const MIE_G: f32 = 0.76;
 
 const NUM_PRIMARY_STEPS: i32 = 64;
 const NUM_SECONDARY_STEPS: i32 = 8;
@@ -29,23 +30,15 @@ const BetaR: vec3f                = vec3f { x: 5.8e-6, y: 13.5e-6, z: 33.1e-6 };
 #[allow(non_upper_case_globals)]
 const BetaM: vec3f                = vec3f { x: 21e-6, y: 21e-6, z: 21e-6 };
 
-#[allow(non_snake_case)]
-fn phaseR(cos_theta: f32) -> f32
-{
-    3.0 / (16.0 * PI) * (1.0 + cos_theta * cos_theta)
+// Spectral scattering coefficients (1 / meters) for wavelengths in nanometers.
+// Rayleigh scattering is proportional to 1/lambda^4, fitted to BetaR (5.8, 13.5, 33.1 at 680, 550, 440 nm).
+pub fn rayleigh_scattering(lambda: f32) -> f32 {
+    let x = 440.0 / lambda;
+    33.1e-6 * x * x * x * x
 }
-#[allow(non_snake_case, non_upper_case_globals)]
-fn phaseM(t: f32) -> f32
-{
-    const g: f32 = 0.76;
-    let gg = g * g;
-    let tt = t * t;
-    let gt = g * t;
-    
-    let num = 3.0 * (1.0 - gg) * (1.0 + tt);
-	let denom = (8.0 * PI) * (2.0 + gg) * (1.0 + gg - 2.0 * gt).powf(1.5);
-
-    num / denom
+// Mie scattering by aerosols barely depends on wavelength.
+pub fn mie_scattering(_lambda: f32) -> f32 {
+    BetaM.x
 }
 
 fn get_sun_image(camera_ray: Ray, sun_direction: vec3f, sun_size: f32, sun_intensity: f32) -> f32 {
@@ -93,8 +86,27 @@ impl SkyAtmosphere {
     }
 
     // #todo-sky: Multiple scattering
-    #[allow(non_snake_case)]
     pub fn sample(&self, ray: Ray) -> vec3f {
+        self.sample_with_coeffs(ray, BetaR, BetaM)
+    }
+
+    // Each channel of the result is the luminance at the corresponding wavelength (nanometers).
+    pub fn sample_spectral(&self, ray: Ray, wavelengths: vec3f) -> vec3f {
+        let beta_r = vec3(
+            rayleigh_scattering(wavelengths.x),
+            rayleigh_scattering(wavelengths.y),
+            rayleigh_scattering(wavelengths.z));
+        let beta_m = vec3(
+            mie_scattering(wavelengths.x),
+            mie_scattering(wavelengths.y),
+            mie_scattering(wavelengths.z));
+        self.sample_with_coeffs(ray, beta_r, beta_m)
+    }
+
+    // beta_r : Rayleigh scattering coefficients at sea level
+    // beta_m : Mie scattering coefficients at sea level
+    #[allow(non_snake_case)]
+    fn sample_with_coeffs(&self, ray: Ray, beta_r: vec3f, beta_m: vec3f) -> vec3f {
         if self.is_empty {
             return vec3f::zero();
         }
@@ -128,8 +140,8 @@ impl SkyAtmosphere {
                 //break;
             }
 
-            optical_depth += segment_length * (BetaR * (-height / Hr).exp());
-            optical_depth += segment_length * (BetaM * (-height / Hm).exp());
+            optical_depth += segment_length * (beta_r * (-height / Hr).exp());
+            optical_depth += segment_length * (beta_m * (-height / Hm).exp());
 
             // Single scattering
             let ray2 = Ray::new(P, -sun_dir);
@@ -148,8 +160,8 @@ impl SkyAtmosphere {
                     break;
                 }
 
-                TL += light_segment_length * BetaR * (-height2 / Hr).exp();
-                TL += light_segment_length * BetaM * (-height2 / Hm).exp();
+                TL += light_segment_length * beta_r * (-height2 / Hr).exp();
+                TL += light_segment_length * beta_m * (-height2 / Hm).exp();
 
                 PL += PL_step_size;
             }
@@ -159,8 +171,8 @@ impl SkyAtmosphere {
                 let curr_t = (-optical_depth).exp();
 
                 let mut single_scattering = vec3f::zero();
-                single_scattering += MAGIC_RAYLEIGH * segment_length * curr_t * (BetaR * (-height / Hr).exp()) * phaseR(mu) * (TL * sun_intensity);
-                single_scattering += MAGIC_MIE * segment_length * curr_t * (BetaM * (-height / Hm).exp()) * phaseM(mu) * (TL * sun_intensity);
+                single_scattering += MAGIC_RAYLEIGH * segment_length * curr_t * (beta_r * (-height / Hr).exp()) * rayleigh_phase(mu) * (TL * sun_intensity);
+                single_scattering += MAGIC_MIE * segment_length * curr_t * (beta_m * (-height / Hm).exp()) * cornette_shanks_phase(MIE_G, mu) * (TL * sun_intensity);
                 
                 result += single_scattering;
             }
@@ -0,0 +1,187 @@
+use crate::math::vec3::*;
+
+// Visible range used for spectral integration (nanometers)
+pub const LAMBDA_MIN: f32 = 360.0;
+pub const LAMBDA_MAX: f32 = 830.0;
+const LAMBDA_STEP: f32 = 5.0;
+
+// Integral of CIE Y matching function over [LAMBDA_MIN, LAMBDA_MAX]
+const CIE_Y_INTEGRAL: f32 = 106.922;
+// XYZ of a constant spectrum of 1.0 (illuminant E), divided by CIE_Y_INTEGRAL
+const ILLUMINANT_E_XYZ: vec3f = vec3f { x: 0.998539, y: 1.0, z: 0.999560 };
+
+// Blackbody radiance is normalized so that this temperature has luminance 1.0.
+// Roughly the temperature of a flame.
+pub const BLACKBODY_REFERENCE_TEMPERATURE: f32 = 2000.0;
+
+// ----------------------------------------------------------
+// CIE 1931 color matching functions
+
+// Piecewise gaussian
+fn gaussian(x: f32, mu: f32, sigma1: f32, sigma2: f32) -> f32 {
+    let t = (x - mu) / (if x < mu { sigma1 } else { sigma2 });
+    (-0.5 * t * t).exp()
+}
+
+// Multi-lobe fit of CIE 1931 2-degree observer.
+// "Simple Analytic Approximations to the CIE XYZ Color Matching Functions" (Wyman et al. 2013)
+pub fn cie_xyz(lambda: f32) -> vec3f {
+    let x = 1.056 * gaussian(lambda, 599.8, 37.9, 31.0)
+        + 0.362 * gaussian(lambda, 442.0, 16.0, 26.7)
+        - 0.065 * gaussian(lambda, 501.1, 20.4, 26.2);
+    let y = 0.821 * gaussian(lambda, 568.8, 46.9, 40.5)
+        + 0.286 * gaussian(lambda, 530.9, 16.3, 31.1);
+    let z = 1.217 * gaussian(lambda, 437.0, 11.8, 36.0)
+        + 0.681 * gaussian(lambda, 459.0, 26.0, 13.8);
+    vec3(x, y, z)
+}
+
+// CIE XYZ to linear sRGB (D65)
+pub fn xyz_to_linear_srgb(xyz: vec3f) -> vec3f {
+    let row0 = vec3(3.2404542, -1.5371385, -0.4985314);
+    let row1 = vec3(-0.969266, 1.8760108, 0.041556);
+    let row2 = vec3(0.0556434, -0.2040259, 1.0572252);
+
+    vec3(row0.dot(xyz), row1.dot(xyz), row2.dot(xyz))
+}
+
+// ----------------------------------------------------------
+// Spectral rendering
+
+// Hero wavelength sampling with 3 wavelengths, so that spectral values fit in vec3f
+// and the integrator can treat each wavelength as a color channel.
+// "Hero Wavelength Spectral Sampling" (Wilkie et al. 2014)
+pub fn sample_wavelengths(u: f32) -> vec3f {
+    let range = LAMBDA_MAX - LAMBDA_MIN;
+    let hero = u * range;
+    let rotate = |i: f32| LAMBDA_MIN + (hero + i * range / 3.0) % range;
+    vec3(rotate(0.0), rotate(1.0), rotate(2.0))
+}
+
+// Monte Carlo estimate of XYZ from spectral values at wavelengths from sample_wavelengths().
+pub fn spectral_to_xyz(values: vec3f, wavelengths: vec3f) -> vec3f {
+    let xyz = cie_xyz(wavelengths.x) * values.x
+        + cie_xyz(wavelengths.y) * values.y
+        + cie_xyz(wavelengths.z) * values.z;
+    // pdf = 1 / (LAMBDA_MAX - LAMBDA_MIN) for each of 3 samples
+    xyz * ((LAMBDA_MAX - LAMBDA_MIN) / (3.0 * CIE_Y_INTEGRAL))
+}
+
+// Converts to linear sRGB, white balanced so that a constant spectrum of 1.0 becomes (1, 1, 1).
+pub fn spectral_to_linear_srgb(values: vec3f, wavelengths: vec3f) -> vec3f {
+    let white = xyz_to_linear_srgb(ILLUMINANT_E_XYZ);
+    xyz_to_linear_srgb(spectral_to_xyz(values, wavelengths)) / white
+}
+
+// RGB to spectrum upsampling.
+// "An RGB-to-Spectrum Conversion for Reflectances" (Smits 1999)
+// 10 bins in [380, 720] nanometers.
+const SMITS_WHITE: [f32; 10]   = [1.0000, 1.0000, 0.9999, 0.9993, 0.9992, 0.9998, 1.0000, 1.0000, 1.0000, 1.0000];
+const SMITS_CYAN: [f32; 10]    = [0.9710, 0.9426, 1.0007, 1.0007, 1.0007, 1.0007, 0.1564, 0.0000, 0.0000, 0.0000];
+const SMITS_MAGENTA: [f32; 10] = [1.0000, 1.0000, 0.9685, 0.2229, 0.0000, 0.0458, 0.8369, 1.0000, 1.0000, 0.9959];
+const SMITS_YELLOW: [f32; 10]  = [0.0001, 0.0000, 0.1088, 0.6651, 1.0000, 1.0000, 0.9996, 0.9586, 0.9685, 0.9840];
+const SMITS_RED: [f32; 10]     = [0.1012, 0.0515, 0.0000, 0.0000, 0.0000, 0.0000, 0.8325, 1.0149, 1.0149, 1.0149];
+const SMITS_GREEN: [f32; 10]   = [0.0000, 0.0000, 0.0273, 0.7937, 1.0000, 0.9418, 0.1719, 0.0000, 0.0000, 0.0025];
+const SMITS_BLUE: [f32; 10]    = [1.0000, 1.0000, 0.8916, 0.3323, 0.0000, 0.0000, 0.0003, 0.0369, 0.0483, 0.0496];
+
+// Spectral value of an RGB color at a single wavelength (nanometers).
+pub fn rgb_to_spectrum(rgb: vec3f, lambda: f32) -> f32 {
+    let bin = ((lambda - 380.0) / 34.0).floor().clamp(0.0, 9.0) as usize;
+    let (r, g, b) = (rgb.x, rgb.y, rgb.z);
+
+    if r <= g && r <= b {
+        let base = r * SMITS_WHITE[bin];
+        if g <= b {
+            base + (g - r) * SMITS_CYAN[bin] + (b - g) * SMITS_BLUE[bin]
+        } else {
+            base + (b - r) * SMITS_CYAN[bin] + (g - b) * SMITS_GREEN[bin]
+        }
+    } else if g <= r && g <= b {
+        let base = g * SMITS_WHITE[bin];
+        if r <= b {
+            base + (r - g) * SMITS_MAGENTA[bin] + (b - r) * SMITS_BLUE[bin]
+        } else {
+            base + (b - g) * SMITS_MAGENTA[bin] + (r - b) * SMITS_RED[bin]
+        }
+    } else {
+        let base = b * SMITS_WHITE[bin];
+        if r <= g {
+            base + (r - b) * SMITS_YELLOW[bin] + (g - r) * SMITS_GREEN[bin]
+        } else {
+            base + (g - b) * SMITS_YELLOW[bin] + (r - g) * SMITS_RED[bin]
+        }
+    }
+}
+
+// rgb_to_spectrum() for each of 3 wavelengths.
+pub fn rgb_to_spectral(rgb: vec3f, wavelengths: vec3f) -> vec3f {
+    vec3(
+        rgb_to_spectrum(rgb, wavelengths.x),
+        rgb_to_spectrum(rgb, wavelengths.y),
+        rgb_to_spectrum(rgb, wavelengths.z))
+}
+
+// ----------------------------------------------------------
+// Blackbody
+
+// Planck's law. Spectral radiance (W / (sr * m^2 * m)) of a blackbody.
+// `lambda` in nanometers, `temperature` in Kelvin.
+pub fn planck(lambda: f32, temperature: f32) -> f32 {
+    if temperature <= 0.0 {
+        return 0.0;
+    }
+    const H: f64 = 6.62607015e-34;  // Planck constant
+    const C: f64 = 2.99792458e8;    // Speed of light
+    const K: f64 = 1.380649e-23;    // Boltzmann constant
+    let l = (lambda as f64) * 1.0e-9;
+    let t = temperature as f64;
+
+    let radiance = (2.0 * H * C * C) / (l.powi(5) * ((H * C / (l * K * t)).exp() - 1.0));
+    radiance as f32
+}
+
+// Planck radiance integrated against the CIE matching functions, not normalized.
+fn blackbody_xyz_unnormalized(temperature: f32) -> vec3f {
+    let mut xyz = vec3f::zero();
+    let mut lambda = LAMBDA_MIN;
+    while lambda <= LAMBDA_MAX {
+        xyz += cie_xyz(lambda) * planck(lambda, temperature);
+        lambda += LAMBDA_STEP;
+    }
+    xyz
+}
+
+// Linear sRGB of a blackbody. BLACKBODY_REFERENCE_TEMPERATURE has luminance 1.0.
+pub fn blackbody_rgb(temperature: f32) -> vec3f {
+    let norm = blackbody_xyz_unnormalized(BLACKBODY_REFERENCE_TEMPERATURE).y;
+    let rgb = xyz_to_linear_srgb(blackbody_xyz_unnormalized(temperature) / norm);
+    // Very low temperatures fall outside of sRGB gamut
+    vec3f::max(rgb, vec3f::zero())
+}
+
+// Precalculated blackbody_rgb(), as integrating Planck's law per raymarching step is too slow.
+pub struct BlackbodyTable {
+    values: Vec<vec3f>,
+    temperature_step: f32
+}
+
+impl BlackbodyTable {
+    pub fn new(max_temperature: f32, temperature_step: f32) -> BlackbodyTable {
+        let count = (max_temperature / temperature_step).ceil() as usize + 1;
+        let values = (0..count)
+            .map(|i| blackbody_rgb(i as f32 * temperature_step))
+            .collect();
+
+        BlackbodyTable { values, temperature_step }
+    }
+
+    // Temperatures out of range are clamped.
+    pub fn lookup(&self, temperature: f32) -> vec3f {
+        let x = (temperature / self.temperature_step).max(0.0);
+        let i = x.floor() as usize;
+        if i + 1 >= self.values.len() {
+            return self.values[self.values.len() - 1];
+        }
+        lerp(self.values[i], self.values[i + 1], x - (i as f32))
+    }
+}
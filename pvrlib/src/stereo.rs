@@ -0,0 +1,117 @@
+use crate::math::vec3::*;
+use crate::math::ray::Ray;
+use crate::camera::*;
+
+// Camera rigs that produce left/right eye cameras for stereoscopic output.
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum StereoEye {
+    Left,
+    Right
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum StereoMode {
+    // Parallel cameras with off-axis (sheared) frustums. No vertical parallax.
+    Parallel,
+    // Cameras rotated toward the zero parallax point. Causes keystone distortion at the image corners.
+    ToeIn,
+    // Omni-directional stereo for 360 degree equirectangular output.
+    OmniDirectional
+}
+
+pub struct StereoRig {
+    // Center of the rig
+    pub position: vec3f,
+    pub look_at: vec3f,
+    pub up: vec3f,
+    // Perspective of each eye. Ignored by OmniDirectional.
+    pub fov_y: f32,
+    pub aspect_ratio: f32,
+    // Distance between the eyes in world units
+    pub interaxial: f32,
+    // Objects at this distance appear on the screen plane.
+    pub zero_parallax_distance: f32,
+    pub mode: StereoMode
+}
+
+impl StereoRig {
+    pub fn get_camera(&self, eye: StereoEye) -> Camera {
+        let eye_offset = match eye {
+            StereoEye::Left => -0.5 * self.interaxial,
+            StereoEye::Right => 0.5 * self.interaxial
+        };
+        let perspective = PerspectiveProjection { fov_y: self.fov_y, aspect_ratio: self.aspect_ratio };
+
+        match self.mode {
+            StereoMode::Parallel => {
+                let projection = OffAxisProjection {
+                    inner: perspective,
+                    eye_offset,
+                    zero_parallax_distance: self.zero_parallax_distance
+                };
+                Camera::with_projection(self.position, self.look_at, self.up, Box::new(projection))
+            },
+            StereoMode::ToeIn => {
+                let forward = (self.look_at - self.position).normalize();
+                let right = (forward ^ self.up).normalize();
+                let convergence_point = self.position + forward * self.zero_parallax_distance;
+                let eye_position = self.position + right * eye_offset;
+                Camera::with_projection(eye_position, convergence_point, self.up, Box::new(perspective))
+            },
+            StereoMode::OmniDirectional => {
+                let projection = OmniStereoProjection {
+                    eye_offset,
+                    zero_parallax_distance: self.zero_parallax_distance
+                };
+                Camera::with_projection(self.position, self.look_at, self.up, Box::new(projection))
+            }
+        }
+    }
+
+    pub fn left_camera(&self) -> Camera {
+        self.get_camera(StereoEye::Left)
+    }
+
+    pub fn right_camera(&self) -> Camera {
+        self.get_camera(StereoEye::Right)
+    }
+}
+
+// Perspective projection of an eye shifted along +x, sheared so that
+// both eyes see the same rectangle at zero_parallax_distance.
+pub struct OffAxisProjection {
+    pub inner: PerspectiveProjection,
+    pub eye_offset: f32,
+    pub zero_parallax_distance: f32
+}
+
+impl CameraProjection for OffAxisProjection {
+    fn generate_ray(&self, s: f32, t: f32) -> Option<Ray> {
+        let ray = self.inner.generate_ray(s, t)?;
+        let origin = vec3(self.eye_offset, 0.0, 0.0);
+        let target = ray.d * (self.zero_parallax_distance / -ray.d.z);
+        // Keep the direction 1 unit long along the view direction, like PerspectiveProjection.
+        let dir = (target - origin) / self.zero_parallax_distance;
+        Some(Ray::new(origin, dir))
+    }
+}
+
+// Equirectangular projection where each ray starts on a circle of radius |eye_offset|,
+// tangent to the ray direction, so that every viewing direction has a correct stereo pair.
+// "Rendering Omni-directional Stereo Content" (Google, 2015)
+pub struct OmniStereoProjection {
+    pub eye_offset: f32,
+    pub zero_parallax_distance: f32
+}
+
+impl CameraProjection for OmniStereoProjection {
+    fn generate_ray(&self, s: f32, t: f32) -> Option<Ray> {
+        let ray = EquirectangularProjection.generate_ray(s, t)?;
+        let phi = (s - 0.5) * 2.0 * std::f32::consts::PI;
+        // Right vector of the horizontal viewing direction
+        let origin = vec3(phi.cos(), 0.0, phi.sin()) * self.eye_offset;
+        let dir = ray.d * self.zero_parallax_distance - origin;
+        Some(Ray::new(origin, dir))
+    }
+}
@@ -0,0 +1,152 @@
+use crate::math::vec3::*;
+use crate::voxelbuffer::VoxelBuffer;
+
+use std::ops::{Add, Sub, Mul};
+
+// Transfer functions remap voxel values to lighting properties.
+// Standard for scientific data (ex: CT scans loaded into a DenseField),
+// and handy to art-direct density without re-rasterizing.
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum CurveInterpolation {
+    Linear,
+    // Passes through every control point. Might overshoot between them.
+    CatmullRom
+}
+
+// Scalar curve through control points (x, y). Constant beyond the first and last points.
+#[derive(Clone, Debug)]
+pub struct Curve {
+    points: Vec<(f32, f32)>,
+    pub interpolation: CurveInterpolation
+}
+
+impl Curve {
+    pub fn new(mut points: Vec<(f32, f32)>, interpolation: CurveInterpolation) -> Curve {
+        assert!(!points.is_empty(), "Curve needs at least one control point");
+        points.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Curve { points, interpolation }
+    }
+    pub fn identity() -> Curve {
+        Curve::new(vec![(0.0, 0.0), (1.0, 1.0)], CurveInterpolation::Linear)
+    }
+
+    pub fn get_points(&self) -> &[(f32, f32)] {
+        &self.points
+    }
+
+    pub fn evaluate(&self, x: f32) -> f32 {
+        evaluate_keys(&self.points, x, self.interpolation)
+    }
+}
+
+// RGB colors at positions. Linear between stops, constant beyond the first and last stops.
+#[derive(Clone, Debug)]
+pub struct ColorRamp {
+    stops: Vec<(f32, vec3f)>
+}
+
+impl ColorRamp {
+    pub fn new(mut stops: Vec<(f32, vec3f)>) -> ColorRamp {
+        assert!(!stops.is_empty(), "ColorRamp needs at least one stop");
+        stops.sort_by(|a, b| a.0.total_cmp(&b.0));
+        ColorRamp { stops }
+    }
+    pub fn constant(color: vec3f) -> ColorRamp {
+        ColorRamp::new(vec![(0.0, color)])
+    }
+
+    pub fn get_stops(&self) -> &[(f32, vec3f)] {
+        &self.stops
+    }
+
+    pub fn evaluate(&self, x: f32) -> vec3f {
+        evaluate_keys(&self.stops, x, CurveInterpolation::Linear)
+    }
+}
+
+// What the color ramps of a TransferFunction are indexed by.
+pub enum RampInput {
+    // Density after the density curve
+    Density,
+    // Another field, mapped to the same bounds as the density buffer (ex: temperature)
+    Field(Box<dyn VoxelBuffer<f32>>)
+}
+
+// For a raw voxel value v and a ramp input x:
+//     density    = density_curve(v)
+//     emission   = emission_value   * density * emission_ramp(x)
+//     absorption = absorption_coeff * density
+//     scattering = scattering_coeff * density * albedo_ramp(x)
+pub struct TransferFunction {
+    pub density_curve: Curve,
+    pub ramp_input: RampInput,
+    pub emission_ramp: Option<ColorRamp>,
+    pub albedo_ramp: Option<ColorRamp>
+}
+
+impl TransferFunction {
+    // Only remaps density.
+    pub fn new(density_curve: Curve) -> TransferFunction {
+        TransferFunction {
+            density_curve,
+            ramp_input: RampInput::Density,
+            emission_ramp: None,
+            albedo_ramp: None
+        }
+    }
+
+    /// Ramp input at local uvw of the volume, given the remapped density there.
+    pub fn ramp_input_at(&self, uvw: vec3f, density: f32) -> f32 {
+        match &self.ramp_input {
+            RampInput::Density => density,
+            RampInput::Field(field) => field.sample_by_local_position(uvw.x, uvw.y, uvw.z)
+        }
+    }
+    /// Multiplier of emission for the ramp input `x`.
+    pub fn emission_tint(&self, x: f32) -> vec3f {
+        self.emission_ramp.as_ref().map_or(vec3f::one(), |ramp| ramp.evaluate(x))
+    }
+    /// Multiplier of scattering for the ramp input `x`.
+    pub fn albedo(&self, x: f32) -> vec3f {
+        self.albedo_ramp.as_ref().map_or(vec3f::one(), |ramp| ramp.evaluate(x))
+    }
+}
+
+fn evaluate_keys<T>(keys: &[(f32, T)], x: f32, interpolation: CurveInterpolation) -> T
+    where T: Add<Output=T> + Sub<Output=T> + Mul<f32, Output=T> + Copy
+{
+    let last = keys.len() - 1;
+    if x <= keys[0].0 {
+        return keys[0].1;
+    }
+    if x >= keys[last].0 {
+        return keys[last].1;
+    }
+
+    // Segment [i1, i2] that contains x
+    let i2 = keys.partition_point(|k| k.0 <= x).clamp(1, last);
+    let i1 = i2 - 1;
+    let (x1, p1) = keys[i1];
+    let (x2, p2) = keys[i2];
+    let span = x2 - x1;
+    let a = if span > 0.0 { (x - x1) / span } else { 0.0 };
+
+    match interpolation {
+        CurveInterpolation::Linear => lerp(p1, p2, a),
+        CurveInterpolation::CatmullRom => {
+            let (x0, p0) = keys[i1.saturating_sub(1)];
+            let (x3, p3) = keys[(i2 + 1).min(last)];
+            // Tangents scaled to the segment, for unevenly spaced control points.
+            let m1 = if x2 > x0 { (p2 - p0) * (span / (x2 - x0)) } else { p2 - p1 };
+            let m2 = if x3 > x1 { (p3 - p1) * (span / (x3 - x1)) } else { p2 - p1 };
+
+            let a2 = a * a;
+            let a3 = a2 * a;
+            p1 * (2.0 * a3 - 3.0 * a2 + 1.0)
+                + m1 * (a3 - 2.0 * a2 + a)
+                + p2 * (-2.0 * a3 + 3.0 * a2)
+                + m2 * (a3 - a2)
+        }
+    }
+}
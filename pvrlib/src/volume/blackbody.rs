@@ -0,0 +1,113 @@
+use super::*;
+use super::voxel::VoxelVolume;
+use crate::math::vec3::*;
+use crate::math::ray::*;
+use crate::math::aabb::AABB;
+use crate::phasefn::PhaseFunction;
+use crate::spectrum::BlackbodyTable;
+use crate::voxelbuffer::VoxelBuffer;
+
+// Temperature range of the blackbody lookup table (Kelvin)
+const MAX_TEMPERATURE: f32 = 12000.0;
+const TEMPERATURE_STEP: f32 = 10.0;
+
+// Density voxel volume that additionally emits blackbody radiation
+// from a temperature field, so fire can have a hot core and cool tips.
+//
+// emission = density_volume.emission + intensity * density * blackbody(temperature + temperature_offset)
+pub struct BlackbodyVoxelVolume {
+    // Density, absorption, scattering, and phase function.
+    pub density_volume: VoxelVolume,
+    // Temperature in Kelvin. Mapped to the same bounds as the density buffer.
+    pub temperature: Box<dyn VoxelBuffer<f32>>,
+
+    pub intensity: f32,
+    pub temperature_offset: f32,
+
+    table: BlackbodyTable
+}
+
+impl BlackbodyVoxelVolume {
+    pub fn new(
+        density_volume: VoxelVolume,
+        temperature: Box<dyn VoxelBuffer<f32>>,
+        intensity: f32,
+        temperature_offset: f32) -> BlackbodyVoxelVolume
+    {
+        BlackbodyVoxelVolume {
+            density_volume,
+            temperature,
+            intensity,
+            temperature_offset,
+            table: BlackbodyTable::new(MAX_TEMPERATURE, TEMPERATURE_STEP)
+        }
+    }
+
+    pub fn get_temperature_buffer(&mut self) -> &mut dyn VoxelBuffer<f32> {
+        &mut *self.temperature
+    }
+
+    /// Temperature (Kelvin) at the given position, excluding the offset.
+    pub fn sample_temperature(&self, world_position: vec3f) -> f32 {
+        let uvw = self.density_volume.world_to_local(world_position);
+        self.temperature.sample_by_local_position(uvw.x, uvw.y, uvw.z)
+    }
+
+    fn blackbody_emission(&self, world_position: vec3f, density: f32) -> vec3f {
+        if density <= 0.0 {
+            return vec3f::zero();
+        }
+        let temperature = self.sample_temperature(world_position) + self.temperature_offset;
+        self.table.lookup(temperature) * (self.intensity * density)
+    }
+}
+
+impl Volume for BlackbodyVoxelVolume {
+    fn emission(&self, p: vec3f) -> vec3f {
+        self.sample(p).emission
+    }
+    fn absorption_coeff(&self, p: vec3f) -> vec3f {
+        self.density_volume.absorption_coeff(p)
+    }
+    fn scattering_coeff(&self, p: vec3f) -> vec3f {
+        self.density_volume.scattering_coeff(p)
+    }
+    fn sample(&self, world_position: vec3f) -> VolumeSample {
+        let density = self.density_volume.sample_density(world_position);
+        let mut samp = self.density_volume.sample_from_density(world_position, density);
+        samp.emission += self.blackbody_emission(world_position, density);
+        samp
+    }
+
+    fn set_phase_function(&mut self, phase_fn: Box<dyn PhaseFunction>) {
+        self.density_volume.set_phase_function(phase_fn);
+    }
+    fn phase_function(&self, p: vec3f, wi: vec3f, wo: vec3f) -> f32 {
+        self.density_volume.phase_function(p, wi, wo)
+    }
+    fn sample_phase_function(&self, p: vec3f, wi: vec3f, u1: f32, u2: f32) -> vec3f {
+        self.density_volume.sample_phase_function(p, wi, u1, u2)
+    }
+
+    fn find_intersections(&self, ray: Ray) -> Vec<(f32, f32)> {
+        self.density_volume.find_intersections(ray)
+    }
+    fn attributes(&self) -> Vec<AttrRef> {
+        let mut attrs = self.density_volume.attributes();
+        attrs.push(ATTR_TEMPERATURE);
+        attrs
+    }
+    // Temperature includes temperature_offset.
+    fn sample_attribute(&self, attr: &AttrRef, world_position: vec3f) -> Option<vec3f> {
+        if *attr == ATTR_TEMPERATURE {
+            scalar_attribute(self.sample_temperature(world_position) + self.temperature_offset)
+        } else if *attr == ATTR_EMISSION {
+            Some(self.emission(world_position))
+        } else {
+            self.density_volume.sample_attribute(attr, world_position)
+        }
+    }
+    fn world_bounds(&self) -> AABB {
+        self.density_volume.world_bounds()
+    }
+}
@@ -1,43 +1,77 @@
 use super::Volume;
+use super::AttrRef;
+use super::LIGHTING_ATTRIBUTES;
 use super::VolumeSample;
+use super::VolumeSegment;
+use super::merge_intervals;
+use super::WeightedReservoir;
 use crate::math::vec3::*;
 use crate::math::ray::*;
 use crate::math::aabb::AABB;
-use crate::phasefn::PhaseFunction;
+use crate::math::bvh::BVH;
+use crate::phasefn::*;
 
+// Sum of child volumes. Children are culled by a BVH over their world bounds,
+// so only the children that overlap a point or a ray are visited.
 pub struct CompositeVolume {
-    pub children: Vec<Box<dyn Volume>>
+    children: Vec<Box<dyn Volume>>,
+    bvh: BVH,
+    world_bounds: AABB,
+    // Union of the children's attributes
+    attributes: Vec<AttrRef>
 }
 
 impl CompositeVolume {
-    //
+    pub fn new(children: Vec<Box<dyn Volume>>) -> CompositeVolume {
+        let bounds: Vec<AABB> = children.iter().map(|child| child.world_bounds()).collect();
+        let world_bounds = bounds.iter()
+            .fold(None, |acc: Option<AABB>, b| Some(acc.map_or(*b, |a| a.extend(*b))))
+            .unwrap_or_default();
+
+        let mut attributes: Vec<AttrRef> = LIGHTING_ATTRIBUTES.to_vec();
+        for attr in children.iter().flat_map(|child| child.attributes()) {
+            if !attributes.contains(&attr) {
+                attributes.push(attr);
+            }
+        }
+
+        CompositeVolume {
+            children,
+            bvh: BVH::new(&bounds),
+            world_bounds,
+            attributes
+        }
+    }
+
+    pub fn get_children(&self) -> &[Box<dyn Volume>] {
+        &self.children
+    }
+
+    // Calls `f` for children whose bounds contain `p`.
+    fn for_each_child<F: FnMut(&dyn Volume)>(&self, p: vec3f, mut f: F) {
+        self.bvh.query_point(p, |i| f(&*self.children[i]));
+    }
 }
 
 impl Volume for CompositeVolume {
     
     fn emission(&self, p: vec3f) -> vec3f {
         let mut total_emission = vec3f::zero();
-        for child in &self.children {
-            total_emission += child.emission(p);
-        }
+        self.for_each_child(p, |child| total_emission += child.emission(p));
 
         total_emission
     }
 
     fn absorption_coeff(&self, p: vec3f) -> vec3f {
         let mut total_absorption = vec3f::zero();
-        for child in &self.children {
-            total_absorption += child.absorption_coeff(p);
-        }
+        self.for_each_child(p, |child| total_absorption += child.absorption_coeff(p));
 
         total_absorption
     }
 
     fn scattering_coeff(&self, p: vec3f) -> vec3f {
         let mut total_scattering = vec3f::zero();
-        for child in &self.children {
-            total_scattering += child.scattering_coeff(p);
-        }
+        self.for_each_child(p, |child| total_scattering += child.scattering_coeff(p));
 
         total_scattering
     }
@@ -46,53 +80,116 @@ impl Volume for CompositeVolume {
         let mut samp = VolumeSample::new();
 
         // #todo: What if childrens intersect each other
-        for child in &self.children {
+        self.for_each_child(world_position, |child| {
             let child_samp = child.sample(world_position);
             samp.emission += child_samp.emission;
             samp.absorption_coeff += child_samp.absorption_coeff;
             samp.scattering_coeff += child_samp.scattering_coeff;
-        }
+        });
 
         return samp;
     }
 
     fn set_phase_function(&mut self, _phase_fn: Box<dyn PhaseFunction>) {
-        // #todo-phase: What to do here?
+        // #todo-phase: Phase functions can't be cloned to every child.
         println!("WARNING: set_phase_fn() on CompositeVolume won't do nothing");
     }
+    // Child phase functions blended by their scattering at p,
+    // so that overlapping children (ex: fog and smoke) stay normalized.
     fn phase_function(&self, p: vec3f, wi: vec3f, wo: vec3f) -> f32 {
-        // #todo-phase: Assumes no overlap between child volumes.
-        // Need to introduce weight per phase fn.
-        let mut total_p = 0.0;
-        for child in &self.children {
-            total_p += child.phase_function(p, wi, wo);
+        let mut total_phase = 0.0;
+        let mut total_weight = 0.0;
+        self.for_each_child(p, |child| {
+            let weight = child.scattering_coeff(p).max_component();
+            if weight > 0.0 {
+                total_phase += weight * child.phase_function(p, wi, wo);
+                total_weight += weight;
+            }
+        });
+        if total_weight > 0.0 { total_phase / total_weight } else { 0.0 }
+    }
+    // Pick a child with the same weights as phase_function(), then sample its lobe.
+    fn sample_phase_function(&self, p: vec3f, wi: vec3f, u1: f32, u2: f32) -> vec3f {
+        let mut reservoir = WeightedReservoir::new(u1);
+        self.bvh.query_point(p, |i| {
+            reservoir.add(i, self.children[i].scattering_coeff(p).max_component());
+        });
+        match reservoir.picked() {
+            Some((i, u1)) => self.children[i].sample_phase_function(p, wi, u1, u2),
+            None => Isotropic{}.sample(wi, u1, u2)
         }
-
-        total_p
     }
 
+    // Children that are entirely behind the ray origin are skipped.
     fn find_intersections(&self, ray: Ray) -> Vec<(f32, f32)> {
-        let mut intervals = Vec::new();
-        for child in &self.children {
-            intervals.append(&mut child.find_intersections(ray));
+        let intervals = self.find_segments(ray).iter().map(|segment| (segment.t_min, segment.t_max)).collect();
+        merge_intervals(intervals)
+    }
+
+    fn find_segments(&self, ray: Ray) -> Vec<VolumeSegment> {
+        // (t, child, is_enter)
+        let mut events: Vec<(f32, usize, bool)> = Vec::new();
+        self.bvh.query_ray(ray, |i| {
+            for (t_min, t_max) in merge_intervals(self.children[i].find_intersections(ray)) {
+                if t_min < t_max {
+                    events.push((t_min, i, true));
+                    events.push((t_max, i, false));
+                }
+            }
+        });
+        events.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        // Sweep along the ray. The set of active children changes only at events.
+        let mut segments: Vec<VolumeSegment> = Vec::new();
+        let mut active: Vec<usize> = Vec::new();
+        let mut t_prev = 0.0;
+        for (t, child, is_enter) in events {
+            if !active.is_empty() && t > t_prev {
+                match segments.last_mut() {
+                    Some(last) if last.t_max == t_prev && last.volumes == active => last.t_max = t,
+                    _ => segments.push(VolumeSegment { t_min: t_prev, t_max: t, volumes: active.clone() })
+                }
+            }
+            if is_enter {
+                let pos = active.binary_search(&child).unwrap_or_else(|pos| pos);
+                active.insert(pos, child);
+            } else if let Ok(pos) = active.binary_search(&child) {
+                active.remove(pos);
+            }
+            t_prev = t;
         }
 
-        intervals
+        segments
     }
 
-    fn world_bounds(&self) -> AABB {
-        if self.children.len() == 0 {
-            return AABB {
-                min: vec3f::zero(), 
-                max: vec3f::zero()
-            };
-        }
-        let mut aabb = self.children[0].world_bounds();
-        let len = self.children.len();
-        for _i in 1..len {
-            aabb = aabb.extend(self.children[1].world_bounds());
+    fn sample_segment(&self, world_position: vec3f, segment: &VolumeSegment) -> VolumeSample {
+        let mut samp = VolumeSample::new();
+        for &i in &segment.volumes {
+            let child_samp = self.children[i].sample(world_position);
+            samp.emission += child_samp.emission;
+            samp.absorption_coeff += child_samp.absorption_coeff;
+            samp.scattering_coeff += child_samp.scattering_coeff;
         }
+        samp
+    }
 
-        aabb
+    fn attributes(&self) -> Vec<AttrRef> {
+        self.attributes.clone()
+    }
+    // Sum of the children that have the attribute, like the lighting properties.
+    fn sample_attribute(&self, attr: &AttrRef, world_position: vec3f) -> Option<vec3f> {
+        if !self.attributes.contains(attr) {
+            return None;
+        }
+        let mut total = vec3f::zero();
+        self.for_each_child(world_position, |child| {
+            if let Some(value) = child.sample_attribute(attr, world_position) {
+                total += value;
+            }
+        });
+        Some(total)
+    }
+    fn world_bounds(&self) -> AABB {
+        self.world_bounds
     }
 }
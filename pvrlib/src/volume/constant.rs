@@ -1,20 +1,225 @@
 use super::*;
 use crate::math::vec3::*;
 use crate::math::ray::*;
-use crate::math::solve_quadratic;
+use crate::math::{solve_quadratic, solve_polynomial};
 use crate::math::aabb::AABB;
 use crate::phasefn::PhaseFunction;
 
+// Shapes in local space, centered at the origin. Round shapes are around the y axis.
+#[derive(Copy, Clone, Debug)]
 pub enum ConstantVolumeShape {
-    Box,
-    Sphere
+    Box { half_size: vec3f },
+    Sphere { radius: f32 },
+    Ellipsoid { radii: vec3f },
+    Cylinder { radius: f32, half_height: f32 },
+    // Base at y = -height / 2, apex at y = height / 2
+    Cone { radius: f32, height: f32 },
+    // Segment from y = -half_height to half_height, swept by a sphere of radius
+    Capsule { radius: f32, half_height: f32 },
+    // Ring in the xz plane
+    Torus { major_radius: f32, minor_radius: f32 }
+}
+
+impl ConstantVolumeShape {
+    pub fn cube(radius: f32) -> ConstantVolumeShape {
+        ConstantVolumeShape::Box { half_size: vec3(radius, radius, radius) }
+    }
+
+    /// Half size of the bounding box.
+    pub fn half_extents(&self) -> vec3f {
+        match *self {
+            ConstantVolumeShape::Box { half_size } => half_size,
+            ConstantVolumeShape::Sphere { radius } => vec3(radius, radius, radius),
+            ConstantVolumeShape::Ellipsoid { radii } => radii,
+            ConstantVolumeShape::Cylinder { radius, half_height } => vec3(radius, half_height, radius),
+            ConstantVolumeShape::Cone { radius, height } => vec3(radius, 0.5 * height, radius),
+            ConstantVolumeShape::Capsule { radius, half_height } => vec3(radius, half_height + radius, radius),
+            ConstantVolumeShape::Torus { major_radius, minor_radius } => {
+                let r = major_radius + minor_radius;
+                vec3(r, minor_radius, r)
+            }
+        }
+    }
+
+    /// Signed distance to the surface, negative inside. Approximate for Ellipsoid.
+    pub fn signed_distance(&self, p: vec3f) -> f32 {
+        let radial = (p.x * p.x + p.z * p.z).sqrt();
+        match *self {
+            ConstantVolumeShape::Box { half_size } => {
+                let q = vec3(p.x.abs(), p.y.abs(), p.z.abs()) - half_size;
+                vec3f::max(q, vec3f::zero()).length() + q.max_component().min(0.0)
+            },
+            ConstantVolumeShape::Sphere { radius } => p.length() - radius,
+            ConstantVolumeShape::Ellipsoid { radii } => {
+                let k0 = (p / radii).length();
+                let k1 = (p / (radii * radii)).length();
+                if k1 > 0.0 { k0 * (k0 - 1.0) / k1 } else { -radii.min_component() }
+            },
+            ConstantVolumeShape::Cylinder { radius, half_height } => {
+                length_2d_signed(radial - radius, p.y.abs() - half_height)
+            },
+            ConstantVolumeShape::Cone { radius, height } => {
+                // Capped cone from https://iquilezles.org/articles/distfunctions/
+                let h = 0.5 * height;
+                let (qx, qy) = (radial, p.y);
+                let (cax, cay) = (qx - qx.min(if qy < 0.0 { radius } else { 0.0 }), qy.abs() - h);
+                let (k2x, k2y) = (-radius, 2.0 * h);
+                let a = ((-qx * k2x + (h - qy) * k2y) / (k2x * k2x + k2y * k2y)).clamp(0.0, 1.0);
+                let (cbx, cby) = (qx + k2x * a, qy - h + k2y * a);
+                let s = if cbx < 0.0 && cay < 0.0 { -1.0 } else { 1.0 };
+                s * (cax * cax + cay * cay).min(cbx * cbx + cby * cby).sqrt()
+            },
+            ConstantVolumeShape::Capsule { radius, half_height } => {
+                vec3(p.x, p.y - p.y.clamp(-half_height, half_height), p.z).length() - radius
+            },
+            ConstantVolumeShape::Torus { major_radius, minor_radius } => {
+                let qx = radial - major_radius;
+                (qx * qx + p.y * p.y).sqrt() - minor_radius
+            }
+        }
+    }
+
+    pub fn contains(&self, p: vec3f) -> bool {
+        self.signed_distance(p) <= 0.0
+    }
+
+    /// Sorted ranges of the ray inside the shape, in local space.
+    pub fn intersect(&self, ray: Ray) -> Vec<(f32, f32)> {
+        let (o, d) = (ray.o, ray.d);
+        let hit = match *self {
+            ConstantVolumeShape::Box { half_size } => box_vs_ray(o, d, half_size),
+            ConstantVolumeShape::Sphere { radius } => sphere_vs_ray(o, d, radius),
+            ConstantVolumeShape::Ellipsoid { radii } => sphere_vs_ray(o / radii, d / radii, 1.0),
+            ConstantVolumeShape::Cylinder { radius, half_height } => cylinder_vs_ray(o, d, radius, half_height),
+            ConstantVolumeShape::Cone { radius, height } => cone_vs_ray(o, d, radius, height),
+            ConstantVolumeShape::Capsule { radius, half_height } => {
+                let offset = vec3(0.0, half_height, 0.0);
+                let parts: Vec<(f32, f32)> = [
+                    cylinder_vs_ray(o, d, radius, half_height),
+                    sphere_vs_ray(o - offset, d, radius),
+                    sphere_vs_ray(o + offset, d, radius)
+                ].iter().flatten().copied().collect();
+                // Convex, so the union is a single range.
+                merge_intervals(parts).first().copied()
+            },
+            ConstantVolumeShape::Torus { major_radius, minor_radius } => {
+                return torus_vs_ray(o, d, major_radius, minor_radius);
+            }
+        };
+        hit.into_iter().collect()
+    }
+}
+
+fn length_2d_signed(x: f32, y: f32) -> f32 {
+    let (px, py) = (x.max(0.0), y.max(0.0));
+    x.max(y).min(0.0) + (px * px + py * py).sqrt()
+}
+
+// Ranges of t where (o + t * d)[axis] is in [lo, hi]. None if empty.
+fn slab(o: f32, d: f32, lo: f32, hi: f32) -> Option<(f32, f32)> {
+    if d == 0.0 {
+        return if lo <= o && o <= hi { Some((f32::MIN, f32::MAX)) } else { None };
+    }
+    let t0 = (lo - o) / d;
+    let t1 = (hi - o) / d;
+    Some((t0.min(t1), t0.max(t1)))
+}
+
+fn overlap(a: (f32, f32), b: (f32, f32)) -> Option<(f32, f32)> {
+    let range = (a.0.max(b.0), a.1.min(b.1));
+    if range.0 <= range.1 { Some(range) } else { None }
+}
+
+fn box_vs_ray(o: vec3f, d: vec3f, half_size: vec3f) -> Option<(f32, f32)> {
+    let mut range = (f32::MIN, f32::MAX);
+    for i in 0..3 {
+        range = overlap(range, slab(o[i], d[i], -half_size[i], half_size[i])?)?;
+    }
+    Some(range)
+}
+
+fn sphere_vs_ray(o: vec3f, d: vec3f, radius: f32) -> Option<(f32, f32)> {
+    solve_quadratic(d.length_sq(), 2.0 * (d & o), o.length_sq() - radius * radius)
+}
+
+// Where the quadratic (a * tt + b * t + c) is not positive.
+fn quadratic_inside(a: f32, b: f32, c: f32) -> Vec<(f32, f32)> {
+    if a.abs() < 1e-8 {
+        return if b.abs() < 1e-8 {
+            if c <= 0.0 { vec![(f32::MIN, f32::MAX)] } else { Vec::new() }
+        } else if b > 0.0 {
+            vec![(f32::MIN, -c / b)]
+        } else {
+            vec![(-c / b, f32::MAX)]
+        };
+    }
+    match solve_quadratic(a, b, c) {
+        Some((t0, t1)) if a > 0.0 => vec![(t0, t1)],
+        Some((t0, t1)) => vec![(f32::MIN, t0), (t1, f32::MAX)],
+        None if a > 0.0 => Vec::new(),
+        None => vec![(f32::MIN, f32::MAX)]
+    }
+}
+
+fn cylinder_vs_ray(o: vec3f, d: vec3f, radius: f32, half_height: f32) -> Option<(f32, f32)> {
+    let height_range = slab(o.y, d.y, -half_height, half_height)?;
+    let a = d.x * d.x + d.z * d.z;
+    let b = 2.0 * (o.x * d.x + o.z * d.z);
+    let c = o.x * o.x + o.z * o.z - radius * radius;
+    let side_range = *quadratic_inside(a, b, c).first()?;
+    overlap(height_range, side_range)
+}
+
+fn cone_vs_ray(o: vec3f, d: vec3f, radius: f32, height: f32) -> Option<(f32, f32)> {
+    let h = 0.5 * height;
+    let height_range = slab(o.y, d.y, -h, h)?;
+    // x^2 + z^2 <= (k * (apex - y))^2, which is a double cone. The slab cuts off the upper one.
+    let k = radius / height;
+    let w = h - o.y;
+    let a = d.x * d.x + d.z * d.z - k * k * d.y * d.y;
+    let b = 2.0 * (o.x * d.x + o.z * d.z) + 2.0 * k * k * w * d.y;
+    let c = o.x * o.x + o.z * o.z - k * k * w * w;
+    quadratic_inside(a, b, c).into_iter().find_map(|range| overlap(range, height_range))
+}
+
+fn torus_vs_ray(o: vec3f, d: vec3f, major_radius: f32, minor_radius: f32) -> Vec<(f32, f32)> {
+    let bounds = ConstantVolumeShape::Torus { major_radius, minor_radius }.half_extents();
+    let (t_min, t_max) = match box_vs_ray(o, d, bounds) {
+        Some(range) => range,
+        None => return Vec::new()
+    };
+
+    // (|p|^2 + R^2 - r^2)^2 - 4R^2 (x^2 + z^2) = 0, quartic in t
+    let (ox, oy, oz) = (o.x as f64, o.y as f64, o.z as f64);
+    let (dx, dy, dz) = (d.x as f64, d.y as f64, d.z as f64);
+    let (rr, r2) = ((major_radius as f64).powi(2), (minor_radius as f64).powi(2));
+    let a = dx * dx + dy * dy + dz * dz;
+    let b = 2.0 * (ox * dx + oy * dy + oz * dz);
+    let c = ox * ox + oy * oy + oz * oz + rr - r2;
+    let coeffs = [
+        c * c - 4.0 * rr * (ox * ox + oz * oz),
+        2.0 * b * c - 8.0 * rr * (ox * dx + oz * dz),
+        b * b + 2.0 * a * c - 4.0 * rr * (dx * dx + dz * dz),
+        2.0 * a * b,
+        a * a
+    ];
+    let eval = |t: f64| coeffs.iter().rev().fold(0.0, |acc, k| acc * t + k);
+
+    let mut breaks = vec![t_min as f64];
+    breaks.append(&mut solve_polynomial(&coeffs, t_min as f64, t_max as f64));
+    breaks.push(t_max as f64);
+    let inside: Vec<(f32, f32)> = breaks.windows(2)
+        .filter(|w| w[1] > w[0] && eval(0.5 * (w[0] + w[1])) < 0.0)
+        .map(|w| (w[0] as f32, w[1] as f32))
+        .collect();
+    merge_intervals(inside)
 }
 
 pub struct ConstantVolume {
     shape: ConstantVolumeShape,
     center: vec3f,
-    radius: f32,
-    box_bounds: AABB,
+    // Density ramps from 0 at the surface to 1 at the falloff width inside.
+    falloff: Falloff,
 
     emission_value: vec3f,
     absorption_coeff: vec3f,
@@ -27,103 +232,56 @@ impl ConstantVolume {
     pub fn new(
         shape: ConstantVolumeShape,
         center: vec3f,
-        radius: f32,
         emission: vec3f,
         absorption: vec3f,
         scattering: vec3f,
         phase_fn: Box<dyn PhaseFunction>) -> ConstantVolume
     {
-        let r = vec3(radius, radius, radius);
         ConstantVolume {
-            shape: shape,
-            center: center,
-            radius: radius,
-            box_bounds: AABB { min: center - r, max: center + r },
+            shape,
+            center,
+            falloff: Falloff::Hard,
             emission_value: emission,
             absorption_coeff: absorption,
             scattering_coeff: scattering,
-            phase_fn: phase_fn
+            phase_fn
         }
     }
 
-    fn contains(&self, p: vec3f) -> bool {
-        match self.shape {
-            ConstantVolumeShape::Box => {
-                let sides = p - self.center;
-                return sides.x.abs() <= self.radius
-                    && sides.y.abs() <= self.radius
-                    && sides.z.abs() <= self.radius;
-            }
-            ConstantVolumeShape::Sphere => {
-                (p - self.center).length_sq() <= (self.radius * self.radius)
-            }
-        }
+    pub fn get_shape(&self) -> ConstantVolumeShape {
+        self.shape
     }
 
-    fn aabb_vs_ray(&self, ray: Ray) -> Vec<(f32, f32)> {
-        let mut t_min = f32::MIN;
-        let mut t_max = f32::MAX;
-        let mut hit = true;
-        for i in 0..3 {
-            let inv_d = 1.0 / ray.d[i];
-            let mut t0 = (self.box_bounds.min[i] - ray.o[i]) * inv_d;
-            let mut t1 = (self.box_bounds.max[i] - ray.o[i]) * inv_d;
-            if inv_d < 0.0 {
-                std::mem::swap(&mut t0, &mut t1);
-            }
-            t_min = t_min.max(t0);
-            t_max = t_max.min(t1);
-            if t_max < t_min {
-                hit = false;
-            }
-        }
-
-        if hit {
-            vec!((t_min, t_max))
-        } else {
-            Vec::new()
-        }
+    // Smooth falloff avoids aliasing at the boundary.
+    pub fn set_falloff(&mut self, falloff: Falloff) {
+        self.falloff = falloff;
+    }
+    pub fn get_falloff(&self) -> Falloff {
+        self.falloff
     }
 
-    fn sphere_vs_ray(&self, ray: Ray) -> Vec<(f32, f32)> {
-        let delta = ray.o - self.center;
-        let a = ray.d & ray.d;
-        let b = 2.0 * (ray.d & delta);
-        let c = (delta & delta) - (self.radius * self.radius);
-
-        let mut intervals = Vec::new();
-        if let Some(v) = solve_quadratic(a, b, c) {
-            intervals.push(v);
-        }
-
-        intervals
+    // 1 deep inside, 0 outside.
+    fn density(&self, p: vec3f) -> f32 {
+        self.falloff.evaluate(self.shape.signed_distance(p - self.center))
     }
 }
 
 impl Volume for ConstantVolume {
     fn emission(&self, p: vec3f) -> vec3f {
-        if self.contains(p) { self.emission_value } else { vec3f::zero() }
+        self.emission_value * self.density(p)
     }
     fn absorption_coeff(&self, p: vec3f) -> vec3f {
-        if self.contains(p) { self.absorption_coeff } else { vec3f::zero() }
+        self.absorption_coeff * self.density(p)
     }
     fn scattering_coeff(&self, p: vec3f) -> vec3f {
-        if self.contains(p) { self.scattering_coeff } else { vec3f::zero() }
+        self.scattering_coeff * self.density(p)
     }
     fn sample(&self, world_position: vec3f) -> VolumeSample {
-        // #todo: Hmm... this should not be called if world_position is out of bounds at first.
-        if self.contains(world_position) {
-            return VolumeSample {
-                emission: self.emission_value,
-                absorption_coeff: self.absorption_coeff,
-                scattering_coeff: self.scattering_coeff
-            };
-        } else {
-            return VolumeSample {
-                emission: vec3f::zero(),
-                absorption_coeff: vec3f::zero(),
-                scattering_coeff: vec3f::zero()
-            };
+        let density = self.density(world_position);
+        VolumeSample {
+            emission: self.emission_value * density,
+            absorption_coeff: self.absorption_coeff * density,
+            scattering_coeff: self.scattering_coeff * density
         }
     }
 
@@ -131,22 +289,22 @@ impl Volume for ConstantVolume {
         self.phase_fn = phase_fn;
     }
     fn phase_function(&self, p: vec3f, wi: vec3f, wo: vec3f) -> f32 {
-        if self.contains(p) {
+        if self.shape.contains(p - self.center) {
             self.phase_fn.probability(wi, wo)
         } else {
             0.0
         }
     }
+    fn sample_phase_function(&self, _p: vec3f, wi: vec3f, u1: f32, u2: f32) -> vec3f {
+        self.phase_fn.sample(wi, u1, u2)
+    }
 
     fn find_intersections(&self, ray: Ray) -> Vec<(f32, f32)> {
-        match self.shape {
-            ConstantVolumeShape::Box => self.aabb_vs_ray(ray),
-            ConstantVolumeShape::Sphere => self.sphere_vs_ray(ray)
-        }
+        self.shape.intersect(Ray { o: ray.o - self.center, d: ray.d })
     }
 
     fn world_bounds(&self) -> AABB {
-        let r = vec3f::new(self.radius, self.radius, self.radius);
+        let r = self.shape.half_extents();
         AABB {
             min: self.center - r,
             max: self.center + r
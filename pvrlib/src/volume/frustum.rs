@@ -0,0 +1,199 @@
+use super::*;
+use crate::math::vec3::*;
+use crate::math::ray::*;
+use crate::math::aabb::AABB;
+use crate::camera::Camera;
+use crate::phasefn::PhaseFunction;
+use crate::primitive::RasterizationTarget;
+use crate::voxelbuffer::VoxelBuffer;
+
+// How voxels are distributed along the view direction.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum FrustumDepth {
+    Linear,
+    // More voxels near the camera, roughly matching the screen space size of distant voxels.
+    Exponential
+}
+
+// Density voxel buffer aligned to the view frustum of a camera.
+// Voxel x and y follow screen space (s, t) and z follows the depth between near and far,
+// so voxel resolution goes where the camera needs it. See PVR book 5.4 "Frustum-aligned buffers".
+pub struct FrustumVolume {
+    pub buffer: Box<dyn VoxelBuffer<f32>>,
+
+    pub emission_value: vec3f,
+    pub absorption_coeff: vec3f,
+    pub scattering_coeff: vec3f,
+    pub phase_fn: Box<dyn PhaseFunction>,
+
+    camera: Camera,
+    near: f32,
+    far: f32,
+    depth_mapping: FrustumDepth,
+    world_bounds: AABB
+}
+
+impl FrustumVolume {
+    // `camera` needs a projection that supports project() (perspective or orthographic).
+    pub fn new(
+        buffer: Box<dyn VoxelBuffer<f32>>,
+        camera: Camera,
+        near: f32,
+        far: f32,
+        depth_mapping: FrustumDepth,
+        phase_fn: Box<dyn PhaseFunction>) -> FrustumVolume
+    {
+        assert!(0.0 < near && near < far, "FrustumVolume: invalid near and far: {}, {}", near, far);
+
+        let mut world_bounds = AABB {
+            min: vec3(f32::MAX, f32::MAX, f32::MAX),
+            max: vec3(f32::MIN, f32::MIN, f32::MIN)
+        };
+        for i in 0..8 {
+            let s = (i & 1) as f32;
+            let t = ((i >> 1) & 1) as f32;
+            let depth = if i & 4 == 0 { near } else { far };
+            let corner = camera.unproject(s, t, depth)
+                .expect("FrustumVolume: the camera projection has no frustum");
+            world_bounds.min = vec3f::min(world_bounds.min, corner);
+            world_bounds.max = vec3f::max(world_bounds.max, corner);
+        }
+
+        FrustumVolume {
+            buffer,
+            emission_value: vec3f::zero(),
+            absorption_coeff: vec3f::zero(),
+            scattering_coeff: vec3f::zero(),
+            phase_fn,
+            camera,
+            near,
+            far,
+            depth_mapping,
+            world_bounds
+        }
+    }
+
+    pub fn get_camera(&self) -> &Camera {
+        &self.camera
+    }
+
+    /// world position to local uvw in voxel volume.
+    /// (u, v) is screen space and w is the normalized depth.
+    pub fn world_to_local(&self, world_position: vec3f) -> vec3f {
+        match self.camera.project(world_position) {
+            Some(stw) => vec3(stw.x, 1.0 - stw.y, self.depth_to_local(stw.z)),
+            // Outside of any buffer
+            None => vec3(-1.0, -1.0, -1.0)
+        }
+    }
+    /// local uvw to world position.
+    pub fn local_to_world(&self, uvw: vec3f) -> vec3f {
+        let depth = self.local_to_depth(uvw.z);
+        self.camera.unproject(uvw.x, 1.0 - uvw.y, depth).unwrap_or(self.camera.get_position())
+    }
+
+    /// world position to voxel coord.
+    pub fn world_to_voxel(&self, world_position: vec3f) -> vec3f {
+        self.world_to_local(world_position) * self.buffer.get_sizef()
+    }
+    /// voxel coord to world position.
+    pub fn voxel_to_world(&self, voxel_coord: vec3f) -> vec3f {
+        self.local_to_world(voxel_coord / self.buffer.get_sizef())
+    }
+
+    /// Rasterization target for a named attribute. Only density can be rasterized.
+    pub fn attribute_target(&mut self, attr: &AttrRef) -> Option<&mut dyn RasterizationTarget> {
+        if *attr == ATTR_DENSITY { Some(self) } else { None }
+    }
+
+    pub fn sample_by_world_position(&self, world_position: vec3f) -> f32 {
+        let uvw = self.world_to_local(world_position);
+        self.buffer.sample_by_local_position(uvw.x, uvw.y, uvw.z)
+    }
+
+    fn depth_to_local(&self, depth: f32) -> f32 {
+        match self.depth_mapping {
+            FrustumDepth::Linear => (depth - self.near) / (self.far - self.near),
+            FrustumDepth::Exponential => {
+                if depth <= 0.0 {
+                    return -1.0;
+                }
+                (depth / self.near).ln() / (self.far / self.near).ln()
+            }
+        }
+    }
+    fn local_to_depth(&self, w: f32) -> f32 {
+        match self.depth_mapping {
+            FrustumDepth::Linear => self.near + w * (self.far - self.near),
+            FrustumDepth::Exponential => self.near * (self.far / self.near).powf(w)
+        }
+    }
+}
+
+impl RasterizationTarget for FrustumVolume {
+    fn world_to_voxel(&self, world_position: vec3f) -> vec3f {
+        FrustumVolume::world_to_voxel(self, world_position)
+    }
+    fn voxel_to_world(&self, voxel_coord: vec3f) -> vec3f {
+        FrustumVolume::voxel_to_world(self, voxel_coord)
+    }
+    fn world_to_local(&self, world_position: vec3f) -> vec3f {
+        FrustumVolume::world_to_local(self, world_position)
+    }
+    fn get_buffer(&mut self) -> &mut dyn VoxelBuffer<f32> {
+        &mut *self.buffer
+    }
+    fn can_project(&self, world_position: vec3f) -> bool {
+        self.camera.world_to_camera(world_position).z < 0.0
+    }
+}
+
+impl Volume for FrustumVolume {
+    fn emission(&self, p: vec3f) -> vec3f {
+        self.emission_value * self.sample_by_world_position(p)
+    }
+    fn absorption_coeff(&self, p: vec3f) -> vec3f {
+        self.absorption_coeff * self.sample_by_world_position(p)
+    }
+    fn scattering_coeff(&self, p: vec3f) -> vec3f {
+        self.scattering_coeff * self.sample_by_world_position(p)
+    }
+    fn sample(&self, world_position : vec3f) -> VolumeSample {
+        let density = self.sample_by_world_position(world_position);
+        VolumeSample {
+            emission: self.emission_value * density,
+            absorption_coeff: self.absorption_coeff * density,
+            scattering_coeff: self.scattering_coeff * density
+        }
+    }
+
+    fn set_phase_function(&mut self, phase_fn: Box<dyn PhaseFunction>) {
+        self.phase_fn = phase_fn;
+    }
+    fn phase_function(&self, _p: vec3f, wi: vec3f, wo: vec3f) -> f32 {
+        self.phase_fn.probability(wi, wo)
+    }
+    fn sample_phase_function(&self, _p: vec3f, wi: vec3f, u1: f32, u2: f32) -> vec3f {
+        self.phase_fn.sample(wi, u1, u2)
+    }
+
+    // #todo-frustum: Intersect with the frustum planes rather than its bounding box.
+    fn find_intersections(&self, ray: Ray) -> Vec<(f32, f32)> {
+        self.buffer.find_intersections(ray, self.world_bounds)
+    }
+    fn attributes(&self) -> Vec<AttrRef> {
+        let mut attrs = LIGHTING_ATTRIBUTES.to_vec();
+        attrs.push(ATTR_DENSITY);
+        attrs
+    }
+    fn sample_attribute(&self, attr: &AttrRef, world_position: vec3f) -> Option<vec3f> {
+        if *attr == ATTR_DENSITY {
+            scalar_attribute(self.sample_by_world_position(world_position))
+        } else {
+            sample_lighting_attribute(self, attr, world_position)
+        }
+    }
+    fn world_bounds(&self) -> AABB {
+        self.world_bounds
+    }
+}
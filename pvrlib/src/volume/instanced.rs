@@ -0,0 +1,198 @@
+use super::*;
+use crate::math::vec3::*;
+use crate::math::ray::*;
+use crate::math::aabb::AABB;
+use crate::math::matrix::Matrix4;
+use crate::math::bvh::BVH;
+use crate::phasefn::*;
+
+use std::sync::Arc;
+
+// Placement of one copy of the prototype.
+#[derive(Copy, Clone, Debug)]
+pub struct VolumeInstance {
+    pub local_to_world: Matrix4,
+    // Multiplies emission, absorption and scattering.
+    pub density: f32,
+    // Multiplies emission.
+    pub emission_tint: vec3f
+}
+
+impl VolumeInstance {
+    pub fn new(local_to_world: Matrix4) -> VolumeInstance {
+        VolumeInstance { local_to_world, density: 1.0, emission_tint: vec3f::one() }
+    }
+}
+
+struct InstanceData {
+    local_to_world: Matrix4,
+    world_to_local: Matrix4,
+    density: f32,
+    emission_tint: vec3f
+}
+
+// Many copies of a single prototype volume (ex: asteroid field, cloud cluster from a few hero puffs).
+// Only per-instance transforms are stored, and instances are culled by a BVH.
+// Overlapping instances add up.
+pub struct InstancedVolume {
+    prototype: Arc<dyn Volume>,
+    instances: Vec<InstanceData>,
+    bvh: BVH,
+    world_bounds: AABB,
+    // Prototype's attributes, cached as they're checked per sample
+    attributes: Vec<AttrRef>
+}
+
+impl InstancedVolume {
+    /// Panics if any instance transform is not invertible.
+    pub fn new(prototype: Arc<dyn Volume>, instances: &[VolumeInstance]) -> InstancedVolume {
+        let prototype_bounds = prototype.world_bounds();
+        let bounds: Vec<AABB> = instances.iter()
+            .map(|instance| instance.local_to_world.transform_aabb(prototype_bounds))
+            .collect();
+        let world_bounds = bounds.iter()
+            .fold(None, |acc: Option<AABB>, b| Some(acc.map_or(*b, |a| a.extend(*b))))
+            .unwrap_or_default();
+
+        let instances = instances.iter().map(|instance| InstanceData {
+            local_to_world: instance.local_to_world,
+            world_to_local: instance.local_to_world.inverse().expect("InstancedVolume: transform is not invertible"),
+            density: instance.density,
+            emission_tint: instance.emission_tint
+        }).collect();
+
+        InstancedVolume {
+            attributes: prototype.attributes(),
+            prototype,
+            instances,
+            bvh: BVH::new(&bounds),
+            world_bounds
+        }
+    }
+
+    pub fn get_prototype(&self) -> Arc<dyn Volume> {
+        self.prototype.clone()
+    }
+    pub fn instance_count(&self) -> usize {
+        self.instances.len()
+    }
+
+    // Calls `f` with (instance, local position) for instances that might contain `p`.
+    fn for_each_instance<F: FnMut(&InstanceData, vec3f)>(&self, p: vec3f, mut f: F) {
+        self.bvh.query_point(p, |i| {
+            let instance = &self.instances[i];
+            f(instance, instance.world_to_local.transform_position(p));
+        });
+    }
+}
+
+impl Volume for InstancedVolume {
+    fn emission(&self, p: vec3f) -> vec3f {
+        let mut total = vec3f::zero();
+        self.for_each_instance(p, |instance, local| {
+            total += self.prototype.emission(local) * instance.emission_tint * instance.density;
+        });
+        total
+    }
+    fn absorption_coeff(&self, p: vec3f) -> vec3f {
+        let mut total = vec3f::zero();
+        self.for_each_instance(p, |instance, local| {
+            total += self.prototype.absorption_coeff(local) * instance.density;
+        });
+        total
+    }
+    fn scattering_coeff(&self, p: vec3f) -> vec3f {
+        let mut total = vec3f::zero();
+        self.for_each_instance(p, |instance, local| {
+            total += self.prototype.scattering_coeff(local) * instance.density;
+        });
+        total
+    }
+    fn sample(&self, world_position: vec3f) -> VolumeSample {
+        let mut samp = VolumeSample::new();
+        self.for_each_instance(world_position, |instance, local| {
+            let s = self.prototype.sample(local);
+            samp.emission += s.emission * instance.emission_tint * instance.density;
+            samp.absorption_coeff += s.absorption_coeff * instance.density;
+            samp.scattering_coeff += s.scattering_coeff * instance.density;
+        });
+        samp
+    }
+
+    fn set_phase_function(&mut self, phase_fn: Box<dyn PhaseFunction>) {
+        match Arc::get_mut(&mut self.prototype) {
+            Some(prototype) => prototype.set_phase_function(phase_fn),
+            None => println!("WARNING: set_phase_fn() on a shared InstancedVolume prototype won't do nothing")
+        }
+    }
+    // Phase of overlapping instances, weighted by their scattering.
+    fn phase_function(&self, p: vec3f, wi: vec3f, wo: vec3f) -> f32 {
+        let mut total_phase = 0.0;
+        let mut total_weight = 0.0;
+        self.for_each_instance(p, |instance, local| {
+            let weight = self.prototype.scattering_coeff(local).max_component() * instance.density;
+            if weight > 0.0 {
+                let local_wi = instance.world_to_local.transform_direction(wi).normalize();
+                let local_wo = instance.world_to_local.transform_direction(wo).normalize();
+                total_phase += weight * self.prototype.phase_function(local, local_wi, local_wo);
+                total_weight += weight;
+            }
+        });
+        if total_weight > 0.0 { total_phase / total_weight } else { 0.0 }
+    }
+
+    // Pick an instance with the same weights as phase_function(), then sample its lobe.
+    fn sample_phase_function(&self, p: vec3f, wi: vec3f, u1: f32, u2: f32) -> vec3f {
+        let mut reservoir = WeightedReservoir::new(u1);
+        self.bvh.query_point(p, |i| {
+            let local = self.instances[i].world_to_local.transform_position(p);
+            reservoir.add((i, local), self.prototype.scattering_coeff(local).max_component() * self.instances[i].density);
+        });
+        match reservoir.picked() {
+            Some(((i, local), u1)) => {
+                let instance = &self.instances[i];
+                let local_wi = instance.world_to_local.transform_direction(wi).normalize();
+                let local_wo = self.prototype.sample_phase_function(local, local_wi, u1, u2);
+                instance.local_to_world.transform_direction(local_wo).normalize()
+            },
+            None => Isotropic{}.sample(wi, u1, u2)
+        }
+    }
+
+    fn find_intersections(&self, ray: Ray) -> Vec<(f32, f32)> {
+        let mut intervals = Vec::new();
+        self.bvh.query_ray(ray, |i| {
+            let m = &self.instances[i].world_to_local;
+            let local_ray = Ray {
+                o: m.transform_position(ray.o),
+                d: m.transform_direction(ray.d)
+            };
+            intervals.append(&mut self.prototype.find_intersections(local_ray));
+        });
+        intervals
+    }
+    fn attributes(&self) -> Vec<AttrRef> {
+        self.attributes.clone()
+    }
+    // Sum over overlapping instances, like the lighting properties. Instance density scales density too.
+    // #todo-attr: Vector attributes (ex: velocity) are not rotated to world space.
+    fn sample_attribute(&self, attr: &AttrRef, world_position: vec3f) -> Option<vec3f> {
+        if LIGHTING_ATTRIBUTES.contains(attr) {
+            return sample_lighting_attribute(self, attr, world_position);
+        }
+        if !self.attributes.contains(attr) {
+            return None;
+        }
+        let mut total = vec3f::zero();
+        self.for_each_instance(world_position, |instance, local| {
+            if let Some(value) = self.prototype.sample_attribute(attr, local) {
+                let scale = if *attr == ATTR_DENSITY { instance.density } else { 1.0 };
+                total += value * scale;
+            }
+        });
+        Some(total)
+    }
+    fn world_bounds(&self) -> AABB {
+        self.world_bounds
+    }
+}
@@ -1,13 +1,24 @@
 pub mod constant;
 pub mod voxel;
 pub mod composite;
+pub mod blackbody;
+pub mod frustum;
+pub mod transformed;
+pub mod instanced;
+pub mod phasefield;
+pub mod multifield;
+pub mod procedural;
+pub mod modifier;
+pub mod sdf;
 
-use crate::math::vec3::vec3f;
+use crate::math::vec3::*;
 use crate::math::ray::Ray;
 use crate::math::aabb::AABB;
 use crate::phasefn::PhaseFunction;
+use crate::phasefn::Isotropic;
 
 use std::marker::Sync;
+use std::borrow::Cow;
 
 pub struct VolumeSample {
     pub emission: vec3f,         // #todo-physics: Physically correct unit
@@ -24,9 +35,162 @@ impl VolumeSample {
     }
 }
 
+// Handle to a named volume attribute. Any volume can expose any attribute,
+// so custom shaders and AOVs can read fields the Volume trait doesn't know about.
+// Scalar attributes (ex: density, temperature) have the same value in all three components.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct AttrRef {
+    name: Cow<'static, str>
+}
+impl AttrRef {
+    pub const fn new(name: &'static str) -> AttrRef {
+        AttrRef { name: Cow::Borrowed(name) }
+    }
+    pub fn named(name: &str) -> AttrRef {
+        AttrRef { name: Cow::Owned(name.to_string()) }
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+// Lighting properties, provided by every volume.
+pub const ATTR_EMISSION: AttrRef = AttrRef::new("emission");
+pub const ATTR_ABSORPTION: AttrRef = AttrRef::new("absorption");
+pub const ATTR_SCATTERING: AttrRef = AttrRef::new("scattering");
+// Common attributes, provided only by volumes that have them.
+pub const ATTR_DENSITY: AttrRef = AttrRef::new("density");
+pub const ATTR_TEMPERATURE: AttrRef = AttrRef::new("temperature");
+pub const ATTR_VELOCITY: AttrRef = AttrRef::new("velocity");
+
+// Attributes of the raymarcher's lighting model. VolumeSample holds all of them,
+// so integrate_ray() reads them at once with sample_segment() rather than by handle.
+pub const LIGHTING_ATTRIBUTES: [AttrRef; 3] = [ATTR_EMISSION, ATTR_ABSORPTION, ATTR_SCATTERING];
+
+// Default of Volume::sample_attribute(). Volumes that override it fall back to this.
+pub fn sample_lighting_attribute<V: Volume + ?Sized>(volume: &V, attr: &AttrRef, world_position: vec3f) -> Option<vec3f> {
+    match attr.name() {
+        "emission" => Some(volume.emission(world_position)),
+        "absorption" => Some(volume.absorption_coeff(world_position)),
+        "scattering" => Some(volume.scattering_coeff(world_position)),
+        _ => None
+    }
+}
+
+fn scalar_attribute(value: f32) -> Option<vec3f> {
+    Some(vec3(value, value, value))
+}
+
+// Range [t_min, t_max] of a ray and the child volumes that overlap it.
+// `volumes` are indices of children for volumes that have children (ex: CompositeVolume),
+// and empty for others.
+#[derive(Clone, PartialEq, Debug)]
+pub struct VolumeSegment {
+    pub t_min: f32,
+    pub t_max: f32,
+    pub volumes: Vec<usize>
+}
+
+// How density fades out toward the surface of a shape (ex: ConstantVolumeShape, SDF).
+// Width is the depth inside the shape, in world units.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Falloff {
+    Hard,
+    Linear(f32),
+    Smooth(f32)
+}
+
+impl Falloff {
+    /// Density multiplier for a signed distance to the surface. 0 outside, 1 deep inside.
+    pub fn evaluate(&self, signed_distance: f32) -> f32 {
+        if signed_distance > 0.0 {
+            return 0.0;
+        }
+        match *self {
+            Falloff::Hard => 1.0,
+            Falloff::Linear(width) => ramp(signed_distance, width),
+            Falloff::Smooth(width) => {
+                let x = ramp(signed_distance, width);
+                x * x * (3.0 - 2.0 * x)
+            }
+        }
+    }
+}
+
+// 1 inside, 0 at the boundary, linear over `width`.
+fn ramp(distance: f32, width: f32) -> f32 {
+    if width <= 0.0 {
+        return 1.0;
+    }
+    (-distance / width).clamp(0.0, 1.0)
+}
+
+// Sorts intervals and merges the overlapping ones.
+pub fn merge_intervals(mut intervals: Vec<(f32, f32)>) -> Vec<(f32, f32)> {
+    intervals.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let mut merged: Vec<(f32, f32)> = Vec::with_capacity(intervals.len());
+    for (t_min, t_max) in intervals {
+        match merged.last_mut() {
+            Some(last) if t_min <= last.1 => last.1 = last.1.max(t_max),
+            _ => merged.push((t_min, t_max))
+        }
+    }
+    merged
+}
+
+// Intersection of two lists of sorted, non-overlapping intervals.
+pub fn intersect_intervals(a: &[(f32, f32)], b: &[(f32, f32)]) -> Vec<(f32, f32)> {
+    let mut result = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        let t_min = a[i].0.max(b[j].0);
+        let t_max = a[i].1.min(b[j].1);
+        if t_min < t_max {
+            result.push((t_min, t_max));
+        }
+        if a[i].1 < b[j].1 { i += 1; } else { j += 1; }
+    }
+    result
+}
+
+// Picks one item of a stream with probability proportional to its weight, without storing them.
+// `u` is remapped after every item so that the picked one can use it again.
+struct WeightedReservoir<T> {
+    picked: Option<T>,
+    total_weight: f32,
+    u: f32
+}
+
+impl<T> WeightedReservoir<T> {
+    fn new(u: f32) -> WeightedReservoir<T> {
+        WeightedReservoir { picked: None, total_weight: 0.0, u }
+    }
+
+    fn add(&mut self, item: T, weight: f32) {
+        if weight.is_nan() || weight <= 0.0 {
+            return;
+        }
+        self.total_weight += weight;
+        let p = weight / self.total_weight;
+        if self.u < p {
+            self.picked = Some(item);
+            self.u /= p;
+        } else {
+            self.u = (self.u - p) / (1.0 - p);
+        }
+        self.u = self.u.clamp(0.0, 1.0 - f32::EPSILON);
+    }
+
+    // The picked item and the remapped `u`.
+    fn picked(self) -> Option<(T, f32)> {
+        let u = self.u;
+        self.picked.map(|item| (item, u))
+    }
+}
+
 // Designed for physically based volumetric lighting.
 // #note: Do not introduce the concept of 'density' here.
-pub trait Volume : Sync {
+pub trait Volume : Send + Sync {
 
     // ----------------------------------------------------------
     // Lighting properties
@@ -50,12 +214,43 @@ pub trait Volume : Sync {
     /// `wo` : Outgoing direction.
     fn phase_function(&self, world_position: vec3f, wi: vec3f, wo: vec3f) -> f32;
 
+    /// Sample an outgoing direction at `world_position` for the incoming direction `wi`.<br/>
+    /// Directions are distributed as phase_function(), which is also their pdf.<br/>
+    /// `u1`, `u2` : Uniform random numbers in [0, 1).<br/>
+    /// Defaults to uniform sampling of the sphere, so volumes whose phase_function() isn't isotropic must override it.
+    fn sample_phase_function(&self, _world_position: vec3f, wi: vec3f, u1: f32, u2: f32) -> vec3f {
+        Isotropic{}.sample(wi, u1, u2)
+    }
+
     // #todo-refactor: This is not mandatory for trait API.
     fn set_phase_function(&mut self, phase_fn: Box<dyn PhaseFunction>);
 
     /// Return valid intervals to raymarch the given ray.
     fn find_intersections(&self, ray: Ray) -> Vec<(f32, f32)>; // (t_min, t_max) of the ray
 
+    /// Return sorted, non-overlapping segments to raymarch the given ray.
+    fn find_segments(&self, ray: Ray) -> Vec<VolumeSegment> {
+        merge_intervals(self.find_intersections(ray))
+            .into_iter()
+            .map(|(t_min, t_max)| VolumeSegment { t_min, t_max, volumes: Vec::new() })
+            .collect()
+    }
+
+    /// Same as sample(), but only the volumes active in `segment` are sampled.
+    fn sample_segment(&self, world_position: vec3f, _segment: &VolumeSegment) -> VolumeSample {
+        self.sample(world_position)
+    }
+
+    /// Attributes that sample_attribute() returns a value for.
+    fn attributes(&self) -> Vec<AttrRef> {
+        LIGHTING_ATTRIBUTES.to_vec()
+    }
+
+    /// Sample a named attribute at the given position. None if this volume doesn't have it.
+    fn sample_attribute(&self, attr: &AttrRef, world_position: vec3f) -> Option<vec3f> {
+        sample_lighting_attribute(self, attr, world_position)
+    }
+
     /// World space bounds of this volume.
     fn world_bounds(&self) -> AABB;
 
@@ -0,0 +1,425 @@
+use super::*;
+use crate::math::vec3::*;
+use crate::math::ray::*;
+use crate::math::solve_quadratic;
+use crate::math::aabb::AABB;
+use crate::phasefn::*;
+
+// Composable wrappers that modify other volumes without re-voxelizing them.
+// (ex: carve a hole through fog, fade a cloud at its edges)
+
+fn scale_sample(samp: VolumeSample, emission: vec3f, absorption: vec3f, scattering: vec3f) -> VolumeSample {
+    VolumeSample {
+        emission: samp.emission * emission,
+        absorption_coeff: samp.absorption_coeff * absorption,
+        scattering_coeff: samp.scattering_coeff * scattering
+    }
+}
+
+// ----------------------------------------------------------
+// ScaledVolume
+
+// Multiplies the coefficients of a volume.
+pub struct ScaledVolume {
+    pub volume: Box<dyn Volume>,
+    pub emission_scale: vec3f,
+    pub absorption_scale: vec3f,
+    pub scattering_scale: vec3f,
+    // Multiplies the density attribute, so that shaders reading it agree with the coefficients.
+    pub density_scale: f32
+}
+
+impl ScaledVolume {
+    // Same scale for all coefficients and density, like a density multiplier.
+    pub fn new(volume: Box<dyn Volume>, scale: f32) -> ScaledVolume {
+        let s = vec3(scale, scale, scale);
+        ScaledVolume { volume, emission_scale: s, absorption_scale: s, scattering_scale: s, density_scale: scale }
+    }
+}
+
+impl Volume for ScaledVolume {
+    fn emission(&self, p: vec3f) -> vec3f {
+        self.volume.emission(p) * self.emission_scale
+    }
+    fn absorption_coeff(&self, p: vec3f) -> vec3f {
+        self.volume.absorption_coeff(p) * self.absorption_scale
+    }
+    fn scattering_coeff(&self, p: vec3f) -> vec3f {
+        self.volume.scattering_coeff(p) * self.scattering_scale
+    }
+    fn sample(&self, world_position: vec3f) -> VolumeSample {
+        scale_sample(self.volume.sample(world_position), self.emission_scale, self.absorption_scale, self.scattering_scale)
+    }
+
+    fn set_phase_function(&mut self, phase_fn: Box<dyn PhaseFunction>) {
+        self.volume.set_phase_function(phase_fn);
+    }
+    fn phase_function(&self, p: vec3f, wi: vec3f, wo: vec3f) -> f32 {
+        self.volume.phase_function(p, wi, wo)
+    }
+    fn sample_phase_function(&self, p: vec3f, wi: vec3f, u1: f32, u2: f32) -> vec3f {
+        self.volume.sample_phase_function(p, wi, u1, u2)
+    }
+
+    fn find_intersections(&self, ray: Ray) -> Vec<(f32, f32)> {
+        self.volume.find_intersections(ray)
+    }
+    fn attributes(&self) -> Vec<AttrRef> {
+        self.volume.attributes()
+    }
+    // Lighting attributes and density are scaled. Others pass through.
+    fn sample_attribute(&self, attr: &AttrRef, world_position: vec3f) -> Option<vec3f> {
+        if *attr == ATTR_DENSITY {
+            return self.volume.sample_attribute(attr, world_position).map(|density| density * self.density_scale);
+        }
+        match sample_lighting_attribute(self, attr, world_position) {
+            Some(value) => Some(value),
+            None => self.volume.sample_attribute(attr, world_position)
+        }
+    }
+    fn world_bounds(&self) -> AABB {
+        self.volume.world_bounds()
+    }
+}
+
+// ----------------------------------------------------------
+// ClippedVolume
+
+#[derive(Copy, Clone, Debug)]
+pub enum ClipShape {
+    // Keeps the side that `normal` points away from.
+    HalfSpace { point: vec3f, normal: vec3f },
+    Sphere { center: vec3f, radius: f32 },
+    Box(AABB)
+}
+
+impl ClipShape {
+    pub fn contains(&self, p: vec3f) -> bool {
+        match *self {
+            ClipShape::HalfSpace { point, normal } => ((p - point) & normal) <= 0.0,
+            ClipShape::Sphere { center, radius } => (p - center).length_sq() <= radius * radius,
+            ClipShape::Box(bounds) => bounds.contains(p)
+        }
+    }
+
+    // Range of the ray inside the shape. Might be infinite for half-spaces.
+    fn intersect(&self, ray: Ray) -> Option<(f32, f32)> {
+        match *self {
+            ClipShape::HalfSpace { point, normal } => {
+                let d_dot_n = ray.d & normal;
+                let t = ((point - ray.o) & normal) / d_dot_n;
+                if d_dot_n > 0.0 {
+                    Some((f32::MIN, t))
+                } else if d_dot_n < 0.0 {
+                    Some((t, f32::MAX))
+                } else if self.contains(ray.o) {
+                    Some((f32::MIN, f32::MAX))
+                } else {
+                    None
+                }
+            },
+            ClipShape::Sphere { center, radius } => {
+                let delta = ray.o - center;
+                solve_quadratic(ray.d.length_sq(), 2.0 * (ray.d & delta), delta.length_sq() - radius * radius)
+            },
+            ClipShape::Box(bounds) => bounds.intersect(ray)
+        }
+    }
+}
+
+// Cuts a volume by a shape. Inverted, it carves the shape out of the volume instead.
+pub struct ClippedVolume {
+    pub volume: Box<dyn Volume>,
+    pub shape: ClipShape,
+    pub invert: bool
+}
+
+impl ClippedVolume {
+    pub fn new(volume: Box<dyn Volume>, shape: ClipShape, invert: bool) -> ClippedVolume {
+        ClippedVolume { volume, shape, invert }
+    }
+
+    fn is_kept(&self, p: vec3f) -> bool {
+        self.shape.contains(p) != self.invert
+    }
+}
+
+impl Volume for ClippedVolume {
+    fn emission(&self, p: vec3f) -> vec3f {
+        if self.is_kept(p) { self.volume.emission(p) } else { vec3f::zero() }
+    }
+    fn absorption_coeff(&self, p: vec3f) -> vec3f {
+        if self.is_kept(p) { self.volume.absorption_coeff(p) } else { vec3f::zero() }
+    }
+    fn scattering_coeff(&self, p: vec3f) -> vec3f {
+        if self.is_kept(p) { self.volume.scattering_coeff(p) } else { vec3f::zero() }
+    }
+    fn sample(&self, world_position: vec3f) -> VolumeSample {
+        if self.is_kept(world_position) { self.volume.sample(world_position) } else { VolumeSample::new() }
+    }
+
+    fn set_phase_function(&mut self, phase_fn: Box<dyn PhaseFunction>) {
+        self.volume.set_phase_function(phase_fn);
+    }
+    fn phase_function(&self, p: vec3f, wi: vec3f, wo: vec3f) -> f32 {
+        self.volume.phase_function(p, wi, wo)
+    }
+    fn sample_phase_function(&self, p: vec3f, wi: vec3f, u1: f32, u2: f32) -> vec3f {
+        self.volume.sample_phase_function(p, wi, u1, u2)
+    }
+
+    // #todo-clip: Inverted shapes don't split the intervals.
+    fn find_intersections(&self, ray: Ray) -> Vec<(f32, f32)> {
+        let intervals = merge_intervals(self.volume.find_intersections(ray));
+        if self.invert {
+            return intervals;
+        }
+        match self.shape.intersect(ray) {
+            Some(range) => intersect_intervals(&intervals, &[range]),
+            None => Vec::new()
+        }
+    }
+    fn attributes(&self) -> Vec<AttrRef> {
+        self.volume.attributes()
+    }
+    fn sample_attribute(&self, attr: &AttrRef, world_position: vec3f) -> Option<vec3f> {
+        let value = self.volume.sample_attribute(attr, world_position)?;
+        Some(if self.is_kept(world_position) { value } else { vec3f::zero() })
+    }
+    fn world_bounds(&self) -> AABB {
+        let bounds = self.volume.world_bounds();
+        if self.invert {
+            return bounds;
+        }
+        let shape_bounds = match self.shape {
+            ClipShape::HalfSpace { .. } => return bounds,
+            ClipShape::Sphere { center, radius } => {
+                let r = vec3(radius, radius, radius);
+                AABB { min: center - r, max: center + r }
+            },
+            ClipShape::Box(shape_bounds) => shape_bounds
+        };
+        // Collapses to a point if they don't overlap.
+        let min = vec3f::max(bounds.min, shape_bounds.min);
+        let max = vec3f::max(vec3f::min(bounds.max, shape_bounds.max), min);
+        AABB { min, max }
+    }
+}
+
+// ----------------------------------------------------------
+// MaskedVolume
+
+// Multiplies the coefficients of a volume by an attribute of another volume.
+// ex) Fade a cloud at its edges with a soft procedural mask.
+pub struct MaskedVolume {
+    pub volume: Box<dyn Volume>,
+    pub mask: Box<dyn Volume>,
+    // x component is used. Density if the mask has it, absorption otherwise.
+    pub mask_attribute: AttrRef
+}
+
+impl MaskedVolume {
+    pub fn new(volume: Box<dyn Volume>, mask: Box<dyn Volume>) -> MaskedVolume {
+        let mask_attribute = if mask.attributes().contains(&ATTR_DENSITY) { ATTR_DENSITY } else { ATTR_ABSORPTION };
+        MaskedVolume { volume, mask, mask_attribute }
+    }
+
+    pub fn sample_mask(&self, world_position: vec3f) -> f32 {
+        self.mask.sample_attribute(&self.mask_attribute, world_position).map_or(0.0, |v| v.x)
+    }
+}
+
+impl Volume for MaskedVolume {
+    fn emission(&self, p: vec3f) -> vec3f {
+        self.volume.emission(p) * self.sample_mask(p)
+    }
+    fn absorption_coeff(&self, p: vec3f) -> vec3f {
+        self.volume.absorption_coeff(p) * self.sample_mask(p)
+    }
+    fn scattering_coeff(&self, p: vec3f) -> vec3f {
+        self.volume.scattering_coeff(p) * self.sample_mask(p)
+    }
+    fn sample(&self, world_position: vec3f) -> VolumeSample {
+        let m = self.sample_mask(world_position);
+        let m = vec3(m, m, m);
+        scale_sample(self.volume.sample(world_position), m, m, m)
+    }
+
+    fn set_phase_function(&mut self, phase_fn: Box<dyn PhaseFunction>) {
+        self.volume.set_phase_function(phase_fn);
+    }
+    fn phase_function(&self, p: vec3f, wi: vec3f, wo: vec3f) -> f32 {
+        self.volume.phase_function(p, wi, wo)
+    }
+    fn sample_phase_function(&self, p: vec3f, wi: vec3f, u1: f32, u2: f32) -> vec3f {
+        self.volume.sample_phase_function(p, wi, u1, u2)
+    }
+
+    // The mask is zero outside of its own intervals.
+    fn find_intersections(&self, ray: Ray) -> Vec<(f32, f32)> {
+        intersect_intervals(
+            &merge_intervals(self.volume.find_intersections(ray)),
+            &merge_intervals(self.mask.find_intersections(ray)))
+    }
+    fn attributes(&self) -> Vec<AttrRef> {
+        self.volume.attributes()
+    }
+    fn sample_attribute(&self, attr: &AttrRef, world_position: vec3f) -> Option<vec3f> {
+        if *attr == ATTR_DENSITY {
+            let density = self.volume.sample_attribute(attr, world_position)?;
+            return Some(density * self.sample_mask(world_position));
+        }
+        match sample_lighting_attribute(self, attr, world_position) {
+            Some(value) => Some(value),
+            None => self.volume.sample_attribute(attr, world_position)
+        }
+    }
+    fn world_bounds(&self) -> AABB {
+        self.volume.world_bounds()
+    }
+}
+
+// ----------------------------------------------------------
+// CombinedVolume
+
+// Per-coefficient operators. Use CompositeVolume to add volumes.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum CombineOp {
+    Max,
+    Min,
+    // a - b, clamped to zero. Carves b out of a.
+    Subtract,
+    // a + b * (1 - opacity of a). Coefficients are per unit length, so the opacity of a
+    // is taken over `length` world units: 1 - exp(-absorption of a * length), per channel.
+    Over { length: f32 }
+}
+
+pub struct CombinedVolume {
+    pub a: Box<dyn Volume>,
+    pub b: Box<dyn Volume>,
+    pub op: CombineOp
+}
+
+impl CombinedVolume {
+    pub fn new(a: Box<dyn Volume>, b: Box<dyn Volume>, op: CombineOp) -> CombinedVolume {
+        CombinedVolume { a, b, op }
+    }
+
+    // `a_absorption` is only used by Over.
+    fn apply(&self, x: vec3f, y: vec3f, a_absorption: vec3f) -> vec3f {
+        match self.op {
+            CombineOp::Max => vec3f::max(x, y),
+            CombineOp::Min => vec3f::min(x, y),
+            CombineOp::Subtract => vec3f::max(x - y, vec3f::zero()),
+            // 1 - opacity is the transmittance of a
+            CombineOp::Over { length } => x + y * (-a_absorption * length).exp()
+        }
+    }
+
+    fn combine(&self, a: VolumeSample, b: VolumeSample) -> VolumeSample {
+        VolumeSample {
+            emission: self.apply(a.emission, b.emission, a.absorption_coeff),
+            absorption_coeff: self.apply(a.absorption_coeff, b.absorption_coeff, a.absorption_coeff),
+            scattering_coeff: self.apply(a.scattering_coeff, b.scattering_coeff, a.absorption_coeff)
+        }
+    }
+
+    // Scattering weights of a and b for phase function blending
+    fn phase_weights(&self, p: vec3f) -> [f32; 2] {
+        let a = self.a.scattering_coeff(p).max_component();
+        let b = self.b.scattering_coeff(p).max_component();
+        match self.op {
+            CombineOp::Subtract => [a, 0.0],
+            _ => [a, b]
+        }
+    }
+}
+
+impl Volume for CombinedVolume {
+    fn emission(&self, p: vec3f) -> vec3f {
+        self.sample(p).emission
+    }
+    fn absorption_coeff(&self, p: vec3f) -> vec3f {
+        self.sample(p).absorption_coeff
+    }
+    fn scattering_coeff(&self, p: vec3f) -> vec3f {
+        self.sample(p).scattering_coeff
+    }
+    fn sample(&self, world_position: vec3f) -> VolumeSample {
+        self.combine(self.a.sample(world_position), self.b.sample(world_position))
+    }
+
+    fn set_phase_function(&mut self, _phase_fn: Box<dyn PhaseFunction>) {
+        // #todo-phase: Phase functions can't be cloned to both volumes.
+        println!("WARNING: set_phase_fn() on CombinedVolume won't do nothing");
+    }
+    // Phase functions of a and b blended by their scattering, like CompositeVolume.
+    fn phase_function(&self, p: vec3f, wi: vec3f, wo: vec3f) -> f32 {
+        let [wa, wb] = self.phase_weights(p);
+        if wa + wb <= 0.0 {
+            return 0.0;
+        }
+        let mut total = 0.0;
+        if wa > 0.0 {
+            total += wa * self.a.phase_function(p, wi, wo);
+        }
+        if wb > 0.0 {
+            total += wb * self.b.phase_function(p, wi, wo);
+        }
+        total / (wa + wb)
+    }
+    fn sample_phase_function(&self, p: vec3f, wi: vec3f, u1: f32, u2: f32) -> vec3f {
+        let [wa, wb] = self.phase_weights(p);
+        let mut reservoir = WeightedReservoir::new(u1);
+        reservoir.add(&self.a, wa);
+        reservoir.add(&self.b, wb);
+        match reservoir.picked() {
+            Some((volume, u1)) => volume.sample_phase_function(p, wi, u1, u2),
+            None => Isotropic{}.sample(wi, u1, u2)
+        }
+    }
+
+    fn find_intersections(&self, ray: Ray) -> Vec<(f32, f32)> {
+        let a = merge_intervals(self.a.find_intersections(ray));
+        match self.op {
+            CombineOp::Max | CombineOp::Over { .. } => {
+                let mut intervals = a;
+                intervals.append(&mut self.b.find_intersections(ray));
+                merge_intervals(intervals)
+            },
+            CombineOp::Min => intersect_intervals(&a, &merge_intervals(self.b.find_intersections(ray))),
+            CombineOp::Subtract => a
+        }
+    }
+    // Union of the attributes of a and b
+    fn attributes(&self) -> Vec<AttrRef> {
+        let mut attributes = self.a.attributes();
+        for attr in self.b.attributes() {
+            if !attributes.contains(&attr) {
+                attributes.push(attr);
+            }
+        }
+        attributes
+    }
+    // Combined with the same op. A volume that doesn't have the attribute counts as zero.
+    fn sample_attribute(&self, attr: &AttrRef, world_position: vec3f) -> Option<vec3f> {
+        if let Some(value) = sample_lighting_attribute(self, attr, world_position) {
+            return Some(value);
+        }
+        let (a, b) = (self.a.sample_attribute(attr, world_position), self.b.sample_attribute(attr, world_position));
+        if a.is_none() && b.is_none() {
+            return None;
+        }
+        let a_absorption = match self.op {
+            CombineOp::Over { .. } => self.a.absorption_coeff(world_position),
+            _ => vec3f::zero()
+        };
+        Some(self.apply(a.unwrap_or_default(), b.unwrap_or_default(), a_absorption))
+    }
+    fn world_bounds(&self) -> AABB {
+        match self.op {
+            CombineOp::Max | CombineOp::Over { .. } => self.a.world_bounds().extend(self.b.world_bounds()),
+            CombineOp::Min | CombineOp::Subtract => self.a.world_bounds()
+        }
+    }
+}
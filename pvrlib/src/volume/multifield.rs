@@ -0,0 +1,267 @@
+use super::*;
+use crate::math::vec3::*;
+use crate::math::ray::*;
+use crate::math::aabb::AABB;
+use crate::math::matrix::Matrix4;
+use crate::phasefn::PhaseFunction;
+use crate::voxelbuffer::VoxelBuffer;
+use crate::primitive::RasterizationTarget;
+
+use std::collections::HashMap;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ColorAttribute {
+    Emission,
+    Absorption,
+    Scattering
+}
+
+// Voxel volume with independent colored fields for emission, absorption and scattering,
+// plus any number of named scalar fields (ex: temperature, fuel).
+// Unlike VoxelVolume there is no density, so each voxel has its own color (ex: colored smoke mixtures).
+// Fields are sampled by the local uvw of the volume, so they may have different resolutions.
+pub struct MultiFieldVolume {
+    pub emission: Box<dyn VoxelBuffer<vec3f>>,
+    pub absorption: Box<dyn VoxelBuffer<vec3f>>,
+    pub scattering: Box<dyn VoxelBuffer<vec3f>>,
+    pub phase_fn: Box<dyn PhaseFunction>,
+
+    scalar_fields: HashMap<String, Box<dyn VoxelBuffer<f32>>>,
+
+    // Same mapping as VoxelVolume
+    local_bounds: AABB,
+    local_to_world: Matrix4,
+    world_to_local: Matrix4,
+    world_bounds: AABB
+}
+
+impl MultiFieldVolume {
+    // Identity transform, so local_bounds is also the world bounds.
+    pub fn new(
+        emission: Box<dyn VoxelBuffer<vec3f>>,
+        absorption: Box<dyn VoxelBuffer<vec3f>>,
+        scattering: Box<dyn VoxelBuffer<vec3f>>,
+        local_bounds: AABB,
+        phase_fn: Box<dyn PhaseFunction>) -> MultiFieldVolume
+    {
+        MultiFieldVolume {
+            emission,
+            absorption,
+            scattering,
+            phase_fn,
+            scalar_fields: HashMap::new(),
+            local_bounds,
+            local_to_world: Matrix4::identity(),
+            world_to_local: Matrix4::identity(),
+            world_bounds: local_bounds
+        }
+    }
+
+    /// Panics if `local_to_world` is not invertible.
+    pub fn set_transform(&mut self, local_to_world: Matrix4) {
+        self.local_to_world = local_to_world;
+        self.world_to_local = local_to_world.inverse().expect("MultiFieldVolume: transform is not invertible");
+        self.world_bounds = local_to_world.transform_aabb(self.local_bounds);
+    }
+    pub fn get_transform(&self) -> Matrix4 {
+        self.local_to_world
+    }
+    pub fn get_local_bounds(&self) -> AABB {
+        self.local_bounds
+    }
+
+    // Replaces the field if `name` already exists.
+    pub fn add_scalar_field(&mut self, name: &str, field: Box<dyn VoxelBuffer<f32>>) {
+        self.scalar_fields.insert(name.to_string(), field);
+    }
+    pub fn get_scalar_field(&self, name: &str) -> Option<&dyn VoxelBuffer<f32>> {
+        self.scalar_fields.get(name).map(|field| field.as_ref())
+    }
+    pub fn sample_scalar(&self, name: &str, world_position: vec3f) -> Option<f32> {
+        let uvw = self.world_to_local(world_position);
+        self.get_scalar_field(name).map(|field| field.sample_by_local_position(uvw.x, uvw.y, uvw.z))
+    }
+
+    /// world position to local uvw in voxel volume.
+    pub fn world_to_local(&self, world_position: vec3f) -> vec3f {
+        fit(self.world_to_local.transform_position(world_position),
+            self.local_bounds.min, self.local_bounds.max,
+            vec3f::zero(), vec3f::one())
+    }
+
+    /// Rasterization target for a colored field. Primitives write `color * density`.
+    pub fn color_target(&mut self, attribute: ColorAttribute, color: vec3f) -> FieldTarget<'_> {
+        let field = match attribute {
+            ColorAttribute::Emission => &mut self.emission,
+            ColorAttribute::Absorption => &mut self.absorption,
+            ColorAttribute::Scattering => &mut self.scattering
+        };
+        FieldTarget {
+            local_bounds: self.local_bounds,
+            local_to_world: self.local_to_world,
+            world_to_local: self.world_to_local,
+            buffer: TargetBuffer::Color(ColoredBuffer { field: field.as_mut(), color })
+        }
+    }
+    /// Rasterization target for a scalar field. None if there is no field named `name`.
+    pub fn scalar_target(&mut self, name: &str) -> Option<FieldTarget<'_>> {
+        let field = self.scalar_fields.get_mut(name)?;
+        Some(FieldTarget {
+            local_bounds: self.local_bounds,
+            local_to_world: self.local_to_world,
+            world_to_local: self.world_to_local,
+            buffer: TargetBuffer::Scalar(field.as_mut())
+        })
+    }
+    /// Rasterization target for a named attribute. `color` is used only for the lighting attributes.
+    pub fn attribute_target(&mut self, attr: &AttrRef, color: vec3f) -> Option<FieldTarget<'_>> {
+        match attr.name() {
+            "emission" => Some(self.color_target(ColorAttribute::Emission, color)),
+            "absorption" => Some(self.color_target(ColorAttribute::Absorption, color)),
+            "scattering" => Some(self.color_target(ColorAttribute::Scattering, color)),
+            name => self.scalar_target(name)
+        }
+    }
+}
+
+impl Volume for MultiFieldVolume {
+    fn emission(&self, p: vec3f) -> vec3f {
+        let uvw = self.world_to_local(p);
+        self.emission.sample_by_local_position(uvw.x, uvw.y, uvw.z)
+    }
+    fn absorption_coeff(&self, p: vec3f) -> vec3f {
+        let uvw = self.world_to_local(p);
+        self.absorption.sample_by_local_position(uvw.x, uvw.y, uvw.z)
+    }
+    fn scattering_coeff(&self, p: vec3f) -> vec3f {
+        let uvw = self.world_to_local(p);
+        self.scattering.sample_by_local_position(uvw.x, uvw.y, uvw.z)
+    }
+    fn sample(&self, world_position: vec3f) -> VolumeSample {
+        let uvw = self.world_to_local(world_position);
+        VolumeSample {
+            emission: self.emission.sample_by_local_position(uvw.x, uvw.y, uvw.z),
+            absorption_coeff: self.absorption.sample_by_local_position(uvw.x, uvw.y, uvw.z),
+            scattering_coeff: self.scattering.sample_by_local_position(uvw.x, uvw.y, uvw.z)
+        }
+    }
+
+    fn set_phase_function(&mut self, phase_fn: Box<dyn PhaseFunction>) {
+        self.phase_fn = phase_fn;
+    }
+    fn phase_function(&self, _p: vec3f, wi: vec3f, wo: vec3f) -> f32 {
+        self.phase_fn.probability(wi, wo)
+    }
+    fn sample_phase_function(&self, _p: vec3f, wi: vec3f, u1: f32, u2: f32) -> vec3f {
+        self.phase_fn.sample(wi, u1, u2)
+    }
+
+    // Union of the intervals of all colored fields, in world space t.
+    fn find_intersections(&self, ray: Ray) -> Vec<(f32, f32)> {
+        let local_ray = Ray {
+            o: self.world_to_local.transform_position(ray.o),
+            d: self.world_to_local.transform_direction(ray.d)
+        };
+        let mut intervals = self.emission.find_intersections(local_ray, self.local_bounds);
+        intervals.append(&mut self.absorption.find_intersections(local_ray, self.local_bounds));
+        intervals.append(&mut self.scattering.find_intersections(local_ray, self.local_bounds));
+        merge_intervals(intervals)
+    }
+    fn attributes(&self) -> Vec<AttrRef> {
+        let mut attrs = LIGHTING_ATTRIBUTES.to_vec();
+        attrs.extend(self.scalar_fields.keys().map(|name| AttrRef::named(name)));
+        attrs
+    }
+    fn sample_attribute(&self, attr: &AttrRef, world_position: vec3f) -> Option<vec3f> {
+        match self.sample_scalar(attr.name(), world_position) {
+            Some(value) => scalar_attribute(value),
+            None => sample_lighting_attribute(self, attr, world_position)
+        }
+    }
+    fn world_bounds(&self) -> AABB {
+        self.world_bounds
+    }
+}
+
+// One field of a MultiFieldVolume, exposed to rasterization primitives.
+pub struct FieldTarget<'a> {
+    local_bounds: AABB,
+    local_to_world: Matrix4,
+    world_to_local: Matrix4,
+    buffer: TargetBuffer<'a>
+}
+
+enum TargetBuffer<'a> {
+    Scalar(&'a mut dyn VoxelBuffer<f32>),
+    Color(ColoredBuffer<'a>)
+}
+
+impl FieldTarget<'_> {
+    fn buffer(&self) -> &dyn VoxelBuffer<f32> {
+        match &self.buffer {
+            TargetBuffer::Scalar(buffer) => &**buffer,
+            TargetBuffer::Color(buffer) => buffer
+        }
+    }
+}
+
+impl RasterizationTarget for FieldTarget<'_> {
+    fn world_to_voxel(&self, world_position: vec3f) -> vec3f {
+        let p = self.world_to_local.transform_position(world_position);
+        fit(p, self.local_bounds.min, self.local_bounds.max, vec3f::zero(), self.buffer().get_sizef())
+    }
+    fn voxel_to_world(&self, voxel_coord: vec3f) -> vec3f {
+        let p = fit(voxel_coord, vec3f::zero(), self.buffer().get_sizef(), self.local_bounds.min, self.local_bounds.max);
+        self.local_to_world.transform_position(p)
+    }
+    fn world_to_local(&self, world_position: vec3f) -> vec3f {
+        fit(self.world_to_local.transform_position(world_position),
+            self.local_bounds.min, self.local_bounds.max,
+            vec3f::zero(), vec3f::one())
+    }
+    fn get_buffer(&mut self) -> &mut dyn VoxelBuffer<f32> {
+        match &mut self.buffer {
+            TargetBuffer::Scalar(buffer) => &mut **buffer,
+            TargetBuffer::Color(buffer) => buffer
+        }
+    }
+}
+
+// Scalar view of a colored field. Reads the projection of the voxel color onto `color`,
+// and writes only that component, so colors rasterized before with other colors are kept.
+struct ColoredBuffer<'a> {
+    field: &'a mut dyn VoxelBuffer<vec3f>,
+    color: vec3f
+}
+
+impl ColoredBuffer<'_> {
+    fn to_scalar(&self, value: vec3f) -> f32 {
+        let length_sq = self.color.length_sq();
+        if length_sq > 0.0 { (value & self.color) / length_sq } else { 0.0 }
+    }
+}
+
+impl VoxelBuffer<f32> for ColoredBuffer<'_> {
+    fn sample_by_local_position(&self, u: f32, v: f32, w: f32) -> f32 {
+        self.to_scalar(self.field.sample_by_local_position(u, v, w))
+    }
+    fn get_size(&self) -> (i32, i32, i32) {
+        self.field.get_size()
+    }
+    fn get_sizef(&self) -> vec3f {
+        self.field.get_sizef()
+    }
+    fn find_intersections(&self, ray: Ray, world_bounds: AABB) -> Vec<(f32, f32)> {
+        self.field.find_intersections(ray, world_bounds)
+    }
+    fn get_occupancy(&self) -> f32 {
+        self.field.get_occupancy()
+    }
+    fn read(&self, i: i32, j: i32, k: i32) -> f32 {
+        self.to_scalar(self.field.read(i, j, k))
+    }
+    fn write(&mut self, i: i32, j: i32, k: i32, value: f32) {
+        let old = self.field.read(i, j, k);
+        self.field.write(i, j, k, old + self.color * (value - self.to_scalar(old)));
+    }
+}
@@ -0,0 +1,64 @@
+use crate::math::vec3::*;
+use crate::phasefn::*;
+use crate::voxelbuffer::VoxelBuffer;
+
+// Keep HG away from the delta lobes at g = +-1.
+const MAX_ECCENTRICITY: f32 = 0.99;
+
+// Phase function whose parameters vary over a voxel volume.
+// Each parameter is read from its own field at the local uvw of the volume,
+// so the fields don't need the same resolution as the density buffer.
+// ex) A cloud whose wispy edges are more forward scattering than its dense core.
+pub enum PhaseField {
+    HenyeyGreenstein {
+        g: Box<dyn VoxelBuffer<f32>>
+    },
+    DoubleHenyeyGreenstein {
+        g1: Box<dyn VoxelBuffer<f32>>,
+        g2: Box<dyn VoxelBuffer<f32>>,
+        b: Box<dyn VoxelBuffer<f32>>
+    }
+}
+
+impl PhaseField {
+    /// Evaluate the phase function at local uvw of the volume.
+    pub fn probability(&self, uvw: vec3f, wi: vec3f, wo: vec3f) -> f32 {
+        match self {
+            PhaseField::HenyeyGreenstein { g } => {
+                HenyeyGreenstein { g: read_eccentricity(g.as_ref(), uvw) }.probability(wi, wo)
+            },
+            PhaseField::DoubleHenyeyGreenstein { g1, g2, b } => {
+                double_hg(g1.as_ref(), g2.as_ref(), b.as_ref(), uvw).probability(wi, wo)
+            }
+        }
+    }
+
+    /// Sample an outgoing direction at local uvw of the volume.
+    pub fn sample(&self, uvw: vec3f, wi: vec3f, u1: f32, u2: f32) -> vec3f {
+        match self {
+            PhaseField::HenyeyGreenstein { g } => {
+                HenyeyGreenstein { g: read_eccentricity(g.as_ref(), uvw) }.sample(wi, u1, u2)
+            },
+            PhaseField::DoubleHenyeyGreenstein { g1, g2, b } => {
+                double_hg(g1.as_ref(), g2.as_ref(), b.as_ref(), uvw).sample(wi, u1, u2)
+            }
+        }
+    }
+}
+
+fn read_eccentricity(field: &dyn VoxelBuffer<f32>, uvw: vec3f) -> f32 {
+    field.sample_by_local_position(uvw.x, uvw.y, uvw.z).clamp(-MAX_ECCENTRICITY, MAX_ECCENTRICITY)
+}
+
+fn double_hg(
+    g1: &dyn VoxelBuffer<f32>,
+    g2: &dyn VoxelBuffer<f32>,
+    b: &dyn VoxelBuffer<f32>,
+    uvw: vec3f) -> DoubleHenyeyGreenstein
+{
+    DoubleHenyeyGreenstein {
+        g1: read_eccentricity(g1, uvw),
+        g2: read_eccentricity(g2, uvw),
+        b: b.sample_by_local_position(uvw.x, uvw.y, uvw.z).clamp(0.0, 1.0)
+    }
+}
@@ -0,0 +1,133 @@
+use super::*;
+use super::constant::ConstantVolumeShape;
+use crate::math::vec3::*;
+use crate::math::ray::*;
+use crate::math::aabb::AABB;
+use crate::math::noise::fBm_octaves;
+use crate::phasefn::PhaseFunction;
+
+#[derive(Copy, Clone, Debug)]
+pub struct NoiseSettings {
+    pub octaves: u32,
+    pub gain: f32,       // amplitude multiplier per octave
+    pub lacunarity: f32, // frequency multiplier per octave
+    pub frequency: f32,  // of the first octave, in 1 / world units
+    pub offset: vec3f,   // in noise space. Animate it to scroll the noise.
+    // Noise below threshold is empty, so higher values give sparser and wispier shapes.
+    pub threshold: f32,
+    pub amplitude: f32
+}
+
+impl Default for NoiseSettings {
+    fn default() -> NoiseSettings {
+        NoiseSettings {
+            octaves: 5,
+            gain: 0.5,
+            lacunarity: 2.0,
+            frequency: 1.0,
+            offset: vec3f::zero(),
+            threshold: 0.0,
+            amplitude: 1.0
+        }
+    }
+}
+
+// Noise evaluated on the fly in sample(), clipped to a ConstantVolumeShape.
+// Infinite resolution for close-up shots and no voxel memory, at the cost of evaluating
+// fBm at every step. Coefficients are multiplied by density like VoxelVolume.
+//
+// density = max(fBm(frequency * (p - center) + offset) - threshold, 0) * amplitude * falloff
+pub struct ProceduralVolume {
+    pub noise: NoiseSettings,
+    pub falloff: Falloff,
+
+    pub emission_value: vec3f,
+    pub absorption_coeff: vec3f,
+    pub scattering_coeff: vec3f,
+    pub phase_fn: Box<dyn PhaseFunction>,
+
+    shape: ConstantVolumeShape,
+    center: vec3f
+}
+
+impl ProceduralVolume {
+    pub fn new(
+        shape: ConstantVolumeShape,
+        center: vec3f,
+        noise: NoiseSettings,
+        phase_fn: Box<dyn PhaseFunction>) -> ProceduralVolume
+    {
+        ProceduralVolume {
+            noise,
+            falloff: Falloff::Hard,
+            emission_value: vec3f::zero(),
+            absorption_coeff: vec3f::zero(),
+            scattering_coeff: vec3f::zero(),
+            phase_fn,
+            shape,
+            center
+        }
+    }
+
+    pub fn sample_density(&self, world_position: vec3f) -> f32 {
+        let falloff = self.falloff.evaluate(self.shape.signed_distance(world_position - self.center));
+        if falloff <= 0.0 {
+            return 0.0;
+        }
+
+        let settings = &self.noise;
+        let p = (world_position - self.center) * settings.frequency + settings.offset;
+        let n = fBm_octaves(p, settings.octaves, settings.gain, settings.lacunarity);
+        (n - settings.threshold).max(0.0) * settings.amplitude * falloff
+    }
+}
+
+impl Volume for ProceduralVolume {
+    fn emission(&self, p: vec3f) -> vec3f {
+        self.emission_value * self.sample_density(p)
+    }
+    fn absorption_coeff(&self, p: vec3f) -> vec3f {
+        self.absorption_coeff * self.sample_density(p)
+    }
+    fn scattering_coeff(&self, p: vec3f) -> vec3f {
+        self.scattering_coeff * self.sample_density(p)
+    }
+    fn sample(&self, world_position: vec3f) -> VolumeSample {
+        let density = self.sample_density(world_position);
+        VolumeSample {
+            emission: self.emission_value * density,
+            absorption_coeff: self.absorption_coeff * density,
+            scattering_coeff: self.scattering_coeff * density
+        }
+    }
+
+    fn set_phase_function(&mut self, phase_fn: Box<dyn PhaseFunction>) {
+        self.phase_fn = phase_fn;
+    }
+    fn phase_function(&self, _p: vec3f, wi: vec3f, wo: vec3f) -> f32 {
+        self.phase_fn.probability(wi, wo)
+    }
+    fn sample_phase_function(&self, _p: vec3f, wi: vec3f, u1: f32, u2: f32) -> vec3f {
+        self.phase_fn.sample(wi, u1, u2)
+    }
+
+    fn find_intersections(&self, ray: Ray) -> Vec<(f32, f32)> {
+        self.shape.intersect(Ray { o: ray.o - self.center, d: ray.d })
+    }
+    fn attributes(&self) -> Vec<AttrRef> {
+        let mut attrs = LIGHTING_ATTRIBUTES.to_vec();
+        attrs.push(ATTR_DENSITY);
+        attrs
+    }
+    fn sample_attribute(&self, attr: &AttrRef, world_position: vec3f) -> Option<vec3f> {
+        if *attr == ATTR_DENSITY {
+            scalar_attribute(self.sample_density(world_position))
+        } else {
+            sample_lighting_attribute(self, attr, world_position)
+        }
+    }
+    fn world_bounds(&self) -> AABB {
+        let r = self.shape.half_extents();
+        AABB { min: self.center - r, max: self.center + r }
+    }
+}
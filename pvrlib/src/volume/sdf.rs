@@ -0,0 +1,213 @@
+use super::*;
+use super::constant::ConstantVolumeShape;
+use crate::math::vec3::*;
+use crate::math::ray::*;
+use crate::math::aabb::AABB;
+use crate::phasefn::PhaseFunction;
+
+// Sphere tracing stops refining a surface closer than this (world units).
+const DEFAULT_HIT_TOLERANCE: f32 = 1.0e-3;
+const MAX_TRACE_STEPS: u32 = 512;
+
+// Tree of signed distance functions.
+// Smoothness is the blend radius in world units. 0 gives the hard boolean.
+pub enum SdfNode {
+    Primitive { shape: ConstantVolumeShape, center: vec3f },
+    Union(Box<SdfNode>, Box<SdfNode>, f32),
+    // Carves the second node out of the first.
+    Subtract(Box<SdfNode>, Box<SdfNode>, f32),
+    Intersect(Box<SdfNode>, Box<SdfNode>, f32)
+}
+
+impl SdfNode {
+    pub fn primitive(shape: ConstantVolumeShape, center: vec3f) -> SdfNode {
+        SdfNode::Primitive { shape, center }
+    }
+    pub fn union(a: SdfNode, b: SdfNode, smoothness: f32) -> SdfNode {
+        SdfNode::Union(Box::new(a), Box::new(b), smoothness)
+    }
+    pub fn subtract(a: SdfNode, b: SdfNode, smoothness: f32) -> SdfNode {
+        SdfNode::Subtract(Box::new(a), Box::new(b), smoothness)
+    }
+    pub fn intersect(a: SdfNode, b: SdfNode, smoothness: f32) -> SdfNode {
+        SdfNode::Intersect(Box::new(a), Box::new(b), smoothness)
+    }
+
+    /// Signed distance in world space, negative inside.
+    /// Approximate for smooth operators and ellipsoids.
+    pub fn distance(&self, p: vec3f) -> f32 {
+        match self {
+            SdfNode::Primitive { shape, center } => shape.signed_distance(p - *center),
+            SdfNode::Union(a, b, k) => smooth_min(a.distance(p), b.distance(p), *k),
+            SdfNode::Subtract(a, b, k) => -smooth_min(-a.distance(p), b.distance(p), *k),
+            SdfNode::Intersect(a, b, k) => -smooth_min(-a.distance(p), -b.distance(p), *k)
+        }
+    }
+
+    /// Conservative bounds of the negative region.
+    pub fn bounds(&self) -> AABB {
+        match self {
+            SdfNode::Primitive { shape, center } => {
+                let r = shape.half_extents();
+                AABB { min: *center - r, max: *center + r }
+            },
+            // The blend bulges out by up to k / 4.
+            SdfNode::Union(a, b, k) => {
+                let pad = vec3(1.0, 1.0, 1.0) * (0.25 * k.max(0.0));
+                let bounds = a.bounds().extend(b.bounds());
+                AABB { min: bounds.min - pad, max: bounds.max + pad }
+            },
+            SdfNode::Subtract(a, _, _) => a.bounds(),
+            SdfNode::Intersect(a, b, _) => {
+                let (a, b) = (a.bounds(), b.bounds());
+                let min = vec3f::max(a.min, b.min);
+                let max = vec3f::max(vec3f::min(a.max, b.max), min);
+                AABB { min, max }
+            }
+        }
+    }
+}
+
+// Polynomial smooth minimum from https://iquilezles.org/articles/smin/
+fn smooth_min(a: f32, b: f32, k: f32) -> f32 {
+    if k <= 0.0 {
+        return a.min(b);
+    }
+    let h = (k - (a - b).abs()).max(0.0) / k;
+    a.min(b) - h * h * k * 0.25
+}
+
+// Stylized shapes with soft boundaries, modeled as a tree of SDF primitives.
+// Coefficients are multiplied by the falloff of the signed distance, so density is 0 outside.
+pub struct SdfVolume {
+    pub root: SdfNode,
+    pub falloff: Falloff,
+    pub hit_tolerance: f32,
+
+    pub emission_value: vec3f,
+    pub absorption_coeff: vec3f,
+    pub scattering_coeff: vec3f,
+    pub phase_fn: Box<dyn PhaseFunction>,
+
+    // Cached
+    world_bounds: AABB
+}
+
+impl SdfVolume {
+    pub fn new(
+        root: SdfNode,
+        falloff: Falloff,
+        emission: vec3f,
+        absorption: vec3f,
+        scattering: vec3f,
+        phase_fn: Box<dyn PhaseFunction>) -> SdfVolume
+    {
+        let world_bounds = root.bounds();
+        SdfVolume {
+            root,
+            falloff,
+            hit_tolerance: DEFAULT_HIT_TOLERANCE,
+            emission_value: emission,
+            absorption_coeff: absorption,
+            scattering_coeff: scattering,
+            phase_fn,
+            world_bounds
+        }
+    }
+
+    /// Call after modifying root.
+    pub fn update_bounds(&mut self) {
+        self.world_bounds = self.root.bounds();
+    }
+
+    pub fn sample_density(&self, world_position: vec3f) -> f32 {
+        self.falloff.evaluate(self.root.distance(world_position))
+    }
+}
+
+impl Volume for SdfVolume {
+    fn emission(&self, p: vec3f) -> vec3f {
+        self.emission_value * self.sample_density(p)
+    }
+    fn absorption_coeff(&self, p: vec3f) -> vec3f {
+        self.absorption_coeff * self.sample_density(p)
+    }
+    fn scattering_coeff(&self, p: vec3f) -> vec3f {
+        self.scattering_coeff * self.sample_density(p)
+    }
+    fn sample(&self, world_position: vec3f) -> VolumeSample {
+        let density = self.sample_density(world_position);
+        VolumeSample {
+            emission: self.emission_value * density,
+            absorption_coeff: self.absorption_coeff * density,
+            scattering_coeff: self.scattering_coeff * density
+        }
+    }
+
+    fn set_phase_function(&mut self, phase_fn: Box<dyn PhaseFunction>) {
+        self.phase_fn = phase_fn;
+    }
+    fn phase_function(&self, _p: vec3f, wi: vec3f, wo: vec3f) -> f32 {
+        self.phase_fn.probability(wi, wo)
+    }
+    fn sample_phase_function(&self, _p: vec3f, wi: vec3f, u1: f32, u2: f32) -> vec3f {
+        self.phase_fn.sample(wi, u1, u2)
+    }
+
+    // Sphere trace both in and out of the shape within the bounds.
+    // Intervals are padded by hit_tolerance, so they always cover the surface.
+    fn find_intersections(&self, ray: Ray) -> Vec<(f32, f32)> {
+        let mut intervals = Vec::new();
+        let (t_start, t_end) = match self.world_bounds.intersect(ray) {
+            Some(range) => range,
+            None => return intervals
+        };
+        // t is not in world units if the direction is not normalized.
+        let speed = ray.d.length();
+        if speed <= 0.0 {
+            return intervals;
+        }
+        let tolerance = self.hit_tolerance.max(1.0e-6);
+
+        let mut t = t_start;
+        let mut entry: Option<f32> = None;
+        for _ in 0..MAX_TRACE_STEPS {
+            if t >= t_end {
+                break;
+            }
+            let distance = self.root.distance(ray.at(t));
+            let inside = distance < tolerance;
+            if inside && entry.is_none() {
+                entry = Some(t);
+            } else if !inside {
+                if let Some(t0) = entry.take() {
+                    intervals.push((t0, t));
+                }
+            }
+            t += distance.abs().max(tolerance) / speed;
+        }
+        // Give up refining if out of steps.
+        if t < t_end && entry.is_none() {
+            entry = Some(t);
+        }
+        if let Some(t0) = entry {
+            intervals.push((t0, t_end));
+        }
+        intervals
+    }
+    fn attributes(&self) -> Vec<AttrRef> {
+        let mut attrs = LIGHTING_ATTRIBUTES.to_vec();
+        attrs.push(ATTR_DENSITY);
+        attrs
+    }
+    fn sample_attribute(&self, attr: &AttrRef, world_position: vec3f) -> Option<vec3f> {
+        if *attr == ATTR_DENSITY {
+            scalar_attribute(self.sample_density(world_position))
+        } else {
+            sample_lighting_attribute(self, attr, world_position)
+        }
+    }
+    fn world_bounds(&self) -> AABB {
+        self.world_bounds
+    }
+}
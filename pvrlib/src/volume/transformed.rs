@@ -0,0 +1,97 @@
+use super::*;
+use crate::math::vec3::*;
+use crate::math::ray::*;
+use crate::math::aabb::AABB;
+use crate::math::matrix::Matrix4;
+use crate::phasefn::PhaseFunction;
+
+use std::sync::Arc;
+
+// Places any volume in the world with a local-to-world matrix.
+// The inner volume is shared, so one modeled element can be instanced many times
+// (one TransformedVolume per instance) without duplicating its memory.
+//
+// Coefficients are not rescaled: a volume scaled up by 2 is twice as thick, not half as dense.
+pub struct TransformedVolume {
+    volume: Arc<dyn Volume>,
+    local_to_world: Matrix4,
+    world_to_local: Matrix4,
+    world_bounds: AABB
+}
+
+impl TransformedVolume {
+    /// Panics if `local_to_world` is not invertible.
+    pub fn new(volume: Box<dyn Volume>, local_to_world: Matrix4) -> TransformedVolume {
+        TransformedVolume::from_shared(Arc::from(volume), local_to_world)
+    }
+
+    /// Another instance of a volume that is already wrapped.
+    pub fn from_shared(volume: Arc<dyn Volume>, local_to_world: Matrix4) -> TransformedVolume {
+        let world_to_local = local_to_world.inverse().expect("TransformedVolume: transform is not invertible");
+        let world_bounds = local_to_world.transform_aabb(volume.world_bounds());
+        TransformedVolume { volume, local_to_world, world_to_local, world_bounds }
+    }
+
+    pub fn get_shared_volume(&self) -> Arc<dyn Volume> {
+        self.volume.clone()
+    }
+    pub fn get_transform(&self) -> Matrix4 {
+        self.local_to_world
+    }
+
+    fn to_local(&self, world_position: vec3f) -> vec3f {
+        self.world_to_local.transform_position(world_position)
+    }
+    fn direction_to_local(&self, world_direction: vec3f) -> vec3f {
+        self.world_to_local.transform_direction(world_direction).normalize()
+    }
+}
+
+impl Volume for TransformedVolume {
+    fn emission(&self, p: vec3f) -> vec3f {
+        self.volume.emission(self.to_local(p))
+    }
+    fn absorption_coeff(&self, p: vec3f) -> vec3f {
+        self.volume.absorption_coeff(self.to_local(p))
+    }
+    fn scattering_coeff(&self, p: vec3f) -> vec3f {
+        self.volume.scattering_coeff(self.to_local(p))
+    }
+    fn sample(&self, world_position: vec3f) -> VolumeSample {
+        self.volume.sample(self.to_local(world_position))
+    }
+
+    // #todo-refactor: Shared volumes can't be modified. Set the phase function before wrapping.
+    fn set_phase_function(&mut self, phase_fn: Box<dyn PhaseFunction>) {
+        match Arc::get_mut(&mut self.volume) {
+            Some(volume) => volume.set_phase_function(phase_fn),
+            None => println!("WARNING: set_phase_fn() on a shared TransformedVolume won't do nothing")
+        }
+    }
+    fn phase_function(&self, p: vec3f, wi: vec3f, wo: vec3f) -> f32 {
+        self.volume.phase_function(self.to_local(p), self.direction_to_local(wi), self.direction_to_local(wo))
+    }
+    // #todo-phase: Non-uniform scale distorts directions, so the pdf is only exact for rigid transforms.
+    fn sample_phase_function(&self, p: vec3f, wi: vec3f, u1: f32, u2: f32) -> vec3f {
+        let local_wo = self.volume.sample_phase_function(self.to_local(p), self.direction_to_local(wi), u1, u2);
+        self.local_to_world.transform_direction(local_wo).normalize()
+    }
+
+    fn find_intersections(&self, ray: Ray) -> Vec<(f32, f32)> {
+        let local_ray = Ray {
+            o: self.world_to_local.transform_position(ray.o),
+            d: self.world_to_local.transform_direction(ray.d)
+        };
+        self.volume.find_intersections(local_ray)
+    }
+    fn attributes(&self) -> Vec<AttrRef> {
+        self.volume.attributes()
+    }
+    // #todo-attr: Vector attributes (ex: velocity) are not rotated to world space.
+    fn sample_attribute(&self, attr: &AttrRef, world_position: vec3f) -> Option<vec3f> {
+        self.volume.sample_attribute(attr, self.to_local(world_position))
+    }
+    fn world_bounds(&self) -> AABB {
+        self.world_bounds
+    }
+}
@@ -2,8 +2,12 @@ use super::*;
 use crate::math::vec3::*;
 use crate::math::ray::*;
 use crate::math::aabb::AABB;
+use crate::math::matrix::Matrix4;
 use crate::phasefn::PhaseFunction;
+use super::phasefield::PhaseField;
+use crate::transfer::TransferFunction;
 use crate::voxelbuffer::VoxelBuffer;
+use crate::primitive::RasterizationTarget;
 
 // #wip: Rename to DensityVoxelVolume.
 // Density-based voxel buffer.
@@ -14,67 +18,180 @@ pub struct VoxelVolume {
     pub absorption_coeff: vec3f,
     pub scattering_coeff: vec3f,
     pub phase_fn: Box<dyn PhaseFunction>,
+    // Overrides phase_fn if set. set_phase_function() clears it.
+    pub phase_field: Option<PhaseField>,
+    // Density is used as is if not set.
+    pub transfer: Option<TransferFunction>,
 
-    //pub transform: Transform // #todo-refactor: transform matrix
-    pub world_bounds: AABB
+    // The buffer is mapped to local_bounds, then transformed by local_to_world.
+    local_bounds: AABB,
+    local_to_world: Matrix4,
+    // Cached
+    world_to_local: Matrix4,
+    world_bounds: AABB
 }
 
 impl VoxelVolume {
+    // Identity transform, so local_bounds is also the world bounds.
+    pub fn new(
+        buffer: Box<dyn VoxelBuffer<f32>>,
+        local_bounds: AABB,
+        emission_value: vec3f,
+        absorption_coeff: vec3f,
+        scattering_coeff: vec3f,
+        phase_fn: Box<dyn PhaseFunction>) -> VoxelVolume
+    {
+        VoxelVolume {
+            buffer,
+            emission_value,
+            absorption_coeff,
+            scattering_coeff,
+            phase_fn,
+            phase_field: None,
+            transfer: None,
+            local_bounds,
+            local_to_world: Matrix4::identity(),
+            world_to_local: Matrix4::identity(),
+            world_bounds: local_bounds
+        }
+    }
+
     pub fn get_buffer(&mut self) -> &mut dyn VoxelBuffer<f32> {
         &mut *self.buffer
     }
 
+    /// Panics if `local_to_world` is not invertible.
+    pub fn set_transform(&mut self, local_to_world: Matrix4) {
+        self.local_to_world = local_to_world;
+        self.world_to_local = local_to_world.inverse().expect("VoxelVolume: transform is not invertible");
+        self.world_bounds = local_to_world.transform_aabb(self.local_bounds);
+    }
+    pub fn get_transform(&self) -> Matrix4 {
+        self.local_to_world
+    }
+    pub fn get_local_bounds(&self) -> AABB {
+        self.local_bounds
+    }
+
     /// world position to voxel coord.
     pub fn world_to_voxel(&self, world_position: vec3f) -> vec3f {
-        fit(world_position, self.world_bounds.min, self.world_bounds.max, vec3f::zero(), self.buffer.get_sizef())
+        let p = self.world_to_local.transform_position(world_position);
+        fit(p, self.local_bounds.min, self.local_bounds.max, vec3f::zero(), self.buffer.get_sizef())
     }
     /// voxel coord to world position.
     pub fn voxel_to_world(&self, voxel_coord: vec3f) -> vec3f {
-        fit(voxel_coord, vec3f::zero(), self.buffer.get_sizef(), self.world_bounds.min, self.world_bounds.max)
+        let p = fit(voxel_coord, vec3f::zero(), self.buffer.get_sizef(), self.local_bounds.min, self.local_bounds.max);
+        self.local_to_world.transform_position(p)
     }
     /// world position to local uvw in voxel volume.
     pub fn world_to_local(&self, world_position: vec3f) -> vec3f {
-        fit(world_position,
-            self.world_bounds.min, self.world_bounds.max,
+        fit(self.world_to_local.transform_position(world_position),
+            self.local_bounds.min, self.local_bounds.max,
             vec3f::zero(), vec3f::one())
     }
 
+    /// Rasterization target for a named attribute. Only density can be rasterized.
+    pub fn attribute_target(&mut self, attr: &AttrRef) -> Option<&mut dyn RasterizationTarget> {
+        if *attr == ATTR_DENSITY { Some(self) } else { None }
+    }
+
     pub fn sample_by_world_position(&self, world_position: vec3f) -> f32 {
         let uvw = self.world_to_local(world_position);
         self.buffer.sample_by_local_position(uvw.x, uvw.y, uvw.z)
     }
+    /// Density after the transfer function's density curve.
+    pub fn sample_density(&self, world_position: vec3f) -> f32 {
+        let raw = self.sample_by_world_position(world_position);
+        match &self.transfer {
+            // Splines can undershoot between control points, and negative density would make transmittance grow.
+            Some(transfer) => transfer.density_curve.evaluate(raw).max(0.0),
+            None => raw
+        }
+    }
+    /// Coefficients for a density already read by sample_density().
+    pub fn sample_from_density(&self, world_position: vec3f, density: f32) -> VolumeSample {
+        let (emission_tint, albedo) = match &self.transfer {
+            Some(transfer) => {
+                let x = transfer.ramp_input_at(self.world_to_local(world_position), density);
+                (transfer.emission_tint(x), transfer.albedo(x))
+            },
+            None => (vec3f::one(), vec3f::one())
+        };
+        VolumeSample {
+            emission: self.emission_value * emission_tint * density,
+            absorption_coeff: self.absorption_coeff * density,
+            scattering_coeff: self.scattering_coeff * albedo * density
+        }
+    }
 }
 
 impl Volume for VoxelVolume {
     fn emission(&self, p: vec3f) -> vec3f {
-        self.emission_value * self.sample_by_world_position(p)
+        self.sample(p).emission
     }
     fn absorption_coeff(&self, p: vec3f) -> vec3f {
-        self.absorption_coeff * self.sample_by_world_position(p)
+        self.absorption_coeff * self.sample_density(p)
     }
     fn scattering_coeff(&self, p: vec3f) -> vec3f {
-        self.scattering_coeff * self.sample_by_world_position(p)
+        self.sample(p).scattering_coeff
     }
     fn sample(&self, world_position : vec3f) -> VolumeSample {
-        let density = self.sample_by_world_position(world_position);
-        VolumeSample {
-            emission: self.emission_value * density,
-            absorption_coeff: self.absorption_coeff * density,
-            scattering_coeff: self.scattering_coeff * density
-        }
+        self.sample_from_density(world_position, self.sample_density(world_position))
     }
 
     fn set_phase_function(&mut self, phase_fn: Box<dyn PhaseFunction>) {
         self.phase_fn = phase_fn;
+        self.phase_field = None;
+    }
+    fn phase_function(&self, p: vec3f, wi: vec3f, wo: vec3f) -> f32 {
+        match &self.phase_field {
+            Some(field) => field.probability(self.world_to_local(p), wi, wo),
+            None => self.phase_fn.probability(wi, wo)
+        }
     }
-    fn phase_function(&self, _p: vec3f, wi: vec3f, wo: vec3f) -> f32 {
-        self.phase_fn.probability(wi, wo)
+    fn sample_phase_function(&self, p: vec3f, wi: vec3f, u1: f32, u2: f32) -> vec3f {
+        match &self.phase_field {
+            Some(field) => field.sample(self.world_to_local(p), wi, u1, u2),
+            None => self.phase_fn.sample(wi, u1, u2)
+        }
     }
 
+    // Intersect the oriented box in local space.
     fn find_intersections(&self, ray: Ray) -> Vec<(f32, f32)> {
-        self.buffer.find_intersections(ray, self.world_bounds)
+        let local_ray = Ray {
+            o: self.world_to_local.transform_position(ray.o),
+            d: self.world_to_local.transform_direction(ray.d)
+        };
+        self.buffer.find_intersections(local_ray, self.local_bounds)
+    }
+    fn attributes(&self) -> Vec<AttrRef> {
+        let mut attrs = LIGHTING_ATTRIBUTES.to_vec();
+        attrs.push(ATTR_DENSITY);
+        attrs
+    }
+    fn sample_attribute(&self, attr: &AttrRef, world_position: vec3f) -> Option<vec3f> {
+        if *attr == ATTR_DENSITY {
+            scalar_attribute(self.sample_density(world_position))
+        } else {
+            sample_lighting_attribute(self, attr, world_position)
+        }
     }
     fn world_bounds(&self) -> AABB {
         self.world_bounds
     }
 }
+
+impl RasterizationTarget for VoxelVolume {
+    fn world_to_voxel(&self, world_position: vec3f) -> vec3f {
+        VoxelVolume::world_to_voxel(self, world_position)
+    }
+    fn voxel_to_world(&self, voxel_coord: vec3f) -> vec3f {
+        VoxelVolume::voxel_to_world(self, voxel_coord)
+    }
+    fn world_to_local(&self, world_position: vec3f) -> vec3f {
+        VoxelVolume::world_to_local(self, world_position)
+    }
+    fn get_buffer(&mut self) -> &mut dyn VoxelBuffer<f32> {
+        &mut *self.buffer
+    }
+}
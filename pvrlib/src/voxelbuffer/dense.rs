@@ -34,7 +34,7 @@ impl<T: Copy> DenseField<T> {
 }
 
 impl<T> VoxelBuffer<T> for DenseField<T>
-	where T: Sync + Copy + Add<Output=T> + Mul<f32, Output=T>
+	where T: Send + Sync + Copy + Add<Output=T> + Mul<f32, Output=T>
 {
 	fn sample_by_local_position(&self, u: f32, v: f32, w: f32) -> T {
 		if u < 0.0 || v < 0.0 || w < 0.0 || u >= 1.0 || v >= 1.0 || w >= 1.0 {
@@ -20,7 +20,7 @@ use std::marker::Sync;
 //     scattering_coeff: vec3f  // constant, multiplied by density
 // }
 
-pub trait VoxelBuffer<T> : Sync {
+pub trait VoxelBuffer<T> : Send + Sync {
 	// Sample by uniform coordinates (0.0 <= u, v, w <= 1.0)
 	// Use read() to sample by raw coordinates.
 	fn sample_by_local_position(&self, u: f32, v: f32, w: f32) -> T;
@@ -1,3 +1,10 @@
+use std::ops::*;
+
+use super::VoxelBuffer;
+use crate::math::vec3::*;
+use crate::math::ray::Ray;
+use crate::math::aabb::AABB;
+
 #[allow(non_camel_case_types)]
 type usize3 = (usize, usize, usize);
 
@@ -148,3 +155,63 @@ impl<T: Clone + Copy> SparseField<T> {
     }
 
 }
+
+// Same sampling as DenseField so that the two can be swapped.
+impl<T> VoxelBuffer<T> for SparseField<T>
+    where T: Send + Sync + Copy + Add<Output=T> + Mul<f32, Output=T>
+{
+    fn sample_by_local_position(&self, u: f32, v: f32, w: f32) -> T {
+        if u < 0.0 || v < 0.0 || w < 0.0 || u >= 1.0 || v >= 1.0 || w >= 1.0 {
+            return self.default_value;
+        }
+        let f = vec3(0.5, 0.5, 0.5) + vec3(u, v, w) * self.get_sizef();
+        let a = f - f.floor();
+
+        let read = |vf: vec3f| -> T {
+            let coord = (vf.x as usize, vf.y as usize, vf.z as usize);
+            self.read_safe(coord).unwrap_or(self.default_value)
+        };
+
+        let v000 = read(f);
+        let v001 = read(f + vec3(0.0, 0.0, 1.0));
+        let v010 = read(f + vec3(0.0, 1.0, 0.0));
+        let v011 = read(f + vec3(0.0, 1.0, 1.0));
+        let v100 = read(f + vec3(1.0, 0.0, 0.0));
+        let v101 = read(f + vec3(1.0, 0.0, 1.0));
+        let v110 = read(f + vec3(1.0, 1.0, 0.0));
+        let v111 = read(f + vec3(1.0, 1.0, 1.0));
+
+        let front = lerp(lerp(v000, v100, a.x), lerp(v010, v110, a.x), a.y);
+        let back = lerp(lerp(v001, v101, a.x), lerp(v011, v111, a.x), a.y);
+        lerp(front, back, a.z)
+    }
+
+    fn get_size(&self) -> (i32, i32, i32) {
+        (self.size.0 as i32, self.size.1 as i32, self.size.2 as i32)
+    }
+    fn get_sizef(&self) -> vec3f {
+        vec3(self.size.0 as f32, self.size.1 as f32, self.size.2 as f32)
+    }
+
+    // #todo-emptyspace: Skip unallocated blocks.
+    fn find_intersections(&self, ray: Ray, world_bounds: AABB) -> Vec<(f32, f32)> {
+        world_bounds.intersect(ray).into_iter().collect()
+    }
+
+    fn get_occupancy(&self) -> f32 {
+        SparseField::get_occupancy(self)
+    }
+
+    // Out of range coords are ignored.
+    fn read(&self, i: i32, j: i32, k: i32) -> T {
+        if i < 0 || j < 0 || k < 0 {
+            return self.default_value;
+        }
+        self.read_safe((i as usize, j as usize, k as usize)).unwrap_or(self.default_value)
+    }
+    fn write(&mut self, i: i32, j: i32, k: i32, value: T) {
+        if i >= 0 && j >= 0 && k >= 0 {
+            self.write_safe((i as usize, j as usize, k as usize), value);
+        }
+    }
+}
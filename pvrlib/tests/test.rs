@@ -1,9 +1,46 @@
 use pvrlib::math::vec3::*;
 use pvrlib::math::noise::*;
 use pvrlib::voxelbuffer::sparse::SparseField;
+use pvrlib::voxelbuffer::dense::DenseField;
+use pvrlib::voxelbuffer::VoxelBuffer;
+use pvrlib::primitive::*;
+use pvrlib::primitive::rast::point::Point;
+use pvrlib::math::ray::Ray;
+use pvrlib::math::random::MT19937;
+use pvrlib::light::*;
+use pvrlib::light::emission::create_emission_lights;
+use pvrlib::phasefn::Isotropic;
+use pvrlib::volume::constant::*;
+use pvrlib::volume::frustum::*;
+use pvrlib::volume::voxel::VoxelVolume;
+use pvrlib::volume::transformed::TransformedVolume;
+use pvrlib::volume::instanced::*;
+use pvrlib::volume::phasefield::PhaseField;
+use pvrlib::volume::multifield::*;
+use pvrlib::volume::blackbody::BlackbodyVoxelVolume;
+use pvrlib::transfer::*;
+use pvrlib::volume::procedural::*;
+use pvrlib::volume::modifier::*;
+use pvrlib::volume::sdf::*;
+use pvrlib::volume::composite::CompositeVolume;
+use pvrlib::math::bvh::BVH;
+use pvrlib::phasefn::*;
+use pvrlib::math::aabb::AABB;
+use pvrlib::math::matrix::Matrix4;
+use pvrlib::math::rotator::*;
+use pvrlib::math::transform::Transform;
+use pvrlib::volume::*;
+use pvrlib::spectrum::*;
+use pvrlib::camera::*;
+use pvrlib::stereo::*;
+use pvrlib::animatedcamera::*;
+use pvrlib::camerafile::*;
 use pvrlib::render::rendertarget::RenderTarget;
+use pvrlib::render::raymarcher::*;
+use pvrlib::render::renderer::RenderSettings;
 
 use bit_vec::BitVec;
+use std::sync::Arc;
 
 macro_rules! assert_eq_float {
     ($x: expr, $y: expr) => {
@@ -155,3 +192,1224 @@ fn test_bit_vec() {
     assert_eq!(bvec.get(nbits), None);
     assert_eq!(bvec.get(nbits + 0), None);
 }
+
+#[test]
+fn test_equiangular_sampling() {
+    let fog = ConstantVolume::new(
+        ConstantVolumeShape::Sphere { radius: 5.0 },
+        vec3f::zero(),
+        vec3f::zero(),
+        vec3(0.05, 0.05, 0.05),
+        vec3(0.5, 0.5, 0.5),
+        Box::new(Isotropic{}));
+    let lights: Vec<Box<dyn Light>> = vec![
+        Box::new(PointLight { position: vec3(0.0, 1.5, 0.0), intensity: vec3(100.0, 100.0, 100.0) })
+    ];
+    let ray = Ray::new(vec3(0.0, 0.0, -20.0), vec3(0.0, 0.0, 1.0));
+    let mut rng = MT19937::new(0);
+
+    // Reference: very fine fixed stepping
+    let settings = RenderSettings { primary_step_size: 0.005, secondary_step_size: 0.01, ..Default::default() };
+    let reference = integrate_ray(&fog, ray, &lights, &settings, &mut rng, None).luminance;
+
+    // Coarse stepping, but in-scattering is estimated by equiangular sampling
+    let settings = RenderSettings {
+        primary_step_size: 0.5,
+        secondary_step_size: 0.01,
+        light_sampling: LightSampling::Equiangular,
+        light_sample_count: 256,
+        ..Default::default()
+    };
+    let estimate = integrate_ray(&fog, ray, &lights, &settings, &mut rng, None).luminance;
+
+    let relative_error = (estimate.x - reference.x).abs() / reference.x;
+    assert!(relative_error < 0.05, "reference={:?} estimate={:?}", reference, estimate);
+}
+
+#[test]
+fn test_emission_lights() {
+    let fireball = ConstantVolume::new(
+        ConstantVolumeShape::Sphere { radius: 2.0 },
+        vec3(1.0, 2.0, 3.0),
+        vec3(1.0, 0.5, 0.25),
+        vec3f::zero(),
+        vec3f::zero(),
+        Box::new(Isotropic{}));
+
+    let lights = create_emission_lights(&fireball, (4, 4, 4), 0.0);
+    assert!(!lights.is_empty());
+
+    // Total intensity should match (emission * volume of the sphere).
+    let expected = 4.0 / 3.0 * std::f32::consts::PI * 8.0;
+    let mut total = 0.0;
+    for light in &lights {
+        let p = light.get_position().unwrap();
+        total += light.sample(p, vec3(0.0, 0.0, 1.0)).luminance.x;
+        assert!((p - vec3(1.0, 2.0, 3.0)).length() <= 2.0);
+    }
+    assert!((total - expected).abs() / expected < 0.05, "total={} expected={}", total, expected);
+}
+
+#[test]
+fn test_blackbody() {
+    // Daylight-ish temperature is roughly white
+    let white = blackbody_rgb(6500.0);
+    assert!((white.x - white.z).abs() / white.y < 0.15, "{:?}", white);
+
+    // Flames are reddish and get brighter with temperature
+    let tip = blackbody_rgb(1500.0);
+    let core = blackbody_rgb(2500.0);
+    assert!(tip.x > tip.y && tip.y > tip.z);
+    assert!(core.y > tip.y);
+    assert_eq_float!(xyz_to_linear_srgb(vec3(0.0, 1.0, 0.0)).y, 1.8760108);
+
+    let table = BlackbodyTable::new(3000.0, 10.0);
+    let expected = blackbody_rgb(1500.0);
+    assert!((table.lookup(1500.0) - expected).length() <= 1.0e-3 * expected.length());
+
+    // Uniform density 0.5 at 1400K, shifted to 1500K by the offset
+    let density_volume = VoxelVolume::new(
+        Box::new(DenseField::new((4, 4, 4), 0.5)),
+        AABB { min: vec3(-1.0, -1.0, -1.0), max: vec3(1.0, 1.0, 1.0) },
+        vec3(0.1, 0.2, 0.3), vec3(1.0, 1.0, 1.0), vec3(0.5, 0.5, 0.5), Box::new(Isotropic{}));
+    let fire = BlackbodyVoxelVolume::new(density_volume, Box::new(DenseField::new((4, 4, 4), 1400.0)), 2.0, 100.0);
+    let p = vec3(0.1, -0.2, 0.3);
+    let expected = vec3(0.1, 0.2, 0.3) * 0.5 + blackbody_rgb(1500.0) * (2.0 * 0.5);
+    let samp = fire.sample(p);
+    assert!((samp.emission - expected).length() <= 1.0e-3 * expected.length(), "{:?} {:?}", samp.emission, expected);
+    assert!((fire.emission(p) - samp.emission).length() < 1e-6);
+    assert_eq_float!(samp.absorption_coeff.x, 0.5);
+    assert_eq_float!(fire.sample_attribute(&ATTR_TEMPERATURE, p).unwrap().x, 1500.0);
+}
+
+#[test]
+fn test_spectral_upsampling() {
+    // Upsample to spectra, then integrate back to RGB.
+    let colors = [vec3(1.0, 1.0, 1.0), vec3(0.8, 0.2, 0.1), vec3(0.1, 0.6, 0.3), vec3(0.2, 0.3, 0.9)];
+    let num_samples = 512;
+    for rgb in colors.iter() {
+        let mut result = vec3f::zero();
+        for i in 0..num_samples {
+            let wavelengths = sample_wavelengths((i as f32 + 0.5) / (num_samples as f32));
+            let spectral = rgb_to_spectral(*rgb, wavelengths);
+            result += spectral_to_linear_srgb(spectral, wavelengths);
+        }
+        result /= num_samples as f32;
+        assert!((result - *rgb).length() < 0.1, "rgb={:?} result={:?}", rgb, result);
+    }
+}
+
+#[test]
+fn test_thin_lens() {
+    let mut camera = Camera::new(vec3(0.0, 0.0, 10.0), vec3f::zero(), vec3(0.0, 1.0, 0.0), 45.0, 1.0);
+    camera.set_lens(Some(ThinLens {
+        aperture_radius: 0.5,
+        focus_distance: 10.0,
+        blade_count: 6,
+        blade_rotation: 15.0
+    }));
+
+    // Every ray through the lens should converge on the focus plane.
+    let mut rng = MT19937::new(1);
+    let center = camera.get_ray(0.3, 0.7).unwrap();
+    let focus_point = center.o + center.d * (10.0 / -center.d.z);
+    for _ in 0..32 {
+        let ray = camera.get_ray_through_lens(0.3, 0.7, rng.rand() as f32, rng.rand() as f32).unwrap();
+        let p = ray.o + ray.d * (ray.o.z / -ray.d.z);
+        assert!((p - focus_point).length() < 1e-3);
+        assert!((ray.o - center.o).length() <= 0.5 + 1e-4);
+    }
+}
+
+#[test]
+fn test_camera_projections() {
+    let position = vec3(1.0, 2.0, 3.0);
+    let forward = vec3(0.0, 0.0, -1.0);
+    let up = vec3(0.0, 1.0, 0.0);
+    let camera_with = |projection: Box<dyn CameraProjection>| {
+        Camera::with_projection(position, position + forward, up, projection)
+    };
+
+    // The image center looks forward in every projection.
+    let cameras = vec![
+        camera_with(Box::new(PerspectiveProjection { fov_y: 45.0, aspect_ratio: 2.0 })),
+        camera_with(Box::new(OrthographicProjection { width: 20.0, height: 10.0 })),
+        camera_with(Box::new(EquirectangularProjection)),
+        camera_with(Box::new(FisheyeProjection { fov: 180.0, aspect_ratio: 1.0 })),
+    ];
+    for camera in &cameras {
+        let ray = camera.get_ray(0.5, 0.5).unwrap();
+        assert!((ray.d - forward).length() < 1e-5);
+    }
+
+    // Orthographic rays are parallel and offset on the image plane.
+    let ray = cameras[1].get_ray(1.0, 0.0).unwrap();
+    assert!((ray.o - (position + vec3(10.0, 5.0, 0.0))).length() < 1e-5);
+    assert!((ray.d - forward).length() < 1e-5);
+
+    // Equirectangular: left edge looks backward, top edge looks up.
+    assert!((cameras[2].get_ray(0.0, 0.5).unwrap().d - vec3(0.0, 0.0, 1.0)).length() < 1e-5);
+    assert!((cameras[2].get_ray(0.5, 0.0).unwrap().d - up).length() < 1e-5);
+
+    // 180 degree fisheye: rim of the image circle is perpendicular to the view direction.
+    assert!((cameras[3].get_ray(1.0, 0.5).unwrap().d - vec3(1.0, 0.0, 0.0)).length() < 1e-5);
+    assert!(cameras[3].get_ray(0.0, 0.0).is_none());
+}
+
+#[test]
+fn test_stereo_rig() {
+    let mut rig = StereoRig {
+        position: vec3(0.0, 0.0, 10.0),
+        look_at: vec3f::zero(),
+        up: vec3(0.0, 1.0, 0.0),
+        fov_y: 45.0,
+        aspect_ratio: 1.0,
+        interaxial: 0.5,
+        zero_parallax_distance: 10.0,
+        mode: StereoMode::Parallel
+    };
+
+    // Both eyes see the same point at zero parallax distance (on the plane z = 0).
+    for mode in [StereoMode::Parallel, StereoMode::ToeIn] {
+        rig.mode = mode;
+        let (left, right) = (rig.left_camera(), rig.right_camera());
+        let l = left.get_ray(0.5, 0.5).unwrap();
+        let r = right.get_ray(0.5, 0.5).unwrap();
+        assert!(l.o.x < 0.0 && r.o.x > 0.0);
+        let pl = l.o + l.d * (l.o.z / -l.d.z);
+        let pr = r.o + r.d * (r.o.z / -r.d.z);
+        assert!((pl - pr).length() < 1e-4);
+        // Parallel rig has no vertical parallax.
+        if mode == StereoMode::Parallel {
+            let l = left.get_ray(0.9, 0.1).unwrap();
+            let r = right.get_ray(0.9, 0.1).unwrap();
+            assert!((l.d.y / l.d.z - r.d.y / r.d.z).abs() < 1e-4);
+        }
+    }
+
+    // Omni-directional stereo: eyes are on opposite sides of the rig center, perpendicular to the view.
+    rig.mode = StereoMode::OmniDirectional;
+    let l = rig.left_camera().get_ray(0.75, 0.5).unwrap();
+    let r = rig.right_camera().get_ray(0.75, 0.5).unwrap();
+    assert!((l.o + r.o - rig.position * 2.0).length() < 1e-4);
+    assert!(((l.o - r.o).length() - rig.interaxial).abs() < 1e-4);
+    assert!(((l.o - r.o) & l.d).abs() < 0.05);
+}
+
+#[test]
+fn test_animated_camera() {
+    let key = |time: f32, position: vec3f, rotation: CameraRotation, fov_y: f32| {
+        CameraKeyframe { time, position, rotation, fov_y }
+    };
+    let keyframes = vec![
+        key(10.0, vec3(10.0, 0.0, 0.0), CameraRotation::LookAt(vec3(10.0, 0.0, -1.0)), 60.0),
+        key(0.0, vec3f::zero(), CameraRotation::Euler(vec3f::zero()), 40.0),
+        key(20.0, vec3(10.0, 10.0, 0.0), CameraRotation::Euler(vec3(0.0, 90.0, 0.0)), 40.0),
+    ];
+    let sample_at = |time: f32| CameraSample { s: 0.5, t: 0.5, lens_s: 0.5, lens_t: 0.5, time };
+
+    for interpolation in [CameraInterpolation::Linear, CameraInterpolation::CatmullRom] {
+        let camera = AnimatedCamera::new(keyframes.clone(), interpolation, 1.0);
+
+        // Keyframes are hit exactly and times out of range are clamped.
+        let ray = camera.generate_ray(&sample_at(-5.0)).unwrap();
+        assert!(ray.o.length() < 1e-5);
+        assert!((ray.d - vec3(0.0, 0.0, -1.0)).length() < 1e-5);
+        let ray = camera.generate_ray(&sample_at(10.0)).unwrap();
+        assert!((ray.o - vec3(10.0, 0.0, 0.0)).length() < 1e-4);
+        assert!((ray.d - vec3(0.0, 0.0, -1.0)).length() < 1e-4);
+        // Yaw of 90 degrees turns the camera to the left (-x).
+        let ray = camera.generate_ray(&sample_at(25.0)).unwrap();
+        assert!((ray.d - vec3(-1.0, 0.0, 0.0)).length() < 1e-4);
+
+        let ray = camera.generate_ray(&sample_at(5.0)).unwrap();
+        assert!(ray.o.x > 0.0 && ray.o.x < 10.0);
+    }
+
+    let camera = AnimatedCamera::new(keyframes, CameraInterpolation::Linear, 1.0);
+    let ray = camera.generate_ray(&sample_at(5.0)).unwrap();
+    assert!((ray.o - vec3(5.0, 0.0, 0.0)).length() < 1e-4);
+}
+
+#[test]
+fn test_camera_file() {
+    let chan = "\
+        # frame tx ty tz rx ry rz vfov
+        1 0 0 50 0 0 0 45
+        2\t1.5 0 50 -10 20 0 40
+        3 3 0 50 0 45 5
+    ";
+    let keyframes = parse_chan(chan).unwrap();
+    assert_eq!(keyframes.len(), 3);
+    assert_eq_float!(keyframes[1].position.x, 1.5);
+    assert_eq_float!(keyframes[1].fov_y, 40.0);
+    assert!(parse_chan("1 2 3").is_err());
+    assert!(parse_chan("1 0 0 0 0 0 x").is_err());
+    assert!(parse_chan("nan 0 0 0 0 0 0").is_err());
+    assert!(parse_chan("0 inf 0 0 0 0 0").is_err());
+
+    // Round trip through both formats
+    let mut camera = AnimatedCamera::new(keyframes, CameraInterpolation::CatmullRom, 1.5);
+    camera.lens = Some(ThinLens { aperture_radius: 0.25, focus_distance: 50.0, blade_count: 6, blade_rotation: 0.0 });
+    let from_chan = parse_chan(&format_chan(camera.get_keyframes())).unwrap();
+    let from_json = parse_json_camera(&format_json_camera(&camera)).unwrap();
+    assert_eq!(from_json.interpolation, CameraInterpolation::CatmullRom);
+    assert_eq_float!(from_json.aspect_ratio, 1.5);
+    assert_eq_float!(from_json.lens.unwrap().aperture_radius, 0.25);
+    let keys = camera.get_keyframes().iter().zip(&from_chan).zip(from_json.get_keyframes());
+    for (i, ((key, chan_key), json_key)) in keys.enumerate() {
+        let sample = CameraSample { s: 0.2, t: 0.7, lens_s: 0.5, lens_t: 0.5, time: 1.0 + 0.75 * (i as f32) };
+        let ray = camera.generate_ray(&sample).unwrap();
+        assert_eq_float!(chan_key.time, key.time);
+        assert_eq_float!(json_key.time, key.time);
+        let other = from_json.generate_ray(&sample).unwrap();
+        assert!((ray.o - other.o).length() < 1e-4 && (ray.d - other.d).length() < 1e-4);
+    }
+
+    let json = r#"{ "aspect_ratio": 1, "interpolation": "linear", "keyframes": [
+        { "time": 0, "position": [0, 0, 10], "look_at": [0, 0, 0], "fov_y": 30 } ] }"#;
+    let camera = parse_json_camera(json).unwrap();
+    assert!(matches!(camera.get_keyframes()[0].rotation, CameraRotation::LookAt(_)));
+    assert!(parse_json_camera("{ \"aspect_ratio\": 1 }").is_err());
+    assert!(parse_json_camera(&json.replace("\"time\": 0", "\"time\": 1e39")).is_err());
+    // Any JSON escape is accepted, and malformed numbers are not.
+    let escaped = json.replace("\"aspect_ratio\"", "\"name\": \"cam\\u00e9ra\\n\\t\\/\\\"1\\\"\", \"aspect_ratio\"");
+    assert!(parse_json_camera(&escaped).is_ok());
+    assert!(parse_json_camera(&json.replace("\"time\": 0", "\"time\": 1e")).is_err());
+    assert!(parse_json_camera(&json.replace("\"time\": 0", "\"time\": +-1")).is_err());
+
+    // Static cameras, with roll
+    let mut camera = Camera::new(vec3(3.0, 4.0, 20.0), vec3(-1.0, 2.0, 0.0), vec3(0.3, 1.0, 0.0).normalize(), 40.0, 1.5);
+    camera.set_lens(Some(ThinLens { aperture_radius: 0.5, focus_distance: 20.0, blade_count: 0, blade_rotation: 0.0 }));
+    let dir = std::env::temp_dir();
+    let chan_path = dir.join("pvrlib_test_camera.chan");
+    let json_path = dir.join("pvrlib_test_camera.json");
+    write_chan_camera(chan_path.to_str().unwrap(), &camera, 40.0).unwrap();
+    write_json_static_camera(json_path.to_str().unwrap(), &camera, 40.0, 1.5).unwrap();
+    let from_chan = read_chan_camera(chan_path.to_str().unwrap(), 0.0, 1.5).unwrap();
+    let from_json = read_json_static_camera(json_path.to_str().unwrap(), 0.0).unwrap();
+    assert!(from_chan.get_lens().is_none());
+    assert_eq_float!(from_json.get_lens().unwrap().aperture_radius, 0.5);
+    for (s, t) in [(0.5, 0.5), (0.1, 0.2), (0.9, 0.7)] {
+        let ray = camera.get_ray_through_lens(s, t, 0.3, 0.8).unwrap();
+        let chan_ray = from_chan.get_ray(s, t).unwrap();
+        let json_ray = from_json.get_ray_through_lens(s, t, 0.3, 0.8).unwrap();
+        assert!((camera.get_ray(s, t).unwrap().d - chan_ray.d).length() < 1e-4);
+        assert!((ray.o - json_ray.o).length() < 1e-4 && (ray.d - json_ray.d).length() < 1e-4);
+    }
+    let _ = std::fs::remove_file(chan_path);
+    let _ = std::fs::remove_file(json_path);
+}
+
+#[test]
+fn test_frustum_volume() {
+    for sparse in [false, true] {
+        for depth_mapping in [FrustumDepth::Linear, FrustumDepth::Exponential] {
+            let buffer: Box<dyn VoxelBuffer<f32>> = if sparse {
+                Box::new(SparseField::new((64, 64, 64), 0.0))
+            } else {
+                Box::new(DenseField::new((64, 64, 64), 0.0))
+            };
+            let camera = Camera::new(vec3(0.0, 0.0, 50.0), vec3f::zero(), vec3(0.0, 1.0, 0.0), 45.0, 1.0);
+            let mut volume = FrustumVolume::new(buffer, camera, 1.0, 100.0, depth_mapping, Box::new(Isotropic{}));
+
+            // Voxel coords round trip
+            let p = vec3(3.0, -2.0, 10.0);
+            let voxel = volume.world_to_voxel(p);
+            assert!((volume.voxel_to_world(voxel) - p).length() < 1e-3);
+            // Screen space x and y, with +y up
+            let uvw = volume.world_to_local(vec3(0.0, 0.0, 0.0));
+            assert!((uvw.x - 0.5).abs() < 1e-5 && (uvw.y - 0.5).abs() < 1e-5);
+            assert!(volume.world_to_local(vec3(0.0, 5.0, 0.0)).y > 0.5);
+
+            let point = Point { center: vec3(0.0, 0.0, 0.0), radius: 5.0 };
+            point.rasterize(&mut volume);
+            assert!(volume.sample_by_world_position(vec3(0.0, 0.0, 0.0)) > 0.9);
+            assert_eq_float!(volume.sample_by_world_position(vec3(0.0, 0.0, 20.0)), 0.0);
+            assert_eq_float!(volume.sample_by_world_position(vec3(0.0, 0.0, 60.0)), 0.0);
+
+            let bounds = volume.world_bounds();
+            assert!(bounds.min.z < -49.0 && bounds.max.z > 48.9);
+
+            // Straddles the camera plane
+            let point = Point { center: vec3(1.0, 0.0, 50.0), radius: 8.0 };
+            point.rasterize(&mut volume);
+            assert!(volume.sample_by_world_position(vec3(0.0, 0.0, 45.0)) > 0.9);
+            assert!(volume.sample_by_world_position(vec3(0.3, 0.3, 48.0)) > 0.9);
+        }
+    }
+}
+
+#[test]
+fn test_matrix() {
+    let transform = Transform {
+        translation: vec3(1.0, 2.0, 3.0),
+        rotation: rotator(30.0, -20.0, 45.0),
+        scale: vec3(2.0, 0.5, 3.0)
+    };
+    let m: Matrix4 = transform.into();
+    let inv = m.inverse().unwrap();
+    let p = vec3(-4.0, 5.0, 0.5);
+    assert!((inv.transform_position(m.transform_position(p)) - p).length() < 1e-4);
+    assert!(((m * inv).transform_position(p) - p).length() < 1e-4);
+    assert!(Matrix4::scale(vec3(1.0, 0.0, 1.0)).inverse().is_none());
+    assert!(Matrix4::scale(vec3(1.0, f32::NAN, 1.0)).inverse().is_none());
+
+    // Same rotation as Euler keyframes of cameras (rx = pitch, ry = yaw, rz = roll)
+    let v = vec3(0.3, -0.2, 0.9);
+    let r = rotator(30.0, -20.0, 45.0);
+    assert!((r.rotate_vector(v) - rotate_zxy(v, vec3(-20.0, 30.0, 45.0))).length() < 1e-5);
+}
+
+#[test]
+fn test_voxel_volume_transform() {
+    // Density 1 only in the half of x > 0 in local space.
+    let mut buffer = DenseField::new((8, 8, 8), 0.0);
+    for x in 4..8 {
+        for y in 0..8 {
+            for z in 0..8 {
+                buffer.write(x, y, z, 1.0);
+            }
+        }
+    }
+    let local_bounds = AABB { min: vec3(-1.0, -1.0, -1.0), max: vec3(1.0, 1.0, 1.0) };
+    let mut volume = VoxelVolume::new(
+        Box::new(buffer), local_bounds, vec3f::zero(), vec3f::one(), vec3f::one(), Box::new(Isotropic{}));
+
+    // Stretch along local x, then turn local +x to world -z.
+    let transform = Transform {
+        translation: vec3(10.0, 0.0, 0.0),
+        rotation: rotator(90.0, 0.0, 0.0),
+        scale: vec3(4.0, 1.0, 1.0)
+    };
+    volume.set_transform(transform.to_matrix());
+
+    let bounds = volume.world_bounds();
+    assert!((bounds.min - vec3(9.0, -1.0, -4.0)).length() < 1e-4);
+    assert!((bounds.max - vec3(11.0, 1.0, 4.0)).length() < 1e-4);
+
+    assert!(volume.sample_by_world_position(vec3(10.0, 0.0, -2.0)) > 0.99);
+    assert!(volume.sample_by_world_position(vec3(10.0, 0.0, 2.0)) < 0.01);
+    let voxel = volume.world_to_voxel(vec3(10.5, 0.25, -2.0));
+    assert!((volume.voxel_to_world(voxel) - vec3(10.5, 0.25, -2.0)).length() < 1e-4);
+
+    // Oriented box intersection keeps world space t.
+    let ray = Ray::new(vec3(10.0, 0.0, 10.0), vec3(0.0, 0.0, -1.0));
+    let intervals = volume.find_intersections(ray);
+    assert_eq!(intervals.len(), 1);
+    assert!((intervals[0].0 - 6.0).abs() < 1e-4 && (intervals[0].1 - 14.0).abs() < 1e-4);
+    let diagonal = Ray::new(vec3(0.0, 0.0, -5.0), vec3(1.0, 0.0, 1.0).normalize());
+    assert!(volume.find_intersections(diagonal).is_empty());
+}
+
+#[test]
+fn test_transformed_volume() {
+    // Unit box at the origin, stretched to 4 x 1 x 1 and moved to x = 10.
+    let cube = ConstantVolume::new(
+        ConstantVolumeShape::cube(0.5), vec3f::zero(),
+        vec3f::zero(), vec3f::one(), vec3f::one(), Box::new(HenyeyGreenstein { g: 0.8 }));
+    let transform = Transform {
+        translation: vec3(10.0, 0.0, 0.0),
+        rotation: rotator(0.0, 0.0, 90.0),
+        scale: vec3(1.0, 4.0, 1.0)
+    };
+    let volume = TransformedVolume::new(Box::new(cube), transform.to_matrix());
+
+    // Local +y is world -x after the roll.
+    let bounds = volume.world_bounds();
+    assert!((bounds.min - vec3(8.0, -0.5, -0.5)).length() < 1e-4);
+    assert!((bounds.max - vec3(12.0, 0.5, 0.5)).length() < 1e-4);
+    assert_eq_float!(volume.absorption_coeff(vec3(11.5, 0.0, 0.0)).x, 1.0);
+    assert_eq_float!(volume.absorption_coeff(vec3(10.0, 1.0, 0.0)).x, 0.0);
+
+    let ray = Ray::new(vec3(0.0, 0.0, 0.0), vec3(1.0, 0.0, 0.0));
+    let intervals = volume.find_intersections(ray);
+    assert!((intervals[0].0 - 8.0).abs() < 1e-4 && (intervals[0].1 - 12.0).abs() < 1e-4);
+
+    // Phase function directions are rotated into local space.
+    let hg = HenyeyGreenstein { g: 0.8 };
+    let (wi, wo) = (vec3(1.0, 0.0, 0.0), vec3(0.0, 1.0, 0.0));
+    let expected = hg.probability(vec3(0.0, -1.0, 0.0), vec3(1.0, 0.0, 0.0));
+    assert!((volume.phase_function(vec3(10.0, 0.0, 0.0), wi, wo) - expected).abs() < 1e-5);
+
+    // Instances share the inner volume.
+    let instance = TransformedVolume::from_shared(volume.get_shared_volume(), Matrix4::translation(vec3(0.0, 5.0, 0.0)));
+    assert_eq_float!(instance.absorption_coeff(vec3(0.0, 5.0, 0.0)).x, 1.0);
+}
+
+#[test]
+fn test_bvh() {
+    let mut rng = MT19937::new(7);
+    let mut rand_vec = |scale: f32| vec3(rng.rand() as f32, rng.rand() as f32, rng.rand() as f32) * scale;
+    let bounds: Vec<AABB> = (0..500).map(|_| {
+        let min = rand_vec(100.0);
+        AABB { min, max: min + rand_vec(5.0) }
+    }).collect();
+    let bvh = BVH::new(&bounds);
+
+    for _ in 0..50 {
+        let p = rand_vec(100.0);
+        let mut found = Vec::new();
+        bvh.query_point(p, |i| found.push(i));
+        found.sort();
+        let expected: Vec<usize> = (0..bounds.len()).filter(|&i| bounds[i].contains(p)).collect();
+        assert_eq!(found, expected);
+
+        let ray = Ray::new(p, (rand_vec(2.0) - vec3(1.0, 1.0, 1.0)).normalize());
+        let mut found = Vec::new();
+        bvh.query_ray(ray, |i| found.push(i));
+        found.sort();
+        let expected: Vec<usize> = (0..bounds.len()).filter(|&i| bounds[i].intersect(ray).is_some()).collect();
+        assert_eq!(found, expected);
+    }
+}
+
+#[test]
+fn test_instanced_volume() {
+    let puff: Arc<dyn Volume> = Arc::new(ConstantVolume::new(
+        ConstantVolumeShape::Sphere { radius: 1.0 }, vec3f::zero(),
+        vec3(1.0, 1.0, 1.0), vec3(0.5, 0.5, 0.5), vec3(0.25, 0.25, 0.25), Box::new(Isotropic{})));
+
+    // 10 x 10 x 10 grid of puffs
+    let mut instances = Vec::new();
+    for i in 0..1000 {
+        let position = vec3((i % 10) as f32, ((i / 10) % 10) as f32, (i / 100) as f32) * 4.0;
+        let mut instance = VolumeInstance::new(Matrix4::translation(position));
+        instance.density = 2.0;
+        instance.emission_tint = vec3(1.0, 0.5, 0.0);
+        instances.push(instance);
+    }
+    let volume = InstancedVolume::new(puff.clone(), &instances);
+    assert_eq!(volume.instance_count(), 1000);
+
+    let bounds = volume.world_bounds();
+    assert!((bounds.min - vec3(-1.0, -1.0, -1.0)).length() < 1e-4);
+    assert!((bounds.max - vec3(37.0, 37.0, 37.0)).length() < 1e-4);
+
+    let samp = volume.sample(vec3(8.0, 12.5, 36.0));
+    assert_eq_float!(samp.absorption_coeff.x, 1.0);
+    assert_eq_float!(samp.emission.y, 1.0);
+    assert_eq_float!(samp.emission.z, 0.0);
+    assert_eq_float!(volume.scattering_coeff(vec3(10.0, 10.0, 10.0)).x, 0.0);
+
+    // A ray along a row of puffs hits 10 of them.
+    let ray = Ray::new(vec3(-10.0, 4.0, 8.0), vec3(1.0, 0.0, 0.0));
+    assert_eq!(volume.find_intersections(ray).len(), 10);
+}
+
+#[test]
+fn test_composite_volume() {
+    let mut rng = MT19937::new(3);
+    let mut children: Vec<Box<dyn Volume>> = Vec::new();
+    for _ in 0..2000 {
+        let center = vec3(rng.rand() as f32, rng.rand() as f32, rng.rand() as f32) * 200.0;
+        let shape = if rng.rand() < 0.5 { ConstantVolumeShape::cube(2.0) } else { ConstantVolumeShape::Sphere { radius: 2.0 } };
+        children.push(Box::new(ConstantVolume::new(
+            shape, center, vec3(0.1, 0.2, 0.3), vec3(1.0, 1.0, 1.0), vec3(0.5, 0.5, 0.5), Box::new(Isotropic{}))));
+    }
+    let volume = CompositeVolume::new(children);
+    let children = volume.get_children();
+
+    for _ in 0..200 {
+        let p = vec3(rng.rand() as f32, rng.rand() as f32, rng.rand() as f32) * 200.0;
+        let expected = children.iter().fold(vec3f::zero(), |acc, child| acc + child.absorption_coeff(p));
+        assert!((volume.absorption_coeff(p) - expected).length() < 1e-4);
+        assert!((volume.sample(p).emission - children.iter().fold(vec3f::zero(), |acc, child| acc + child.emission(p))).length() < 1e-4);
+
+        let ray = Ray::new(p, (vec3(rng.rand() as f32, rng.rand() as f32, rng.rand() as f32) - vec3(0.5, 0.5, 0.5)).normalize());
+        // Children entirely behind the ray origin are culled.
+        let expected: Vec<(f32, f32)> = children.iter()
+            .flat_map(|child| child.find_intersections(ray))
+            .filter(|&(_, t_end)| t_end >= 0.0)
+            .collect();
+        assert_eq!(volume.find_intersections(ray), merge_intervals(expected));
+    }
+
+    let empty = CompositeVolume::new(Vec::new());
+    assert_eq!(empty.world_bounds().max, vec3f::zero());
+    assert_eq!(empty.absorption_coeff(vec3f::zero()), vec3f::zero());
+}
+
+#[test]
+fn test_volume_segments() {
+    assert_eq!(merge_intervals(vec![(5.0, 6.0), (0.0, 2.0), (1.0, 3.0), (3.0, 4.0)]), vec![(0.0, 4.0), (5.0, 6.0)]);
+
+    let unit_box = |x: f32| -> Box<dyn Volume> {
+        Box::new(ConstantVolume::new(
+            ConstantVolumeShape::cube(1.0), vec3(x, 0.0, 0.0),
+            vec3f::zero(), vec3(1.0, 1.0, 1.0), vec3f::zero(), Box::new(Isotropic{})))
+    };
+    // Boxes over x in [-1, 1], [0, 2] and [5, 7]
+    let volume = CompositeVolume::new(vec![unit_box(0.0), unit_box(1.0), unit_box(6.0)]);
+    let ray = Ray::new(vec3(-10.0, 0.0, 0.0), vec3(1.0, 0.0, 0.0));
+
+    let segments = volume.find_segments(ray);
+    let expected = [
+        VolumeSegment { t_min: 9.0, t_max: 10.0, volumes: vec![0] },
+        VolumeSegment { t_min: 10.0, t_max: 11.0, volumes: vec![0, 1] },
+        VolumeSegment { t_min: 11.0, t_max: 12.0, volumes: vec![1] },
+        VolumeSegment { t_min: 15.0, t_max: 17.0, volumes: vec![2] }
+    ];
+    assert_eq!(segments.len(), expected.len());
+    for (segment, expected) in segments.iter().zip(expected.iter()) {
+        assert_eq_float!(segment.t_min, expected.t_min);
+        assert_eq_float!(segment.t_max, expected.t_max);
+        assert_eq!(segment.volumes, expected.volumes);
+    }
+    assert_eq!(volume.find_intersections(ray).len(), 2);
+
+    let overlap = &segments[1];
+    assert_eq_float!(volume.sample_segment(ray.at(10.5), overlap).absorption_coeff.x, 2.0);
+    assert_eq_float!(volume.sample_segment(ray.at(10.5), &segments[0]).absorption_coeff.x, 1.0);
+
+    // Steps are cut at segment boundaries, so constant segments are integrated exactly.
+    let pair = CompositeVolume::new(vec![unit_box(0.0), unit_box(1.0)]);
+    let mut rng = MT19937::new(0);
+    for step in [0.75, 0.4, 0.01] {
+        let settings = RenderSettings { primary_step_size: step, ..Default::default() };
+        let transmittance = integrate_ray(&pair, ray, &[], &settings, &mut rng, None).transmittance;
+        assert!((transmittance.x - (-4.0_f32).exp()).abs() < 1e-4, "step={} T={:?}", step, transmittance);
+    }
+    assert_eq!(merge_intervals(vec![(f32::NAN, 1.0), (0.0, 2.0)]).len(), 2);
+
+    // Volumes without children get merged intervals.
+    let single = unit_box(0.0);
+    let segments = single.find_segments(ray);
+    assert_eq!(segments.len(), 1);
+    assert!(segments[0].volumes.is_empty());
+}
+
+// Integral of the phase function over the sphere of outgoing directions
+fn integrate_phase<F: Fn(vec3f) -> f32>(phase: F) -> f32 {
+    let n = 200;
+    let mut total = 0.0;
+    for i in 0..n {
+        let cos_theta = 1.0 - 2.0 * (i as f32 + 0.5) / n as f32;
+        let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+        for j in 0..n {
+            let phi = 2.0 * std::f32::consts::PI * (j as f32 + 0.5) / n as f32;
+            let wo = vec3(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta);
+            total += phase(wo);
+        }
+    }
+    total * 4.0 * std::f32::consts::PI / (n * n) as f32
+}
+
+#[test]
+fn test_composite_phase_function() {
+    let fog = ConstantVolume::new(
+        ConstantVolumeShape::cube(2.0), vec3f::zero(),
+        vec3f::zero(), vec3f::zero(), vec3(3.0, 3.0, 3.0), Box::new(HenyeyGreenstein { g: 0.6 }));
+    let smoke = ConstantVolume::new(
+        ConstantVolumeShape::cube(2.0), vec3(1.0, 0.0, 0.0),
+        vec3f::zero(), vec3f::zero(), vec3(1.0, 1.0, 1.0), Box::new(Isotropic{}));
+    let volume = CompositeVolume::new(vec![Box::new(fog), Box::new(smoke)]);
+
+    let wi = vec3(0.0, 0.0, 1.0);
+    let p = vec3(0.5, 0.0, 0.0); // inside both
+    assert!((integrate_phase(|wo| volume.phase_function(p, wi, wo)) - 1.0).abs() < 0.01);
+    let hg = HenyeyGreenstein { g: 0.6 };
+    assert_eq_float!(volume.phase_function(p, wi, wi), 0.75 * hg.probability(wi, wi) + 0.25 * ISOTROPIC_PHASE_FN);
+
+    // Mean cosine of sampled directions follows the blended lobes: 0.75 * 0.6 + 0.25 * 0.0
+    let mut rng = MT19937::new(5);
+    let num_samples = 20000;
+    let mut mean_cos = 0.0;
+    for _ in 0..num_samples {
+        let wo = volume.sample_phase_function(p, wi, rng.rand() as f32, rng.rand() as f32);
+        assert!((wo.length() - 1.0).abs() < 1e-3);
+        mean_cos += (wi & wo) / num_samples as f32;
+    }
+    assert!((mean_cos - 0.45).abs() < 0.02);
+
+    // Only the smoke scatters here.
+    let p = vec3(2.5, 0.0, 0.0);
+    assert_eq_float!(volume.phase_function(p, wi, wi), ISOTROPIC_PHASE_FN);
+}
+
+#[test]
+fn test_phase_function_sampling() {
+    // Mie-like lobe with a strong forward peak and a small backward glory
+    let mie_csv = "angle,value\n0,800\n2,300\n5,60\n10,15\n30,2\n90,0.3\n150,0.2\n170,0.6\n180,1.0\n";
+    let phase_fns: Vec<Box<dyn PhaseFunction>> = vec![
+        Box::new(Isotropic{}),
+        Box::new(HenyeyGreenstein { g: 0.8 }),
+        Box::new(HenyeyGreenstein { g: -0.3 }),
+        Box::new(DoubleHenyeyGreenstein { g1: 0.7, g2: -0.5, b: 0.4 }),
+        Box::new(Rayleigh{}),
+        Box::new(CornetteShanks::new(0.76)),
+        Box::new(Schlick::from_hg(0.7)),
+        Box::new(Schlick { k: -0.4 }),
+        Box::new(Draine::new(0.5, 0.8)),
+        Box::new(TabulatedPhaseFunction::parse_csv(mie_csv).unwrap())
+    ];
+    let wi = vec3(1.0, 2.0, -0.5).normalize();
+    let mut rng = MT19937::new(11);
+    for phase_fn in phase_fns {
+        assert!((integrate_phase(|wo| phase_fn.probability(wi, wo)) - 1.0).abs() < 0.01);
+
+        let expected_mean_cos = integrate_phase(|wo| (wi & wo) * phase_fn.probability(wi, wo));
+        let num_samples = 20000;
+        let mut mean_cos = 0.0;
+        for _ in 0..num_samples {
+            let wo = phase_fn.sample(wi, rng.rand() as f32, rng.rand() as f32);
+            assert!((wo.length() - 1.0).abs() < 1e-3);
+            mean_cos += (wi & wo) / num_samples as f32;
+        }
+        assert!((mean_cos - expected_mean_cos).abs() < 0.02);
+    }
+
+    // Draine with alpha = 1 is Cornette-Shanks.
+    let wo = vec3(0.3, -0.2, 0.9).normalize();
+    assert_eq_float!(Draine::new(0.6, 1.0).probability(wi, wo), cornette_shanks_phase(0.6, wi & wo));
+    assert_eq_float!(Rayleigh{}.probability(wi, wi), 2.0 * Rayleigh{}.probability(wi, (wi ^ vec3(0.0, 0.0, 1.0)).normalize()));
+}
+
+#[test]
+fn test_tabulated_phase_function() {
+    let table = TabulatedPhaseFunction::parse_csv("# constant\n0, 1\n180, 1\n").unwrap();
+    let wi = vec3(0.0, 0.0, 1.0);
+    assert_eq_float!(table.probability(wi, vec3(1.0, 0.0, 0.0)), ISOTROPIC_PHASE_FN);
+
+    // Linear in cos(theta) between samples, normalized over the sphere
+    let table = TabulatedPhaseFunction::new(&[(0.0, 3.0), (90.0, 2.0), (180.0, 1.0)]).unwrap();
+    assert_eq_float!(table.probability(wi, wi), 3.0 * ISOTROPIC_PHASE_FN / 2.0);
+    assert_eq_float!(table.probability(wi, -wi), ISOTROPIC_PHASE_FN / 2.0);
+
+    assert!(TabulatedPhaseFunction::parse_csv("0,1\n90,x\n").is_err());
+    assert!(TabulatedPhaseFunction::parse_csv("0,1,2\n180,1,2\n").is_err());
+    assert!(TabulatedPhaseFunction::parse_csv("0,1\n").is_err());
+    assert!(TabulatedPhaseFunction::new(&[(0.0, 0.0), (180.0, 0.0)]).is_err());
+    assert!(TabulatedPhaseFunction::new(&[(0.0, 1.0), (200.0, 1.0)]).is_err());
+    assert!(TabulatedPhaseFunction::read_csv("no_such_phase_function.csv").is_err());
+}
+
+#[test]
+fn test_phase_field() {
+    // Forward scattering edge (x < 0), back scattering core (x > 0)
+    let half_field = |left: f32, right: f32| -> Box<dyn VoxelBuffer<f32>> {
+        let mut field = DenseField::new((4, 4, 4), 0.0);
+        for x in 0..4 {
+            for y in 0..4 {
+                for z in 0..4 {
+                    field.write(x, y, z, if x < 2 { left } else { right });
+                }
+            }
+        }
+        Box::new(field)
+    };
+    let local_bounds = AABB { min: vec3(-1.0, -1.0, -1.0), max: vec3(1.0, 1.0, 1.0) };
+    let mut volume = VoxelVolume::new(
+        Box::new(DenseField::new((8, 8, 8), 1.0)), local_bounds,
+        vec3f::zero(), vec3f::one(), vec3f::one(), Box::new(Isotropic{}));
+    volume.phase_field = Some(PhaseField::HenyeyGreenstein { g: half_field(0.8, -0.3) });
+
+    let wi = vec3(0.0, 1.0, 0.0);
+    let wo = vec3(0.6, 0.8, 0.0);
+    // Exactly at voxel x = 1 and x = 3 of the fields
+    let edge = vec3(-0.75, 0.0, 0.0);
+    let core = vec3(0.25, 0.0, 0.0);
+    assert_eq_float!(volume.phase_function(edge, wi, wo), HenyeyGreenstein { g: 0.8 }.probability(wi, wo));
+    assert_eq_float!(volume.phase_function(core, wi, wo), HenyeyGreenstein { g: -0.3 }.probability(wi, wo));
+
+    let mut rng = MT19937::new(13);
+    let mean_cos = |p: vec3f, rng: &mut MT19937| {
+        let n = 10000;
+        (0..n).map(|_| wi & volume.sample_phase_function(p, wi, rng.rand() as f32, rng.rand() as f32)).sum::<f32>() / n as f32
+    };
+    assert!((mean_cos(edge, &mut rng) - 0.8).abs() < 0.02);
+    assert!((mean_cos(core, &mut rng) + 0.3).abs() < 0.02);
+
+    // Out of range values are clamped.
+    volume.phase_field = Some(PhaseField::DoubleHenyeyGreenstein {
+        g1: half_field(2.0, 0.5), g2: half_field(-0.5, -0.5), b: half_field(1.5, 0.25)
+    });
+    assert_eq_float!(volume.phase_function(edge, wi, wo), HenyeyGreenstein { g: 0.99 }.probability(wi, wo));
+    let dhg = DoubleHenyeyGreenstein { g1: 0.5, g2: -0.5, b: 0.25 };
+    assert_eq_float!(volume.phase_function(core, wi, wo), dhg.probability(wi, wo));
+
+    volume.phase_field = None;
+    assert_eq_float!(volume.phase_function(core, wi, wo), ISOTROPIC_PHASE_FN);
+
+    // A new phase function replaces the field.
+    volume.phase_field = Some(PhaseField::HenyeyGreenstein { g: half_field(0.8, -0.3) });
+    volume.set_phase_function(Box::new(HenyeyGreenstein { g: 0.5 }));
+    assert!(volume.phase_field.is_none());
+    assert_eq_float!(volume.phase_function(core, wi, wo), HenyeyGreenstein { g: 0.5 }.probability(wi, wo));
+}
+
+#[test]
+fn test_multi_field_volume() {
+    let local_bounds = AABB { min: vec3(-10.0, -10.0, -10.0), max: vec3(10.0, 10.0, 10.0) };
+    let mut volume = MultiFieldVolume::new(
+        Box::new(DenseField::new((20, 20, 20), vec3f::zero())),
+        Box::new(SparseField::new((40, 40, 40), vec3f::zero())),
+        Box::new(DenseField::new((20, 20, 20), vec3f::zero())),
+        local_bounds,
+        Box::new(Isotropic{}));
+    volume.add_scalar_field("temperature", Box::new(DenseField::new((10, 10, 10), 0.0)));
+
+    // Red emissive core inside a bluish smoke
+    let core = Point { center: vec3(0.0, 0.0, 0.0), radius: 3.0 };
+    let smoke = Point { center: vec3(0.0, 0.0, 0.0), radius: 8.0 };
+    core.rasterize(&mut volume.color_target(ColorAttribute::Emission, vec3(2.0, 0.0, 0.0)));
+    smoke.rasterize(&mut volume.color_target(ColorAttribute::Absorption, vec3(0.1, 0.2, 0.5)));
+    smoke.rasterize(&mut volume.color_target(ColorAttribute::Scattering, vec3(0.5, 0.5, 0.5)));
+    core.rasterize(&mut volume.scalar_target("temperature").unwrap());
+    assert!(volume.scalar_target("fuel").is_none());
+
+    let center = vec3(0.5, 0.5, 0.5);
+    let samp = volume.sample(center);
+    assert!((samp.emission - vec3(2.0, 0.0, 0.0)).length() < 1e-4);
+    assert!((samp.absorption_coeff - vec3(0.1, 0.2, 0.5)).length() < 1e-4);
+    assert!((samp.scattering_coeff - vec3(0.5, 0.5, 0.5)).length() < 1e-4);
+    // Exactly at a voxel of the coarser temperature field
+    assert_eq_float!(volume.sample_scalar("temperature", vec3(-1.0, -1.0, -1.0)).unwrap(), 1.0);
+    assert!(volume.sample_scalar("fuel", center).is_none());
+
+    // Smoke only
+    let edge = vec3(6.5, 0.5, 0.5);
+    assert!(volume.emission(edge).length() < 1e-4);
+    assert!((volume.absorption_coeff(edge) - vec3(0.1, 0.2, 0.5)).length() < 1e-4);
+    assert_eq_float!(volume.sample_scalar("temperature", edge).unwrap(), 0.0);
+
+    volume.set_transform(Matrix4::translation(vec3(100.0, 0.0, 0.0)));
+    assert!((volume.emission(center + vec3(100.0, 0.0, 0.0)) - vec3(2.0, 0.0, 0.0)).length() < 1e-4);
+    let ray = Ray::new(vec3(100.0, 0.0, -50.0), vec3(0.0, 0.0, 1.0));
+    let intervals = volume.find_intersections(ray);
+    assert_eq!(intervals.len(), 1);
+    assert!((intervals[0].0 - 40.0).abs() < 1e-3 && (intervals[0].1 - 60.0).abs() < 1e-3);
+
+    // Blue glow around the red core, in the same field. Rasterizing red again keeps the blue.
+    let offset = vec3(100.0, 0.0, 0.0);
+    let glow = Point { center: offset, radius: 8.0 };
+    glow.rasterize(&mut volume.color_target(ColorAttribute::Emission, vec3(0.0, 0.0, 1.0)));
+    Point { center: offset, radius: 3.0 }.rasterize(&mut volume.color_target(ColorAttribute::Emission, vec3(2.0, 0.0, 0.0)));
+    assert!((volume.emission(center + offset) - vec3(2.0, 0.0, 1.0)).length() < 1e-4);
+    assert!((volume.emission(edge + offset) - vec3(0.0, 0.0, 1.0)).length() < 1e-4);
+}
+
+#[test]
+fn test_volume_attributes() {
+    assert_eq!(AttrRef::named("density"), ATTR_DENSITY);
+    assert_eq!(ATTR_VELOCITY.name(), "velocity");
+
+    let local_bounds = AABB { min: vec3(-1.0, -1.0, -1.0), max: vec3(1.0, 1.0, 1.0) };
+    let mut smoke = VoxelVolume::new(
+        Box::new(DenseField::new((8, 8, 8), 0.0)), local_bounds,
+        vec3f::zero(), vec3(2.0, 2.0, 2.0), vec3f::one(), Box::new(Isotropic{}));
+    Point { center: vec3f::zero(), radius: 5.0 }.rasterize(smoke.attribute_target(&ATTR_DENSITY).unwrap());
+    assert!(smoke.attribute_target(&ATTR_TEMPERATURE).is_none());
+
+    let p = vec3(0.1, 0.2, 0.3);
+    assert_eq_float!(smoke.sample_attribute(&ATTR_DENSITY, p).unwrap().y, 1.0);
+    assert_eq_float!(smoke.sample_attribute(&ATTR_ABSORPTION, p).unwrap().z, 2.0);
+    assert!(smoke.sample_attribute(&ATTR_VELOCITY, p).is_none());
+
+    let fire = BlackbodyVoxelVolume::new(
+        VoxelVolume::new(Box::new(DenseField::new((4, 4, 4), 1.0)), local_bounds,
+            vec3f::zero(), vec3f::one(), vec3f::zero(), Box::new(Isotropic{})),
+        Box::new(DenseField::new((4, 4, 4), 1000.0)), 1.0, 500.0);
+    assert_eq_float!(fire.sample_attribute(&ATTR_TEMPERATURE, p).unwrap().x, 1500.0);
+    assert!((fire.sample_attribute(&ATTR_EMISSION, p).unwrap() - fire.emission(p)).length() < 1e-4);
+
+    let mut fuel = MultiFieldVolume::new(
+        Box::new(DenseField::new((8, 8, 8), vec3f::zero())),
+        Box::new(DenseField::new((8, 8, 8), vec3f::zero())),
+        Box::new(DenseField::new((8, 8, 8), vec3f::zero())),
+        local_bounds, Box::new(Isotropic{}));
+    fuel.add_scalar_field("fuel", Box::new(DenseField::new((8, 8, 8), 0.0)));
+    let fuel_attr = AttrRef::named("fuel");
+    let blob = Point { center: vec3f::zero(), radius: 5.0 };
+    blob.rasterize(&mut fuel.attribute_target(&fuel_attr, vec3f::one()).unwrap());
+    blob.rasterize(&mut fuel.attribute_target(&ATTR_ABSORPTION, vec3(0.5, 0.5, 0.5)).unwrap());
+    assert!(fuel.attribute_target(&ATTR_VELOCITY, vec3f::one()).is_none());
+    assert_eq_float!(fuel.sample_attribute(&fuel_attr, p).unwrap().x, 1.0);
+    assert_eq_float!(fuel.sample_attribute(&ATTR_ABSORPTION, p).unwrap().x, 0.5);
+
+    // Composite has the union of attributes and sums the overlapping children.
+    let composite = CompositeVolume::new(vec![Box::new(smoke), Box::new(fire), Box::new(fuel)]);
+    let attrs = composite.attributes();
+    for attr in [ATTR_EMISSION, ATTR_ABSORPTION, ATTR_SCATTERING, ATTR_DENSITY, ATTR_TEMPERATURE, fuel_attr.clone()] {
+        assert!(attrs.contains(&attr));
+    }
+    assert_eq!(attrs.len(), 6);
+    assert_eq_float!(composite.sample_attribute(&ATTR_DENSITY, p).unwrap().x, 2.0);
+    assert_eq_float!(composite.sample_attribute(&ATTR_ABSORPTION, p).unwrap().x, 3.5);
+    assert_eq_float!(composite.sample_attribute(&fuel_attr, p).unwrap().x, 1.0);
+    assert_eq_float!(composite.sample_attribute(&ATTR_DENSITY, vec3(5.0, 0.0, 0.0)).unwrap().x, 0.0);
+    assert!(composite.sample_attribute(&ATTR_VELOCITY, p).is_none());
+
+    // Temperature AOV through an opaque box converges to the temperature itself.
+    let ray = Ray::new(vec3(0.0, 0.0, -10.0), vec3(0.0, 0.0, 1.0));
+    let aov = integrate_attribute(&composite, ray, &ATTR_TEMPERATURE, 0.01).unwrap();
+    assert!(aov.x > 0.95 * 1500.0 && aov.x <= 1500.0);
+    assert!(integrate_attribute(&composite, ray, &ATTR_VELOCITY, 0.01).is_none());
+
+    // Instances sum like the lighting properties, and instance density scales density.
+    let puff = VoxelVolume::new(
+        Box::new(DenseField::new((8, 8, 8), 1.0)), local_bounds,
+        vec3f::zero(), vec3f::one(), vec3f::zero(), Box::new(Isotropic{}));
+    let mut instances = [VolumeInstance::new(Matrix4::translation(vec3(-0.5, 0.0, 0.0))), VolumeInstance::new(Matrix4::translation(vec3(0.5, 0.0, 0.0)))];
+    instances[1].density = 0.5;
+    let instanced = InstancedVolume::new(Arc::new(puff), &instances);
+    assert!(instanced.attributes().contains(&ATTR_DENSITY));
+    assert_eq_float!(instanced.sample_attribute(&ATTR_DENSITY, p).unwrap().x, 1.5);
+    assert_eq_float!(instanced.sample_attribute(&ATTR_DENSITY, vec3(-1.2, 0.0, 0.0)).unwrap().x, 1.0);
+    assert_eq_float!(instanced.sample_attribute(&ATTR_ABSORPTION, p).unwrap().x, 1.5);
+    assert!(instanced.sample_attribute(&ATTR_TEMPERATURE, p).is_none());
+
+    // The last step is cut at the segment end, as in integrate_ray().
+    let ray = Ray::new(vec3(1.2, 0.0, -10.0), vec3(0.0, 0.0, 1.0));
+    let coarse = integrate_attribute(&instanced, ray, &ATTR_DENSITY, 0.75).unwrap();
+    let fine = integrate_attribute(&instanced, ray, &ATTR_DENSITY, 0.01).unwrap();
+    assert!((coarse.x - fine.x).abs() < 1e-3, "coarse={} fine={}", coarse.x, fine.x);
+}
+
+#[test]
+fn test_transfer_function() {
+    let points = vec![(0.0, 0.0), (0.3, 0.0), (0.5, 2.0), (1.0, 2.5)];
+    let linear = Curve::new(points.clone(), CurveInterpolation::Linear);
+    let spline = Curve::new(points.clone(), CurveInterpolation::CatmullRom);
+    for &(x, y) in &points {
+        assert_eq_float!(linear.evaluate(x), y);
+        assert_eq_float!(spline.evaluate(x), y);
+    }
+    assert_eq_float!(linear.evaluate(0.4), 1.0);
+    assert_eq_float!(linear.evaluate(-1.0), 0.0);
+    assert_eq_float!(linear.evaluate(2.0), 2.5);
+    assert!(spline.evaluate(0.75) > 2.0 && spline.evaluate(0.75) < 2.5);
+    assert_eq_float!(Curve::identity().evaluate(0.7), 0.7);
+    // NaN control points sort last instead of panicking.
+    let with_nan = Curve::new(vec![(f32::NAN, 1.0), (1.0, 2.0), (0.0, 0.0)], CurveInterpolation::Linear);
+    assert_eq_float!(with_nan.get_points()[0].0, 0.0);
+    assert!(with_nan.get_points()[2].0.is_nan());
+    assert!(ColorRamp::new(vec![(f32::NAN, vec3f::one()), (0.0, vec3f::zero())]).get_stops()[1].0.is_nan());
+
+    let ramp = ColorRamp::new(vec![(1.0, vec3(1.0, 1.0, 0.0)), (0.0, vec3(1.0, 0.0, 0.0))]);
+    assert!((ramp.evaluate(0.5) - vec3(1.0, 0.5, 0.0)).length() < 1e-5);
+    assert!((ramp.evaluate(3.0) - vec3(1.0, 1.0, 0.0)).length() < 1e-5);
+
+    // CT-like dataset: raw values 0.2 (tissue) in x < 0, 0.8 (bone) in x > 0
+    let mut buffer = DenseField::new((4, 4, 4), 0.0);
+    let mut heat = DenseField::new((4, 4, 4), 0.0);
+    for x in 0..4 {
+        for y in 0..4 {
+            for z in 0..4 {
+                buffer.write(x, y, z, if x < 2 { 0.2 } else { 0.8 });
+                heat.write(x, y, z, if x < 2 { 0.0 } else { 1.0 });
+            }
+        }
+    }
+    let local_bounds = AABB { min: vec3(-1.0, -1.0, -1.0), max: vec3(1.0, 1.0, 1.0) };
+    let mut volume = VoxelVolume::new(
+        Box::new(buffer), local_bounds, vec3(1.0, 1.0, 1.0), vec3(1.0, 1.0, 1.0), vec3(1.0, 1.0, 1.0), Box::new(Isotropic{}));
+    // Exactly at voxel x = 1 and x = 3
+    let tissue = vec3(-0.75, 0.0, 0.0);
+    let bone = vec3(0.25, 0.0, 0.0);
+    assert_eq_float!(volume.absorption_coeff(bone).x, 0.8);
+
+    // Hide tissue and boost bone.
+    let mut transfer = TransferFunction::new(Curve::new(vec![(0.3, 0.0), (0.7, 4.0)], CurveInterpolation::Linear));
+    transfer.emission_ramp = Some(ramp);
+    transfer.albedo_ramp = Some(ColorRamp::constant(vec3(0.5, 0.5, 0.5)));
+    volume.transfer = Some(transfer);
+    assert_eq_float!(volume.absorption_coeff(tissue).x, 0.0);
+    let samp = volume.sample(bone);
+    assert_eq_float!(samp.absorption_coeff.x, 4.0);
+    assert!((samp.emission - vec3(4.0, 4.0, 0.0)).length() < 1e-4);
+    assert!((samp.scattering_coeff - vec3(2.0, 2.0, 2.0)).length() < 1e-4);
+    assert_eq_float!(volume.sample_attribute(&ATTR_DENSITY, bone).unwrap().x, 4.0);
+
+    // Catmull-Rom undershoots below zero before (0.5, 2.0), but density stays non-negative.
+    assert!(spline.evaluate(0.2) < 0.0);
+    volume.transfer.as_mut().unwrap().density_curve = spline;
+    assert_eq_float!(volume.sample_density(tissue), 0.0);
+    assert!(volume.absorption_coeff(tissue).x >= 0.0);
+
+    // Color by another field
+    volume.transfer.as_mut().unwrap().ramp_input = RampInput::Field(Box::new(heat));
+    volume.transfer.as_mut().unwrap().density_curve = Curve::identity();
+    let samp = volume.sample(bone);
+    assert!((samp.emission - vec3(0.8, 0.8, 0.0)).length() < 1e-4);
+    assert!((volume.emission(tissue) - vec3(0.2, 0.0, 0.0)).length() < 1e-4);
+}
+
+#[test]
+fn test_constant_volume_shapes() {
+    let shapes = [
+        ConstantVolumeShape::Box { half_size: vec3(1.0, 2.0, 0.5) },
+        ConstantVolumeShape::Sphere { radius: 1.5 },
+        ConstantVolumeShape::Ellipsoid { radii: vec3(2.0, 1.0, 0.5) },
+        ConstantVolumeShape::Cylinder { radius: 1.0, half_height: 2.0 },
+        ConstantVolumeShape::Cone { radius: 1.5, height: 3.0 },
+        ConstantVolumeShape::Capsule { radius: 0.5, half_height: 1.0 },
+        ConstantVolumeShape::Torus { major_radius: 2.0, minor_radius: 0.5 }
+    ];
+    let center = vec3(1.0, -2.0, 3.0);
+    let mut rng = MT19937::new(11);
+    let mut rand_vec = || vec3(rng.rand() as f32, rng.rand() as f32, rng.rand() as f32) - vec3(0.5, 0.5, 0.5);
+
+    for shape in shapes.iter() {
+        let volume = ConstantVolume::new(*shape, center, vec3f::zero(), vec3f::one(), vec3f::zero(), Box::new(Isotropic{}));
+        let bounds = volume.world_bounds();
+        let mut num_hits = 0;
+        for _ in 0..300 {
+            let ray = Ray::new(center + rand_vec() * 10.0, rand_vec().normalize());
+            let intervals = volume.find_intersections(ray);
+            num_hits += intervals.len();
+            for w in intervals.windows(2) {
+                assert!(w[0].1 <= w[1].0);
+            }
+            // Compare against the sign of the SDF along the ray.
+            for i in 0..200 {
+                let t = -10.0 + 0.1 * i as f32;
+                let p = ray.o + t * ray.d;
+                let distance = shape.signed_distance(p - center);
+                let inside = intervals.iter().any(|&(t0, t1)| t0 <= t && t <= t1);
+                if distance.abs() > 1e-3 {
+                    assert_eq!(inside, distance < 0.0, "{:?} t={} distance={}", shape, t, distance);
+                }
+                if inside {
+                    assert!(bounds.contains(p) || distance.abs() <= 1e-3);
+                }
+            }
+        }
+        assert!(num_hits > 0, "{:?}", shape);
+    }
+
+    // Two walls of the ring
+    let torus = ConstantVolumeShape::Torus { major_radius: 2.0, minor_radius: 0.5 };
+    let intervals = torus.intersect(Ray::new(vec3(-5.0, 0.0, 0.0), vec3(1.0, 0.0, 0.0)));
+    assert_eq!(intervals.len(), 2);
+    for (a, b) in intervals.iter().zip(&[(2.5, 3.5), (6.5, 7.5)]) {
+        assert!((a.0 - b.0).abs() < 1e-4 && (a.1 - b.1).abs() < 1e-4, "{:?}", intervals);
+    }
+
+    // Density ramps from 0 at the surface to 1 at the falloff width inside.
+    let mut sphere = ConstantVolume::new(
+        ConstantVolumeShape::Sphere { radius: 2.0 }, vec3f::zero(),
+        vec3f::zero(), vec3f::one(), vec3f::zero(), Box::new(Isotropic{}));
+    assert_eq_float!(sphere.absorption_coeff(vec3(1.9, 0.0, 0.0)).x, 1.0);
+    sphere.set_falloff(Falloff::Smooth(1.0));
+    assert_eq!(sphere.get_falloff(), Falloff::Smooth(1.0));
+    assert_eq_float!(sphere.absorption_coeff(vec3(0.5, 0.0, 0.0)).x, 1.0);
+    assert_eq_float!(sphere.absorption_coeff(vec3(1.5, 0.0, 0.0)).x, 0.5);
+    assert!(sphere.absorption_coeff(vec3(1.9, 0.0, 0.0)).x < 0.05);
+    assert_eq_float!(sphere.absorption_coeff(vec3(2.1, 0.0, 0.0)).x, 0.0);
+    sphere.set_falloff(Falloff::Linear(1.0));
+    assert_eq_float!(sphere.absorption_coeff(vec3(1.75, 0.0, 0.0)).x, 0.25);
+}
+
+#[test]
+fn test_procedural_volume() {
+    let noise_settings = NoiseSettings { octaves: 6, frequency: 0.5, offset: vec3(3.0, 0.0, 0.0), threshold: 0.3, amplitude: 2.0, ..Default::default() };
+    let center = vec3(10.0, 0.0, 0.0);
+    let mut volume = ProceduralVolume::new(ConstantVolumeShape::Sphere { radius: 4.0 }, center, noise_settings, Box::new(Isotropic{}));
+    volume.absorption_coeff = vec3(1.0, 1.0, 1.0);
+
+    let mut rng = MT19937::new(17);
+    let mut num_empty = 0;
+    for _ in 0..1000 {
+        let p = center + vec3(rng.rand() as f32 - 0.5, rng.rand() as f32 - 0.5, rng.rand() as f32 - 0.5) * 5.0;
+        let expected = if (p - center).length() <= 4.0 {
+            let n = fBm_octaves((p - center) * 0.5 + vec3(3.0, 0.0, 0.0), 6, 0.5, 2.0);
+            (n - 0.3).max(0.0) * 2.0
+        } else {
+            0.0
+        };
+        assert_eq_float!(volume.sample_density(p), expected);
+        assert_eq_float!(volume.absorption_coeff(p).y, expected);
+        if expected == 0.0 {
+            num_empty += 1;
+        }
+    }
+    // The threshold carves out empty space.
+    assert!(num_empty > 0 && num_empty < 1000);
+
+    // The default settings are the same as fBm().
+    let p = vec3(0.3, 1.7, -2.2);
+    assert_eq_float!(fBm(p), fBm_octaves(p, 5, 0.5, 2.0));
+
+    volume.noise.threshold = 0.0;
+    volume.falloff = Falloff::Linear(2.0);
+    let near_edge = center + vec3(0.0, 3.0, 0.0); // Halfway into the falloff
+    let n = fBm_octaves((near_edge - center) * 0.5 + vec3(3.0, 0.0, 0.0), 6, 0.5, 2.0);
+    assert_eq_float!(volume.sample_density(near_edge), n * 2.0 * 0.5);
+    volume.falloff = Falloff::Smooth(2.0);
+    assert_eq_float!(volume.sample_density(near_edge), n * 2.0 * 0.5);
+    assert!(volume.sample_density(center + vec3(0.0, 1.0, 0.0)) > 0.0);
+
+    let ray = Ray::new(vec3(10.0, 0.0, -20.0), vec3(0.0, 0.0, 1.0));
+    let intervals = volume.find_intersections(ray);
+    assert_eq!(intervals.len(), 1);
+    assert!((intervals[0].0 - 16.0).abs() < 1e-3 && (intervals[0].1 - 24.0).abs() < 1e-3);
+
+    let cube = ProceduralVolume::new(ConstantVolumeShape::cube(4.0), center, NoiseSettings::default(), Box::new(Isotropic{}));
+    assert!(cube.sample_density(center + vec3(3.9, 3.9, 3.9)) > 0.0);
+    assert_eq_float!(cube.sample_density(center + vec3(4.1, 0.0, 0.0)), 0.0);
+    assert_eq!(cube.find_intersections(ray).len(), 1);
+}
+
+#[test]
+fn test_sdf_volume() {
+    let sphere = |x: f32, radius: f32| SdfNode::primitive(ConstantVolumeShape::Sphere { radius }, vec3(x, 0.0, 0.0));
+
+    // Hard union of two spheres over x in [-3, -1] and [1, 3]
+    let pair = SdfNode::union(sphere(-2.0, 1.0), sphere(2.0, 1.0), 0.0);
+    assert_eq_float!(pair.distance(vec3f::zero()), 1.0);
+    // The smooth union fills the gap between them.
+    let blob = SdfNode::union(sphere(-2.0, 1.0), sphere(2.0, 1.0), 6.0);
+    assert!(blob.distance(vec3f::zero()) < 0.0);
+    assert!(blob.bounds().contains(vec3(3.9, 0.0, 0.0)));
+    // Carve a hole through a box
+    let frame = SdfNode::subtract(
+        SdfNode::primitive(ConstantVolumeShape::cube(2.0), vec3f::zero()),
+        SdfNode::primitive(ConstantVolumeShape::Capsule { radius: 1.0, half_height: 5.0 }, vec3f::zero()), 0.0);
+    assert!(frame.distance(vec3f::zero()) > 0.0);
+    assert!(frame.distance(vec3(1.5, 0.0, 0.0)) < 0.0);
+    let lens = SdfNode::intersect(sphere(-1.0, 2.0), sphere(1.0, 2.0), 0.0);
+    assert!(lens.distance(vec3f::zero()) < 0.0 && lens.distance(vec3(-1.5, 0.0, 0.0)) > 0.0);
+    assert_eq_float!(lens.bounds().min.x, -1.0);
+
+    let mut volume = SdfVolume::new(pair, Falloff::Hard, vec3f::zero(), vec3f::one(), vec3f::zero(), Box::new(Isotropic{}));
+    let ray = Ray::new(vec3(-10.0, 0.0, 0.0), vec3(1.0, 0.0, 0.0));
+    let intervals = volume.find_intersections(ray);
+    assert_eq!(intervals.len(), 2);
+    for (a, b) in intervals.iter().zip(&[(7.0, 9.0), (11.0, 13.0)]) {
+        // Covers the surface within the tolerance
+        assert!(a.0 <= b.0 && b.0 - a.0 < 2e-3 && a.1 >= b.1 && a.1 - b.1 < 2e-3, "{:?}", intervals);
+    }
+    // Unnormalized directions keep t
+    let slow_ray = Ray { o: ray.o, d: ray.d * 0.5 };
+    assert!((volume.find_intersections(slow_ray)[1].0 - 22.0).abs() < 4e-3);
+    assert!(volume.find_intersections(Ray::new(vec3(-10.0, 5.0, 0.0), vec3(1.0, 0.0, 0.0))).is_empty());
+
+    // Every point with density is inside an interval.
+    let mut rng = MT19937::new(23);
+    volume.root = SdfNode::union(frame, SdfNode::primitive(ConstantVolumeShape::Torus { major_radius: 3.0, minor_radius: 0.5 }, vec3f::zero()), 0.5);
+    volume.update_bounds();
+    for _ in 0..200 {
+        let dir = vec3(rng.rand() as f32 - 0.5, rng.rand() as f32 - 0.5, rng.rand() as f32 - 0.5).normalize();
+        let ray = Ray::new(dir * -8.0, dir);
+        let intervals = volume.find_intersections(ray);
+        for i in 0..320 {
+            let t = 0.05 * i as f32;
+            if volume.sample_density(ray.at(t)) > 0.0 {
+                assert!(intervals.iter().any(|&(t0, t1)| t0 <= t && t <= t1), "t={} {:?}", t, intervals);
+            }
+        }
+    }
+
+    // Density falls off over the signed distance.
+    volume.root = sphere(0.0, 2.0);
+    volume.update_bounds();
+    volume.falloff = Falloff::Linear(1.0);
+    assert_eq_float!(volume.absorption_coeff(vec3(1.5, 0.0, 0.0)).x, 0.5);
+    assert_eq_float!(volume.absorption_coeff(vec3(0.5, 0.0, 0.0)).x, 1.0);
+    assert_eq_float!(volume.sample_attribute(&ATTR_DENSITY, vec3(2.5, 0.0, 0.0)).unwrap().x, 0.0);
+    assert_eq_float!(volume.world_bounds().max.x, 2.0);
+}
+
+#[test]
+fn test_volume_modifiers() {
+    assert_eq!(intersect_intervals(&[(0.0, 2.0), (3.0, 6.0)], &[(1.0, 4.0), (5.0, 7.0)]), vec![(1.0, 2.0), (3.0, 4.0), (5.0, 6.0)]);
+
+    let fog = |center: vec3f, radius: f32, absorption: f32| -> Box<dyn Volume> {
+        Box::new(ConstantVolume::new(
+            ConstantVolumeShape::cube(radius), center,
+            vec3(0.1, 0.1, 0.1), vec3(absorption, absorption, absorption), vec3(0.5, 0.5, 0.5), Box::new(Isotropic{})))
+    };
+    let ray = Ray::new(vec3(-20.0, 0.0, 0.0), vec3(1.0, 0.0, 0.0));
+    let assert_intervals = |intervals: Vec<(f32, f32)>, expected: &[(f32, f32)]| {
+        assert_eq!(intervals.len(), expected.len());
+        for (a, b) in intervals.iter().zip(expected) {
+            assert!((a.0 - b.0).abs() < 1e-4 && (a.1 - b.1).abs() < 1e-4);
+        }
+    };
+
+    let scaled = ScaledVolume::new(fog(vec3f::zero(), 10.0, 1.0), 0.5);
+    assert_eq_float!(scaled.absorption_coeff(vec3f::zero()).x, 0.5);
+    assert_eq_float!(scaled.sample_attribute(&ATTR_SCATTERING, vec3f::zero()).unwrap().x, 0.25);
+    let scaled_voxels = ScaledVolume::new(Box::new(VoxelVolume::new(
+        Box::new(DenseField::new((4, 4, 4), 2.0)), AABB { min: vec3(-1.0, -1.0, -1.0), max: vec3(1.0, 1.0, 1.0) },
+        vec3f::zero(), vec3f::one(), vec3f::zero(), Box::new(Isotropic{}))), 0.5);
+    assert_eq_float!(scaled_voxels.sample_attribute(&ATTR_DENSITY, vec3f::zero()).unwrap().x, 1.0);
+    assert_eq_float!(scaled_voxels.sample_attribute(&ATTR_ABSORPTION, vec3f::zero()).unwrap().x, 1.0);
+
+    // Hole through the fog
+    let hole = ClippedVolume::new(fog(vec3f::zero(), 10.0, 1.0), ClipShape::Sphere { center: vec3f::zero(), radius: 3.0 }, true);
+    assert_eq_float!(hole.absorption_coeff(vec3(1.0, 1.0, 1.0)).x, 0.0);
+    assert_eq_float!(hole.sample(vec3(5.0, 0.0, 0.0)).absorption_coeff.x, 1.0);
+    assert_intervals(hole.find_intersections(ray), &[(10.0, 30.0)]);
+
+    let half = ClippedVolume::new(fog(vec3f::zero(), 10.0, 1.0),
+        ClipShape::HalfSpace { point: vec3f::zero(), normal: vec3(1.0, 0.0, 0.0) }, false);
+    assert_eq_float!(half.absorption_coeff(vec3(-1.0, 0.0, 0.0)).x, 1.0);
+    assert_eq_float!(half.absorption_coeff(vec3(1.0, 0.0, 0.0)).x, 0.0);
+    assert_intervals(half.find_intersections(ray), &[(10.0, 20.0)]);
+    let boxed = ClippedVolume::new(fog(vec3f::zero(), 10.0, 1.0),
+        ClipShape::Box(AABB { min: vec3(5.0, -1.0, -1.0), max: vec3(15.0, 1.0, 1.0) }), false);
+    assert_intervals(boxed.find_intersections(ray), &[(25.0, 30.0)]);
+    assert!((boxed.world_bounds().max - vec3(10.0, 1.0, 1.0)).length() < 1e-5);
+
+    // Soft mask from a sphere of absorption 0.25
+    let mask = Box::new(ConstantVolume::new(
+        ConstantVolumeShape::Sphere { radius: 5.0 }, vec3f::zero(), vec3f::zero(), vec3(0.25, 0.25, 0.25), vec3f::zero(), Box::new(Isotropic{})));
+    let masked = MaskedVolume::new(fog(vec3f::zero(), 10.0, 2.0), mask);
+    assert_eq!(masked.mask_attribute, ATTR_ABSORPTION);
+    assert_eq_float!(masked.absorption_coeff(vec3(1.0, 0.0, 0.0)).x, 0.5);
+    assert_eq_float!(masked.emission(vec3(7.0, 0.0, 0.0)).x, 0.0);
+    assert_intervals(masked.find_intersections(ray), &[(15.0, 25.0)]);
+
+    // Overlapping fog of absorption 1 in x = [-10, 10] and 3 in x = [0, 20]
+    let combine = |op: CombineOp| CombinedVolume::new(fog(vec3f::zero(), 10.0, 1.0), fog(vec3(10.0, 0.0, 0.0), 10.0, 3.0), op);
+    let (left, overlap, right) = (vec3(-5.0, 0.0, 0.0), vec3(5.0, 0.0, 0.0), vec3(15.0, 0.0, 0.0));
+
+    let max = combine(CombineOp::Max);
+    assert_eq_float!(max.absorption_coeff(overlap).x, 3.0);
+    assert_eq_float!(max.absorption_coeff(left).x, 1.0);
+    assert_intervals(max.find_intersections(ray), &[(10.0, 40.0)]);
+    let min = combine(CombineOp::Min);
+    assert_eq_float!(min.absorption_coeff(overlap).x, 1.0);
+    assert_eq_float!(min.absorption_coeff(right).x, 0.0);
+    assert_intervals(min.find_intersections(ray), &[(20.0, 30.0)]);
+    let subtract = combine(CombineOp::Subtract);
+    assert_eq_float!(subtract.absorption_coeff(overlap).x, 0.0);
+    assert_eq_float!(subtract.absorption_coeff(left).x, 1.0);
+    assert_intervals(subtract.find_intersections(ray), &[(10.0, 30.0)]);
+    // b behind 1 unit of a, and half as much of b behind 0.5 units of a
+    let over = combine(CombineOp::Over { length: 1.0 });
+    assert_eq_float!(over.absorption_coeff(overlap).x, 1.0 + 3.0 * (-1.0_f32).exp());
+    assert_eq_float!(over.absorption_coeff(right).x, 3.0);
+    let over_half = combine(CombineOp::Over { length: 0.5 });
+    assert_eq_float!(over_half.absorption_coeff(overlap).x, 1.0 + 3.0 * (-0.5_f32).exp());
+    assert!((max.world_bounds().max - vec3(20.0, 10.0, 10.0)).length() < 1e-5);
+
+    // Density of voxel volumes is combined too, so masks can read it.
+    let voxels = |density: f32, min_x: f32| -> Box<dyn Volume> {
+        Box::new(VoxelVolume::new(
+            Box::new(DenseField::new((4, 4, 4), density)), AABB { min: vec3(min_x, -1.0, -1.0), max: vec3(min_x + 2.0, 1.0, 1.0) },
+            vec3f::zero(), vec3f::one(), vec3f::zero(), Box::new(Isotropic{})))
+    };
+    let combined_density = CombinedVolume::new(voxels(1.0, -1.0), voxels(3.0, 0.0), CombineOp::Max);
+    assert!(combined_density.attributes().contains(&ATTR_DENSITY));
+    assert_eq_float!(combined_density.sample_attribute(&ATTR_DENSITY, vec3(0.5, 0.0, 0.0)).unwrap().x, 3.0);
+    let density_min = CombinedVolume::new(voxels(1.0, -1.0), voxels(3.0, 0.0), CombineOp::Min);
+    assert_eq_float!(density_min.sample_attribute(&ATTR_DENSITY, vec3(0.5, 0.0, 0.0)).unwrap().x, 1.0);
+    assert!(combined_density.sample_attribute(&ATTR_TEMPERATURE, vec3f::zero()).is_none());
+    // Fog has no density, so it counts as zero.
+    let carved = CombinedVolume::new(fog(vec3f::zero(), 10.0, 1.0), voxels(3.0, 0.0), CombineOp::Subtract);
+    assert_eq_float!(carved.sample_attribute(&ATTR_DENSITY, vec3(0.5, 0.0, 0.0)).unwrap().x, 0.0);
+    let with_fog = CombinedVolume::new(fog(vec3f::zero(), 10.0, 1.0), voxels(3.0, 0.0), CombineOp::Max);
+    assert_eq_float!(with_fog.sample_attribute(&ATTR_DENSITY, vec3(0.5, 0.0, 0.0)).unwrap().x, 3.0);
+    let density_over = CombinedVolume::new(voxels(1.0, -1.0), voxels(3.0, 0.0), CombineOp::Over { length: 1.0 });
+    assert_eq_float!(density_over.sample_attribute(&ATTR_DENSITY, vec3(0.5, 0.0, 0.0)).unwrap().x, 1.0 + 3.0 * (-1.0_f32).exp());
+    let masked_by_combined = MaskedVolume::new(fog(vec3f::zero(), 10.0, 1.0), Box::new(combined_density));
+    assert_eq!(masked_by_combined.mask_attribute, ATTR_DENSITY);
+
+    // Subtracted volume doesn't contribute to the phase function.
+    let wi = vec3(0.0, 0.0, 1.0);
+    assert_eq_float!(subtract.phase_function(overlap, wi, wi), ISOTROPIC_PHASE_FN);
+    assert_eq_float!(max.phase_function(overlap, wi, wi), ISOTROPIC_PHASE_FN);
+}